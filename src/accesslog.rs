@@ -0,0 +1,102 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use serde_json::json;
+use crate::privacy::is_redacting_logs;
+use crate::requestid::RequestId;
+use log::info;
+use std::time::{Duration, Instant};
+
+/// Lyrics-specific details a handler attaches to the request for the access log
+#[derive(Clone, Debug)]
+pub struct LyricsLogFields {
+    pub track_id: String,
+    pub format: String,
+    pub cache_hit: bool,
+}
+
+/// Middleware logging one JSON object per request under the `access` log target,
+/// for log ingestion pipelines
+pub async fn json_access_log<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+
+    let res = next.call(req).await?;
+
+    let entry = access_log_entry(res.request(), method, path, res.status().as_u16(), started.elapsed());
+    info!(target: "access", "{}", entry);
+
+    Ok(res)
+}
+
+/// The access log object for a finished request
+fn access_log_entry(request: &HttpRequest, method: String, path: String, status: u16, duration: Duration) -> serde_json::Value {
+    // In no_store mode log the matched route, since the path can hold IDs
+    let redact = is_redacting_logs();
+    let path = if redact {
+        request.match_pattern().unwrap_or_else(|| "-".to_string())
+    } else {
+        path
+    };
+    let fields = request.extensions().get::<LyricsLogFields>().cloned();
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone());
+
+    json!({
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "method": method,
+        "path": path,
+        "request_id": request_id,
+        "track_id": fields.as_ref().filter(|_| !redact).map(|f| f.track_id.as_str()),
+        "format": fields.as_ref().map(|f| f.format.as_str()),
+        "status": status,
+        "duration_ms": duration.as_secs_f64() * 1000.0,
+        "cache_hit": fields.as_ref().map(|f| f.cache_hit),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn entry_has_request_and_lyrics_fields() {
+        let request = TestRequest::get().uri("/lyrics/abc?format=lrc").to_http_request();
+        request.extensions_mut().insert(RequestId("req-1".to_string()));
+        request.extensions_mut().insert(LyricsLogFields {
+            track_id: "abc".to_string(),
+            format: "lrc".to_string(),
+            cache_hit: true,
+        });
+
+        let entry = access_log_entry(&request, "GET".to_string(), "/lyrics/abc".to_string(), 200, Duration::from_millis(12));
+
+        assert!(entry["timestamp"].is_string());
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/lyrics/abc");
+        assert_eq!(entry["request_id"], "req-1");
+        assert_eq!(entry["track_id"], "abc");
+        assert_eq!(entry["format"], "lrc");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["duration_ms"], 12.0);
+        assert_eq!(entry["cache_hit"], true);
+    }
+
+    #[test]
+    fn entry_without_lyrics_fields_has_nulls() {
+        let request = TestRequest::get().uri("/health").to_http_request();
+
+        let entry = access_log_entry(&request, "GET".to_string(), "/health".to_string(), 404, Duration::ZERO);
+
+        assert_eq!(entry["status"], 404);
+        assert!(entry["request_id"].is_null());
+        assert!(entry["track_id"].is_null());
+        assert!(entry["format"].is_null());
+        assert!(entry["cache_hit"].is_null());
+    }
+}
@@ -0,0 +1,73 @@
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+
+/// Checks the request's `X-API-Key` header or `apikey` query parameter against the configured key.
+/// When no key is configured, every request is allowed.
+pub fn is_authorized(req: &HttpRequest, query: &HashMap<String, String>, api_key: Option<&str>) -> bool {
+    let expected = match api_key {
+        Some(key) => key,
+        None => return true,
+    };
+    
+    let provided = req.headers()
+        .get("X-API-Key")
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| query.get("apikey").map(String::as_str));
+    
+    match provided {
+        Some(provided) => constant_time_eq(provided.as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn accepts_the_key_in_header_or_query() {
+        let req = TestRequest::default().insert_header(("X-API-Key", "secret")).to_http_request();
+        assert!(is_authorized(&req, &query(&[]), Some("secret")));
+
+        let req = TestRequest::default().to_http_request();
+        assert!(is_authorized(&req, &query(&[("apikey", "secret")]), Some("secret")));
+    }
+
+    #[test]
+    fn rejects_a_wrong_or_missing_key() {
+        let req = TestRequest::default().insert_header(("X-API-Key", "secreT")).to_http_request();
+        assert!(!is_authorized(&req, &query(&[]), Some("secret")));
+        assert!(!is_authorized(&req, &query(&[("apikey", "secret-but-longer")]), Some("secret")));
+
+        let req = TestRequest::default().to_http_request();
+        assert!(!is_authorized(&req, &query(&[]), Some("secret")));
+    }
+
+    #[test]
+    fn allows_everything_without_a_configured_key() {
+        let req = TestRequest::default().to_http_request();
+        assert!(is_authorized(&req, &query(&[]), None));
+    }
+
+    #[test]
+    fn compares_whole_strings() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secrex"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct CacheEntry {
+    value: serde_json::Value,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+/// In-memory LRU cache of formatted lyrics, keyed by track ID and request options
+pub struct LyricsCache {
+    entries: HashMap<String, CacheEntry>,
+    ttl: Duration,
+    capacity: usize,
+    clock: u64,
+}
+
+impl LyricsCache {
+    /// Create a new cache holding at most `capacity` entries for `ttl` each.
+    /// A capacity of zero disables caching.
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        LyricsCache {
+            entries: HashMap::new(),
+            ttl,
+            capacity,
+            clock: 0,
+        }
+    }
+
+    /// Returns the cached value for the key if present and not expired
+    pub fn get(&mut self, key: &str) -> Option<serde_json::Value> {
+        self.get_at(key, Instant::now())
+    }
+
+    // `get` as of `now`, so expiry can be tested without waiting
+    fn get_at(&mut self, key: &str, now: Instant) -> Option<serde_json::Value> {
+        let expired = match self.entries.get(key) {
+            Some(entry) => now.saturating_duration_since(entry.inserted_at) >= self.ttl,
+            None => return None,
+        };
+
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry.value.clone())
+    }
+
+    /// Number of stored entries, including expired ones not yet evicted
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Stores a value, evicting the least recently used entry when full
+    pub fn insert(&mut self, key: String, value: serde_json::Value) {
+        self.insert_at(key, value, Instant::now());
+    }
+
+    // `insert` as of `now`
+    fn insert_at(&mut self, key: String, value: serde_json::Value, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict(now);
+        }
+
+        self.clock += 1;
+        self.entries.insert(key, CacheEntry {
+            value,
+            inserted_at: now,
+            last_used: self.clock,
+        });
+    }
+
+    /// Drops expired entries, or the least recently used one if none have expired
+    fn evict(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| now.saturating_duration_since(entry.inserted_at) < ttl);
+
+        if self.entries.len() < before {
+            return;
+        }
+
+        let oldest = self.entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn returns_stored_value_before_ttl() {
+        let start = Instant::now();
+        let mut cache = LyricsCache::new(TTL, 10);
+        cache.insert_at("track:lrc".to_string(), json!({"lines": []}), start);
+
+        assert_eq!(cache.get_at("track:lrc", start + Duration::from_secs(59)), Some(json!({"lines": []})));
+    }
+
+    #[test]
+    fn misses_unknown_keys() {
+        let start = Instant::now();
+        let mut cache = LyricsCache::new(TTL, 10);
+        cache.insert_at("track:lrc".to_string(), json!(1), start);
+
+        assert_eq!(cache.get_at("track:id3", start), None);
+        assert!(LyricsCache::new(TTL, 10).get_at("track:lrc", start).is_none());
+    }
+
+    #[test]
+    fn expires_entries_after_ttl() {
+        let start = Instant::now();
+        let mut cache = LyricsCache::new(TTL, 10);
+        cache.insert_at("track:lrc".to_string(), json!(1), start);
+
+        assert_eq!(cache.get_at("track:lrc", start + TTL), None);
+        // Expired entries are dropped when looked up
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_when_full() {
+        let start = Instant::now();
+        let mut cache = LyricsCache::new(TTL, 2);
+        cache.insert_at("a".to_string(), json!("a"), start);
+        cache.insert_at("b".to_string(), json!("b"), start);
+        cache.get_at("a", start);
+        cache.insert_at("c".to_string(), json!("c"), start);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_at("a", start).is_some());
+        assert!(cache.get_at("b", start).is_none());
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut cache = LyricsCache::new(TTL, 0);
+        cache.insert("a".to_string(), json!("a"));
+
+        assert!(cache.is_empty());
+    }
+}
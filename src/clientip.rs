@@ -0,0 +1,173 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use actix_web::web;
+use std::net::IpAddr;
+
+/// An IP network such as `10.0.0.0/8` or `fd00::/8`
+#[derive(Clone, Copy, Debug)]
+struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Parses a CIDR, or a bare address meaning just that host
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr.parse::<IpAddr>().ok()?, Some(prefix_len.parse::<u8>().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return None;
+        }
+
+        Some(IpNetwork { addr, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Proxies allowed to report the client address in `X-Forwarded-For`
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNetwork>,
+}
+
+impl TrustedProxies {
+    /// Parses a list of CIDRs, returning the first invalid entry as the error
+    pub fn parse(entries: &[String]) -> std::result::Result<Self, String> {
+        let networks = entries
+            .iter()
+            .map(|entry| IpNetwork::parse(entry).ok_or_else(|| entry.clone()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(TrustedProxies { networks })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// The client address for a connection from `peer`. `X-Forwarded-For` is only believed when
+    /// the peer is a trusted proxy, and then only up to the rightmost address no trusted proxy
+    /// added, since anything left of it could have been sent by the client itself.
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = peer?;
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+
+        for hop in forwarded.iter().rev() {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                },
+                // A garbled entry can't be traced further, so the last proxy that added it is the client
+                Err(_) => break,
+            }
+        }
+
+        Some(client)
+    }
+}
+
+/// Client address of the request, for rate limiting and access logs
+pub fn client_ip(req: &ServiceRequest) -> String {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let client = match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(proxies) => proxies.client_ip(peer, req.headers()),
+        None => peer,
+    };
+
+    client.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn proxies(entries: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse(&entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(HeaderName::from_static("x-forwarded-for"), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_spoof_their_address() {
+        let headers = forwarded_for(&["1.2.3.4"]);
+        assert_eq!(proxies(&["10.0.0.0/8"]).client_ip(ip("203.0.113.9"), &headers), ip("203.0.113.9"));
+        assert_eq!(TrustedProxies::default().client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn trusted_proxies_report_the_rightmost_untrusted_hop() {
+        let trusted = proxies(&["10.0.0.0/8", "fd00::/8"]);
+
+        // The client sent a fake first entry; the proxies appended the real one
+        let headers = forwarded_for(&["6.6.6.6, 198.51.100.7", "10.1.2.3"]);
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("198.51.100.7"));
+
+        let headers = forwarded_for(&["2001:db8::1, fd00::2"]);
+        assert_eq!(trusted.client_ip(ip("fd00::1"), &headers), ip("2001:db8::1"));
+
+        // Without the header the proxy itself is all that is known
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+
+        // A garbled hop stops the walk at the proxy that added it
+        let headers = forwarded_for(&["198.51.100.7, garbage, 10.1.2.3"]);
+        assert_eq!(trusted.client_ip(ip("10.0.0.1"), &headers), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn parses_cidrs_and_bare_addresses() {
+        let trusted = proxies(&["192.168.1.0/24", "127.0.0.1"]);
+        assert!(trusted.is_trusted("192.168.1.200".parse().unwrap()));
+        assert!(!trusted.is_trusted("192.168.2.1".parse().unwrap()));
+        assert!(trusted.is_trusted("127.0.0.1".parse().unwrap()));
+        assert!(!trusted.is_trusted("127.0.0.2".parse().unwrap()));
+        // IPv4-mapped IPv6 peers match IPv4 networks
+        assert!(trusted.is_trusted("::ffff:192.168.1.5".parse().unwrap()));
+
+        assert_eq!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).unwrap_err(), "10.0.0.0/33");
+        assert_eq!(TrustedProxies::parse(&["proxy.local".to_string()]).unwrap_err(), "proxy.local");
+    }
+}
@@ -1,92 +1,700 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::env;
-use log::{info, warn};
+use std::str::FromStr;
+use directories::ProjectDirs;
+use log::{error, info, warn};
+use crate::spotify::{DEFAULT_APP_PLATFORM, DEFAULT_APP_VERSION, DEFAULT_TOTP_SECRET, DEFAULT_TOTP_VERSION, DEFAULT_USER_AGENT};
+
+/// How access logs are written
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Naming of the keys in JSON lyrics responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCase {
+    Camel,
+    Snake,
+}
 
 pub struct Config {
-    pub sp_dc: String,
+    pub sp_dc: Vec<String>,
     pub port: u16,
+    pub host: String,
+    pub base_path: String,
+    pub lyrics_cache_ttl_secs: u64,
+    pub lyrics_cache_capacity: usize,
+    pub lyrics_cache_dir: Option<String>,
+    pub negative_cache_ttl_secs: u64,
+    pub disable_file_cache: bool,
+    pub no_store: bool,
+    pub shutdown_timeout_secs: u64,
+    pub workers: usize,
+    pub api_key: Option<String>,
+    pub request_signing_secret: Option<String>,
+    pub rate_limit_per_minute: u32,
+    pub max_retries: u32,
+    pub server_time_attempts: u32,
+    pub base_backoff_ms: u64,
+    pub request_timeout_secs: u64,
+    pub log_format: LogFormat,
+    pub response_case: ResponseCase,
+    pub allowed_origins: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+    pub totp_secret: String,
+    pub totp_version: u32,
+    pub token_expiry_margin_secs: u64,
+    pub token_url: Option<String>,
+    pub lyrics_url: Option<String>,
+    pub server_time_url: Option<String>,
+    pub web_player_url: Option<String>,
+    pub tracks_url: Option<String>,
+    pub playlist_url: Option<String>,
+    pub transcript_url: Option<String>,
+    pub lrclib_fallback: bool,
+    pub lrclib_url: Option<String>,
+    pub validate_token_on_startup: bool,
+    pub allow_anonymous: bool,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub user_agent: String,
+    pub app_platform: String,
+    pub spotify_app_version: String,
+    pub discover_app_version: bool,
+    pub debug_endpoints: bool,
+    pub debug_upstream: bool,
+    pub max_lyric_lines: usize,
 }
 
 impl Config {
-    pub fn load() -> Self {
+    /// Loads the configuration, reading `config_path` (or the file named by `CONFIG_PATH` /
+    /// `SPOTIFY_LYRICS_CONFIG`) instead of searching the default locations.
+    /// Fails only if an explicitly requested file can't be read.
+    pub fn load(config_path: Option<&Path>) -> io::Result<Self> {
+        let config_path = config_path.map(Path::to_path_buf).or_else(config_path_from_env);
+
+        let file_values = match config_path {
+            Some(path) => Config::load_from_path(&path)?,
+            None => Config::load_from_file().unwrap_or_default(),
+        };
+
+        Ok(Config::from_values(&file_values))
+    }
+
+    /// Builds the configuration from the merged config file values, with environment variables
+    /// taking precedence over them
+    fn from_values(file_values: &HashMap<String, String>) -> Self {
         let mut config = Config {
-            sp_dc: String::new(),
+            sp_dc: Vec::new(),
             port: 8080,
+            host: "0.0.0.0".to_string(),
+            base_path: String::new(),
+            lyrics_cache_ttl_secs: 3600,
+            lyrics_cache_capacity: 1000,
+            lyrics_cache_dir: None,
+            negative_cache_ttl_secs: 900,
+            disable_file_cache: false,
+            no_store: false,
+            shutdown_timeout_secs: 30,
+            workers: 0,
+            api_key: None,
+            request_signing_secret: None,
+            rate_limit_per_minute: 0,
+            max_retries: 2,
+            server_time_attempts: 3,
+            base_backoff_ms: 500,
+            request_timeout_secs: 15,
+            log_format: LogFormat::Text,
+            response_case: ResponseCase::Camel,
+            allowed_origins: Vec::new(),
+            trusted_proxies: Vec::new(),
+            totp_secret: DEFAULT_TOTP_SECRET.to_string(),
+            totp_version: DEFAULT_TOTP_VERSION,
+            token_expiry_margin_secs: 30,
+            token_url: None,
+            lyrics_url: None,
+            server_time_url: None,
+            web_player_url: None,
+            tracks_url: None,
+            playlist_url: None,
+            transcript_url: None,
+            lrclib_fallback: false,
+            lrclib_url: None,
+            validate_token_on_startup: false,
+            allow_anonymous: false,
+            tls_cert_path: None,
+            tls_key_path: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            app_platform: DEFAULT_APP_PLATFORM.to_string(),
+            spotify_app_version: DEFAULT_APP_VERSION.to_string(),
+            discover_app_version: false,
+            debug_endpoints: false,
+            debug_upstream: false,
+            max_lyric_lines: 10000,
         };
-        
-        // Try to load from config file first
-        if let Some(sp_dc) = Config::load_from_file() {
-            info!("Loaded SP_DC from config file");
-            config.sp_dc = sp_dc;
+
+        // A secret file (e.g. a Docker/Kubernetes secret mount) wins, then the environment
+        // variable, then the config file
+        if let Some(sp_dc) = lookup(file_values, "sp_dc_file").and_then(|path| read_secret_file(&path)) {
+            config.sp_dc = parse_list(&sp_dc);
+            info!("Loaded {} SP_DC token(s) from secret file", config.sp_dc.len());
         } else if let Ok(sp_dc) = env::var("SP_DC") {
-            // Fall back to environment variable
-            info!("Loaded SP_DC from environment variable");
-            config.sp_dc = sp_dc;
+            config.sp_dc = parse_list(&sp_dc);
+            info!("Loaded {} SP_DC token(s) from environment variable", config.sp_dc.len());
+        } else if let Some(sp_dc) = file_values.get("sp_dc") {
+            config.sp_dc = parse_list(sp_dc);
+            info!("Loaded {} SP_DC token(s) from config file", config.sp_dc.len());
         } else {
             warn!("SP_DC not found in config file or environment variables");
         }
-        
-        // Get port from environment variable or use default
-        if let Ok(port_str) = env::var("PORT") {
-            if let Ok(port) = port_str.parse::<u16>() {
-                config.port = port;
-            }
+
+        // Get port from environment variable or config file, or use default
+        if let Some(port) = lookup_parsed(file_values, "port") {
+            config.port = port;
+        }
+
+        // Address to listen on
+        if let Some(host) = lookup(file_values, "host") {
+            config.host = host;
+        }
+
+        // Path prefix for hosting under a reverse proxy subpath, e.g. "/lyrics"
+        if let Some(base_path) = lookup(file_values, "base_path") {
+            config.base_path = normalize_base_path(&base_path);
+        }
+
+        // In-memory lyrics cache settings
+        if let Some(ttl) = lookup_parsed(file_values, "lyrics_cache_ttl_secs") {
+            config.lyrics_cache_ttl_secs = ttl;
+        }
+
+        if let Some(capacity) = lookup_parsed(file_values, "lyrics_cache_capacity") {
+            config.lyrics_cache_capacity = capacity;
+        }
+
+        // Optional directory to persist the lyrics cache across restarts, using the same TTL
+        config.lyrics_cache_dir = lookup(file_values, "lyrics_cache_dir");
+
+        // How long tracks without lyrics are remembered, 0 to always ask Spotify again
+        if let Some(ttl) = lookup_parsed(file_values, "negative_cache_ttl_secs") {
+            config.negative_cache_ttl_secs = ttl;
+        }
+
+        // Keep the access token in memory instead of a temp file, for read-only filesystems
+        if let Some(disable) = lookup_parsed(file_values, "disable_file_cache") {
+            config.disable_file_cache = disable;
+        }
+
+        // Privacy mode: nothing written to disk and no IDs in the logs
+        if let Some(no_store) = lookup_parsed(file_values, "no_store") {
+            config.no_store = no_store;
+        }
+
+        // Grace period for in-flight requests when shutting down
+        if let Some(timeout) = lookup_parsed(file_values, "shutdown_timeout_secs") {
+            config.shutdown_timeout_secs = timeout;
+        }
+
+        // HTTP worker threads; 0 keeps actix's default of one per CPU core
+        if let Some(workers) = lookup_parsed(file_values, "workers") {
+            config.workers = workers;
+        }
+
+        // Optional API key; when unset the API is open to everyone
+        config.api_key = lookup(file_values, "api_key");
+
+        // Optional shared secret for verifying X-Signature headers added by a gateway
+        config.request_signing_secret = lookup(file_values, "request_signing_secret");
+
+        // Per-IP request limit; 0 means unlimited
+        if let Some(limit) = lookup_parsed(file_values, "rate_limit_per_minute") {
+            config.rate_limit_per_minute = limit;
+        }
+
+        // Retry policy for transient Spotify errors
+        if let Some(max_retries) = lookup_parsed(file_values, "max_retries") {
+            config.max_retries = max_retries;
+        }
+
+        if let Some(base_backoff_ms) = lookup_parsed(file_values, "base_backoff_ms") {
+            config.base_backoff_ms = base_backoff_ms;
+        }
+
+        // Server time attempts before the TOTP falls back to the local clock
+        if let Some(attempts) = lookup_parsed(file_values, "server_time_attempts") {
+            config.server_time_attempts = attempts;
+        }
+
+        // Timeout for each outbound request to Spotify
+        if let Some(timeout) = lookup_parsed(file_values, "request_timeout_secs") {
+            config.request_timeout_secs = timeout;
+        }
+
+        // Access log format, "text" or "json"
+        match lookup(file_values, "log_format").as_deref() {
+            Some("json") => config.log_format = LogFormat::Json,
+            Some("text") | None => {},
+            Some(other) => warn!("Ignoring invalid value for log_format: {}", other),
+        }
+
+        // Key naming in JSON lyrics responses, "camel" as Spotify uses or "snake"
+        match lookup(file_values, "response_case").as_deref() {
+            Some("snake") => config.response_case = ResponseCase::Snake,
+            Some("camel") | None => {},
+            Some(other) => warn!("Ignoring invalid value for response_case: {}", other),
+        }
+
+        // CORS origins allowed to call the API; empty allows any origin
+        if let Some(origins) = lookup(file_values, "allowed_origins") {
+            config.allowed_origins = parse_list(&origins);
+        }
+
+        // Proxies whose X-Forwarded-For header is believed; empty uses the connecting address
+        if let Some(proxies) = lookup(file_values, "trusted_proxies") {
+            config.trusted_proxies = parse_list(&proxies);
+        }
+
+        // TOTP secret and version, so a rotation by Spotify doesn't require a rebuild
+        if let Some(secret) = lookup(file_values, "totp_secret") {
+            config.totp_secret = secret;
+        }
+
+        if let Some(version) = lookup_parsed(file_values, "totp_version") {
+            config.totp_version = version;
+        }
+
+        // Refresh access tokens this many seconds before they expire
+        if let Some(margin) = lookup_parsed(file_values, "token_expiry_margin_secs") {
+            config.token_expiry_margin_secs = margin;
+        }
+
+        // Optional Spotify endpoint overrides, e.g. for a regional proxy or mirror
+        config.token_url = lookup(file_values, "token_url");
+        config.lyrics_url = lookup(file_values, "lyrics_url");
+        config.server_time_url = lookup(file_values, "server_time_url");
+        config.web_player_url = lookup(file_values, "web_player_url");
+        config.tracks_url = lookup(file_values, "tracks_url");
+        config.playlist_url = lookup(file_values, "playlist_url");
+        config.transcript_url = lookup(file_values, "transcript_url");
+
+        // Look up tracks Spotify has no lyrics for on LRCLIB
+        if let Some(fallback) = lookup_parsed(file_values, "lrclib_fallback") {
+            config.lrclib_fallback = fallback;
+        }
+        config.lrclib_url = lookup(file_values, "lrclib_url");
+
+        // Fetch a token before serving, so bad credentials fail the deploy instead of the first request
+        if let Some(validate) = lookup_parsed(file_values, "validate_token_on_startup") {
+            config.validate_token_on_startup = validate;
+        }
+
+        // Serve with the anonymous token Spotify hands out for an invalid sp_dc instead of failing
+        if let Some(allow) = lookup_parsed(file_values, "allow_anonymous") {
+            config.allow_anonymous = allow;
+        }
+
+        // PEM certificate chain and private key; when both are set the server speaks HTTPS and HTTP/2
+        config.tls_cert_path = lookup(file_values, "tls_cert_path");
+        config.tls_key_path = lookup(file_values, "tls_key_path");
+
+        // How requests identify themselves to Spotify, so a stale web player version can be bumped without a rebuild
+        if let Some(user_agent) = lookup(file_values, "user_agent") {
+            config.user_agent = user_agent;
+        }
+
+        if let Some(app_platform) = lookup(file_values, "app_platform") {
+            config.app_platform = app_platform;
+        }
+
+        if let Some(app_version) = lookup(file_values, "spotify_app_version") {
+            config.spotify_app_version = app_version;
+        }
+
+        // Read the current web player version from open.spotify.com at startup
+        if let Some(discover) = lookup_parsed(file_values, "discover_app_version") {
+            config.discover_app_version = discover;
+        }
+
+        // Lyrics longer than this are treated as an upstream anomaly
+        if let Some(max_lines) = lookup_parsed(file_values, "max_lyric_lines") {
+            config.max_lyric_lines = max_lines;
+        }
+
+        // Troubleshooting features that expose Spotify's raw responses; keep off in production
+        if let Some(debug_endpoints) = lookup_parsed(file_values, "debug_endpoints") {
+            config.debug_endpoints = debug_endpoints;
         }
-        
+
+        // Logs every Spotify request and response, with credentials redacted
+        if let Some(debug_upstream) = lookup_parsed(file_values, "debug_upstream") {
+            config.debug_upstream = debug_upstream;
+        }
+
         config
     }
-    
-    fn load_from_file() -> Option<String> {
-        // Check multiple possible config file locations
-        let config_paths = vec![
-            // Current directory
-            PathBuf::from("config.toml"),
-            // User's home directory
-            dirs::home_dir().map(|p| p.join(".config/spotifylyricsapi/config.toml"))?,
-            // System-wide config
-            PathBuf::from("/etc/spotifylyricsapi/config.toml"),
-        ];
-        
-        for path in config_paths {
-            if path.exists() {
-                match fs::read_to_string(&path) {
-                    Ok(content) => {
-                        info!("Found config file at: {}", path.display());
-                        return parse_config_content(&content);
-                    },
-                    Err(e) => {
-                        warn!("Failed to read config file at {}: {}", path.display(), e);
-                    }
+
+    /// Reads the config file at an explicitly requested path
+    fn load_from_path(path: &Path) -> io::Result<HashMap<String, String>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to read config file {}: {}", path.display(), e)))?;
+
+        info!("Loaded config file from: {}", path.display());
+        Ok(parse_config_content(&content))
+    }
+
+    /// Reads every config file found in the search locations and merges them key by key,
+    /// so e.g. a `./config.toml` only needs the settings that differ from `/etc`
+    fn load_from_file() -> Option<HashMap<String, String>> {
+        merge_config_files(&config_search_paths())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.sp_dc.is_empty()
+    }
+
+    /// Addresses to listen on for `host`: an IPv4 or IPv6 address (brackets optional, e.g. `[::]`),
+    /// a hostname resolving to one or more addresses, or `dual` for every interface on both stacks
+    pub fn listen_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        // Where IPv6 sockets also accept IPv4 (the Linux default) binding `::` covers both and the
+        // IPv4 bind is skipped as a duplicate; where they are IPv6-only both binds are needed
+        if self.host.eq_ignore_ascii_case("dual") {
+            return Ok(vec![
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.port),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.port),
+            ]);
+        }
+
+        let host = self.host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(&self.host);
+
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Ok(vec![SocketAddr::new(ip, self.port)]),
+            Err(_) => Ok((host, self.port).to_socket_addrs()?.collect()),
+        }
+    }
+}
+
+/// Turns a path prefix like "lyrics/" into "/lyrics", with "/" or an empty value meaning the root
+fn normalize_base_path(base_path: &str) -> String {
+    let trimmed = base_path.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+/// Candidate config file locations, highest priority first
+pub fn config_search_paths() -> Vec<PathBuf> {
+    // Current directory
+    let mut paths = vec![PathBuf::from("config.toml")];
+
+    // The platform's config directory: ~/.config/spotifylyricsapi on Linux,
+    // ~/Library/Application Support/spotifylyricsapi on macOS, %APPDATA%\spotifylyricsapi\config on Windows
+    if let Some(project_dirs) = ProjectDirs::from("", "", "spotifylyricsapi") {
+        paths.push(project_dirs.config_dir().join("config.toml"));
+    }
+
+    // Paths used before platform directories were supported, kept for existing installs
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".config/spotifylyricsapi/config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/spotifylyricsapi/config.toml"));
+
+    // On Linux the platform directory and the legacy home path are usually the same file
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+    paths
+}
+
+/// Reads the config files that exist among `paths`, given highest priority first, and merges
+/// them key by key. None when there are no readable files.
+fn merge_config_files(paths: &[PathBuf]) -> Option<HashMap<String, String>> {
+    let mut merged: Option<HashMap<String, String>> = None;
+
+    // Lowest priority first, so each later file overrides the keys it sets
+    for path in paths.iter().rev() {
+        if path.exists() {
+            match fs::read_to_string(path) {
+                Ok(content) => {
+                    info!("Found config file at: {}", path.display());
+                    merged.get_or_insert_with(HashMap::new).extend(parse_config_content(&content));
+                },
+                Err(e) => {
+                    warn!("Failed to read config file at {}: {}", path.display(), e);
                 }
             }
         }
-        
-        None
     }
-    
-    pub fn is_valid(&self) -> bool {
-        !self.sp_dc.is_empty()
+
+    merged
+}
+
+/// Config file path requested through the environment, if any
+fn config_path_from_env() -> Option<PathBuf> {
+    ["CONFIG_PATH", "SPOTIFY_LYRICS_CONFIG"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|path| !path.trim().is_empty()))
+        .map(PathBuf::from)
+}
+
+/// Looks up a setting in the upper-cased environment variable, falling back to the config file
+fn lookup(file_values: &HashMap<String, String>, key: &str) -> Option<String> {
+    env::var(key.to_uppercase())
+        .ok()
+        .or_else(|| file_values.get(key).cloned())
+}
+
+/// Looks up a setting and parses it, warning and ignoring the value if it is malformed
+fn lookup_parsed<T: FromStr>(file_values: &HashMap<String, String>, key: &str) -> Option<T> {
+    let value = lookup(file_values, key)?;
+    match value.parse::<T>() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("Ignoring invalid value for {}: {}", key, value);
+            None
+        }
+    }
+}
+
+/// Reads a secret from a file, trimming surrounding whitespace and the trailing newline
+fn read_secret_file(path: &str) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(content) if !content.trim().is_empty() => Some(content.trim().to_string()),
+        Ok(_) => {
+            error!("Secret file {} is empty", path);
+            None
+        },
+        Err(e) => {
+            error!("Failed to read secret file {}: {}", path, e);
+            None
+        }
     }
 }
 
-fn parse_config_content(content: &str) -> Option<String> {
+/// Splits a comma-separated value or a TOML-style `["a", "b"]` array into its items
+fn parse_list(value: &str) -> Vec<String> {
+    value.trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(|item| item.trim().trim_matches('"').trim_matches('\'').trim())
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_config_content(content: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+
     for line in content.lines() {
         let line = line.trim();
-        if line.starts_with("sp_dc") || line.starts_with("SP_DC") {
-            let parts: Vec<&str> = line.splitn(2, '=').collect();
-            if parts.len() == 2 {
-                // Remove quotes and whitespace
-                let value = parts[1].trim()
-                    .trim_matches('"')
-                    .trim_matches('\'')
-                    .trim();
-                
-                if !value.is_empty() {
-                    return Some(value.to_string());
-                }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.splitn(2, '=').collect();
+        if parts.len() == 2 {
+            // Keys are case-insensitive so both sp_dc and SP_DC work
+            let key = parts[0].trim().to_lowercase();
+
+            // Remove quotes and whitespace
+            let value = parts[1].trim()
+                .trim_matches('"')
+                .trim_matches('\'')
+                .trim();
+
+            if !value.is_empty() {
+                values.insert(key, value.to_string());
             }
         }
     }
-    None
-}
\ No newline at end of file
+
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The only test that sets environment variables, since they are shared by every test thread
+    #[test]
+    fn merges_config_files_with_environment_taking_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.toml");
+        let instance = dir.path().join("instance.toml");
+        fs::write(&base, "port = 9000\nsp_dc = \"base-token\"\nhost = \"127.0.0.1\"\n").unwrap();
+        fs::write(&instance, "sp_dc = \"instance-token\"\n").unwrap();
+
+        env::remove_var("PORT");
+        env::remove_var("SP_DC");
+        let merged = merge_config_files(&[instance.clone(), dir.path().join("missing.toml"), base.clone()]).unwrap();
+        let config = Config::from_values(&merged);
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.sp_dc, vec!["instance-token"]);
+
+        env::set_var("PORT", "9100");
+        env::set_var("SP_DC", "env-token");
+        let config = Config::from_values(&merged);
+        env::remove_var("PORT");
+        env::remove_var("SP_DC");
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.sp_dc, vec!["env-token"]);
+        assert_eq!(config.host, "127.0.0.1");
+
+        // CONFIG_PATH replaces the search locations, and wins over SPOTIFY_LYRICS_CONFIG
+        env::set_var("SPOTIFY_LYRICS_CONFIG", &instance);
+        assert_eq!(Config::load(None).unwrap().sp_dc, vec!["instance-token"]);
+        env::set_var("CONFIG_PATH", &base);
+        let config = Config::load(None);
+        env::set_var("CONFIG_PATH", dir.path().join("missing.toml"));
+        let missing = Config::load(None);
+        env::remove_var("CONFIG_PATH");
+        env::remove_var("SPOTIFY_LYRICS_CONFIG");
+        assert_eq!(config.unwrap().sp_dc, vec!["base-token"]);
+        // A path that was asked for must exist rather than silently falling back
+        let error = missing.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("missing.toml"), "{}", error);
+    }
+
+    fn config_with_host(host: &str) -> Config {
+        Config { host: host.to_string(), port: 8080, ..Config::from_values(&HashMap::new()) }
+    }
+
+    #[test]
+    fn dual_host_listens_on_both_stacks() {
+        let addrs = config_with_host("dual").listen_addrs().unwrap();
+
+        assert_eq!(addrs, vec![
+            "[::]:8080".parse::<SocketAddr>().unwrap(),
+            "0.0.0.0:8080".parse::<SocketAddr>().unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn host_accepts_bracketed_ipv6() {
+        assert_eq!(config_with_host("[::1]").listen_addrs().unwrap(), vec!["[::1]:8080".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(config_with_host("127.0.0.1").listen_addrs().unwrap(), vec!["127.0.0.1:8080".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn no_config_files_gives_none() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(merge_config_files(&[dir.path().join("config.toml")]).is_none());
+    }
+
+    #[test]
+    fn sp_dc_lists_accept_commas_and_arrays() {
+        assert_eq!(parse_list("tok1,tok2"), vec!["tok1", "tok2"]);
+        assert_eq!(parse_list("[\"tok1\", 'tok2', ]"), vec!["tok1", "tok2"]);
+        assert_eq!(parse_list(" tok1 "), vec!["tok1"]);
+        assert!(parse_list("[]").is_empty());
+    }
+
+    #[test]
+    fn reads_sp_dc_from_a_secret_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("sp_dc");
+        let config_file = dir.path().join("config.toml");
+        fs::write(&secret, "file-token-1,file-token-2\n").unwrap();
+        fs::write(&config_file, format!("sp_dc = \"inline-token\"\nsp_dc_file = \"{}\"\n", secret.display())).unwrap();
+
+        let config = Config::load(Some(&config_file)).unwrap();
+        assert_eq!(config.sp_dc, vec!["file-token-1", "file-token-2"]);
+    }
+
+    #[test]
+    fn secret_files_are_trimmed_and_must_not_be_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        let path = secret.display().to_string();
+
+        assert!(read_secret_file(&path).is_none());
+        fs::write(&secret, " \n").unwrap();
+        assert!(read_secret_file(&path).is_none());
+        fs::write(&secret, "  token\n").unwrap();
+        assert_eq!(read_secret_file(&path).as_deref(), Some("token"));
+    }
+
+    #[test]
+    fn searches_the_platform_dir_before_legacy_paths() {
+        let paths = config_search_paths();
+        let platform = ProjectDirs::from("", "", "spotifylyricsapi").unwrap().config_dir().join("config.toml");
+        let position = |path: &Path| paths.iter().position(|p| p == path);
+
+        assert_eq!(paths[0], PathBuf::from("config.toml"));
+        assert_eq!(position(&platform), Some(1));
+        assert_eq!(paths.last(), Some(&PathBuf::from("/etc/spotifylyricsapi/config.toml")));
+        let unique: HashSet<_> = paths.iter().collect();
+        assert_eq!(unique.len(), paths.len());
+
+        #[cfg(target_os = "macos")]
+        assert!(platform.ends_with("Library/Application Support/spotifylyricsapi/config.toml"));
+
+        // On Linux without XDG_CONFIG_HOME the platform dir is the legacy path, listed once
+        let legacy = dirs::home_dir().unwrap().join(".config/spotifylyricsapi/config.toml");
+        if legacy != platform {
+            assert!(position(&legacy) > position(&platform));
+        }
+    }
+
+    #[test]
+    fn client_identity_defaults_to_the_current_web_player() {
+        let config = Config::from_values(&HashMap::new());
+        assert_eq!(config.user_agent, DEFAULT_USER_AGENT);
+        assert_eq!(config.app_platform, DEFAULT_APP_PLATFORM);
+        assert_eq!(config.spotify_app_version, DEFAULT_APP_VERSION);
+
+        let config = Config::from_values(&parse_config_content(
+            "user_agent = \"test-agent\"\napp_platform = \"TestPlatform\"\nspotify_app_version = \"1.2.3\"\n"
+        ));
+        assert_eq!(config.user_agent, "test-agent");
+        assert_eq!(config.app_platform, "TestPlatform");
+        assert_eq!(config.spotify_app_version, "1.2.3");
+    }
+
+    #[test]
+    fn workers_default_to_auto_and_ignore_malformed_values() {
+        assert_eq!(Config::from_values(&HashMap::new()).workers, 0);
+        assert_eq!(Config::from_values(&parse_config_content("workers = 4\n")).workers, 4);
+        assert_eq!(Config::from_values(&parse_config_content("workers = \"-2\"\n")).workers, 0);
+    }
+
+    #[test]
+    fn file_cache_is_enabled_unless_disabled() {
+        assert!(!Config::from_values(&HashMap::new()).disable_file_cache);
+        assert!(Config::from_values(&parse_config_content("disable_file_cache = true\n")).disable_file_cache);
+    }
+
+    #[test]
+    fn base_path_is_normalized_to_a_leading_slash() {
+        assert_eq!(normalize_base_path("lyrics"), "/lyrics");
+        assert_eq!(normalize_base_path(" /lyrics/ "), "/lyrics");
+        assert_eq!(normalize_base_path("/api/lyrics/"), "/api/lyrics");
+        assert_eq!(normalize_base_path("/"), "");
+        assert_eq!(normalize_base_path(""), "");
+    }
+
+    #[test]
+    fn response_case_is_camel_unless_snake() {
+        assert_eq!(Config::from_values(&HashMap::new()).response_case, ResponseCase::Camel);
+        assert_eq!(Config::from_values(&parse_config_content("response_case = \"snake\"\n")).response_case, ResponseCase::Snake);
+        assert_eq!(Config::from_values(&parse_config_content("response_case = \"kebab\"\n")).response_case, ResponseCase::Camel);
+    }
+
+    #[test]
+    fn anonymous_tokens_are_rejected_unless_allowed() {
+        assert!(!Config::from_values(&HashMap::new()).allow_anonymous);
+        assert!(Config::from_values(&parse_config_content("allow_anonymous = true\n")).allow_anonymous);
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    #[serde(rename = "storedAt")]
+    stored_at: u64,
+    value: serde_json::Value,
+}
+
+/// File-backed lyrics cache that survives restarts, one JSON file per cache key.
+/// Failures are logged and treated as misses so a broken disk never breaks lookups.
+pub struct DiskCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl DiskCache {
+    /// Create a cache storing entries in `dir` for `ttl` each, creating the directory if needed
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("Failed to create lyrics cache directory {}: {}", dir.display(), e);
+        }
+
+        DiskCache { dir, ttl }
+    }
+
+    /// Returns the stored value for the key if present and not expired
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let path = self.path_for(key);
+        let contents = fs::read_to_string(&path).ok()?;
+
+        let entry: DiskEntry = match serde_json::from_str(&contents) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Ignoring corrupt lyrics cache file {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        if now_secs().saturating_sub(entry.stored_at) >= self.ttl.as_secs() {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+
+        Some(entry.value)
+    }
+
+    /// Stores a value, replacing the file atomically so readers never see a partial entry
+    pub fn insert(&self, key: &str, value: &serde_json::Value) {
+        let entry = DiskEntry {
+            stored_at: now_secs(),
+            value: value.clone(),
+        };
+
+        if let Err(e) = write_atomically(&self.dir, &self.path_for(key), &entry) {
+            warn!("Failed to write lyrics cache entry for {}: {}", key, e);
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Cache keys contain client input, so files are named by a hash of the key
+    fn path_for(&self, key: &str) -> PathBuf {
+        let name: String = Sha1::digest(key.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        self.dir.join(format!("{}.json", name))
+    }
+}
+
+fn write_atomically(dir: &Path, path: &Path, entry: &DiskEntry) -> std::io::Result<()> {
+    let json = serde_json::to_vec(entry)?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(&json)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn entries_survive_a_new_cache_on_the_same_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        DiskCache::new(dir.path().to_path_buf(), TTL).insert("track:lrc", &json!({"lines": []}));
+
+        let restarted = DiskCache::new(dir.path().to_path_buf(), TTL);
+        assert_eq!(restarted.get("track:lrc"), Some(json!({"lines": []})));
+        assert_eq!(restarted.get("track:id3"), None);
+    }
+
+    #[test]
+    fn expired_entries_miss_and_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::new(dir.path().to_path_buf(), TTL);
+        let path = cache.path_for("track:lrc");
+        let entry = DiskEntry { stored_at: now_secs() - TTL.as_secs(), value: json!(1) };
+        write_atomically(dir.path(), &path, &entry).unwrap();
+
+        assert_eq!(cache.get("track:lrc"), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn corrupt_files_read_as_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::new(dir.path().to_path_buf(), TTL);
+        fs::write(cache.path_for("track:lrc"), "{\"storedAt\": ").unwrap();
+
+        assert_eq!(cache.get("track:lrc"), None);
+
+        // The next insert replaces it
+        cache.insert("track:lrc", &json!(2));
+        assert_eq!(cache.get("track:lrc"), Some(json!(2)));
+    }
+
+    #[test]
+    fn files_are_named_by_a_hash_of_the_key() {
+        let cache = DiskCache::new(std::env::temp_dir(), TTL);
+        let name = cache.path_for("../../etc/passwd").file_name().unwrap().to_str().unwrap().to_string();
+
+        assert_eq!(name.len(), 40 + ".json".len());
+        assert!(name.trim_end_matches(".json").chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -0,0 +1,11 @@
+//! Spotify client behind the lyrics server: token handling, lyrics fetching, formatting and caching.
+//! The HTTP server itself lives in main.rs.
+
+pub mod cache;
+pub mod diskcache;
+pub mod metrics;
+pub mod privacy;
+pub mod retry;
+pub mod spotify;
+pub mod spotifyexception;
+mod upstreamlog;
@@ -1,135 +1,2923 @@
-mod spotify;
-mod spotifyexception;
+mod accesslog;
+mod auth;
+mod clientip;
 mod config;
+mod openapi;
+mod ratelimit;
+mod requestid;
+mod signature;
+mod tls;
 
 use actix_cors::Cors;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, middleware::Logger};
-use spotify::Spotify;
+use clap::Parser;
+use spotifylyricsapi::{metrics, privacy, spotify, spotifyexception};
+use actix_web::{http::header, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, middleware::{from_fn, Compress, Condition, Logger}};
+use spotify::{decode_totp_secret, is_text_format, FormatOptions, LinePage, LrcTags, SearchResponse, Spotify, SpotifyItem, TrackMetadata, trim_line_words, DEFAULT_LRCLIB_URL, SUPPORTED_FORMATS};
 use spotifyexception::SpotifyException;
-use std::sync::Mutex;
-use log::{info, error};
+use std::path::PathBuf;
+use std::time::Duration;
+use futures::stream::{self, StreamExt};
+use sha1::{Digest, Sha1};
+use log::{info, error, warn};
 use serde_json::json;
-use config::Config;
+use accesslog::LyricsLogFields;
+use config::{Config, LogFormat, ResponseCase};
+use clientip::TrustedProxies;
+use metrics::METRICS;
+use privacy::loggable;
+use ratelimit::RateLimiter;
+use signature::RequestVerifier;
+
+// Lyric responses may be cached by browsers and CDNs for a day
+const LYRICS_CACHE_CONTROL: &str = "public, max-age=86400";
+
+// Behind an API key only the client's own cache may keep them, or a CDN would hand them to anyone
+const PRIVATE_LYRICS_CACHE_CONTROL: &str = "private, max-age=86400";
+
+// actix's default access log line with the client address resolved through trusted proxies,
+// followed by the request ID
+const ACCESS_LOG_FORMAT: &str = r#"%{client}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}o"#;
+
+// Access log line for no_store mode, with the matched route instead of the request line
+// and without the client address or referer
+const REDACTED_ACCESS_LOG_FORMAT: &str = r#""%{route}xo" %s %b "%{User-Agent}i" %T %{x-request-id}o"#;
+
+// How many album or playlist tracks are fetched from Spotify at once
+const TRACK_LIST_FETCH_CONCURRENCY: usize = 4;
+
+// Playlists can hold thousands of tracks, so only this many get their lyrics fetched
+const MAX_PLAYLIST_TRACKS: usize = 500;
+
+// How many preloaded tracks are fetched from Spotify at once, kept low so a warm-up
+// doesn't crowd out the requests being served meanwhile
+const PRELOAD_CONCURRENCY: usize = 2;
+
+// Largest list of tracks a single preload request may queue
+const MAX_PRELOAD_TRACKS: usize = 100;
+
+/// Serves synchronized Spotify lyrics over HTTP.
+/// Settings not given here come from the config file and environment variables.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Config file to read instead of searching the default locations
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Port to listen on, overriding the config file and PORT
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to listen on, e.g. 127.0.0.1, :: or dual, overriding the config file and HOST
+    #[arg(long, value_name = "ADDR")]
+    host: Option<String>,
+}
+
+impl Cli {
+    // Command-line flags win over the config file and environment variables
+    fn apply_overrides(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+    }
+}
 
 // Struct to hold application state
 struct AppState {
-    spotify: Mutex<Spotify>,
+    spotify: Spotify,
+    api_key: Option<String>,
+    debug_endpoints: bool,
+    response_case: ResponseCase,
+}
+
+// Response returned when the API key is missing or wrong
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .json(error_body("UNAUTHORIZED", "a valid API key is required!"))
 }
 
 // Handler for the main endpoint that processes GET requests with query parameters
 async fn get_lyrics(
+    req: HttpRequest,
     query: web::Query<std::collections::HashMap<String, String>>,
     data: web::Data<AppState>
 ) -> impl Responder {
-    // Get the spotify client from state
-    let spotify = data.spotify.lock().unwrap();
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
     
-    // Check if trackid or url is provided
-    let track_id = if let Some(trackid) = query.get("trackid") {
-        trackid.to_string()
+    // Check if trackid, episodeid or url is provided
+    let item = if let Some(trackid) = query.get("trackid") {
+        SpotifyItem::Track(trackid.to_string())
+    } else if let Some(episodeid) = query.get("episodeid") {
+        SpotifyItem::Episode(episodeid.to_string())
     } else if let Some(url) = query.get("url") {
-        if let Some(extracted_id) = Spotify::extract_track_id(url) {
-            extracted_id
-        } else {
-            return HttpResponse::BadRequest()
-                .json(json!({
-                    "error": true,
-                    "message": "invalid url parameter!"
-                }));
+        match Spotify::extract_item_result(url) {
+            Ok(item) => item,
+            // Short share links only reveal the track once their redirects are followed
+            Err(parse_error) => match data.spotify.resolve_share_url(url).await {
+                Ok(Some(item)) => item,
+                Ok(None) => return spotify_error_response(&parse_error, "Invalid url parameter"),
+                Err(e) => return spotify_error_response(&e, "Failed to resolve share URL"),
+            },
         }
     } else {
         return HttpResponse::BadRequest()
-            .json(json!({
-                "error": true,
-                "message": "url or trackid parameter is required!"
-            }));
+            .json(error_body("MISSING_PARAM", "url or trackid parameter is required!"));
     };
     
-    // Get format parameter with default as "id3"
-    let format = query.get("format").unwrap_or(&"id3".to_string()).to_string();
+    lyrics_response(&req, &item, &query, &data).await
+}
+
+// Handler for the path-style endpoint, e.g. /lyrics/{track_id}?format=lrc
+async fn get_lyrics_by_path(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
     
-    // Only accept "id3" or "lrc" as formats
-    if format != "id3" && format != "lrc" {
-        return HttpResponse::BadRequest()
-            .json(json!({
-                "error": true,
-                "message": "format parameter must be either 'id3' or 'lrc'!"
-            }));
+    lyrics_response(&req, &SpotifyItem::Track(path.into_inner()), &query, &data).await
+}
+
+// Handler for podcast transcripts by path, e.g. /episode/{episode_id}?format=srt
+async fn get_transcript_by_path(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    lyrics_response(&req, &SpotifyItem::Episode(path.into_inner()), &query, &data).await
+}
+
+// Lyrics options shared by every lyrics endpoint, validated from the query string
+struct LyricsQuery {
+    format: String,
+    offset_ms: i64,
+    vocal_removal: bool,
+    market: String,
+    lang: Option<String>,
+    callback: Option<String>,
+    pretty: bool,
+    include_metadata: bool,
+    trim_words: bool,
+    skip_empty: bool,
+    page: Option<LinePage>,
+    lrc_tags: LrcTags,
+}
+
+impl LyricsQuery {
+    fn format_options(&self) -> FormatOptions<'_> {
+        FormatOptions {
+            offset_ms: self.offset_ms,
+            lang: self.lang.as_deref(),
+            skip_empty: self.skip_empty,
+            ..FormatOptions::default()
+        }
+    }
+}
+
+// Validates the shared query parameters, returning the 400 response to send if one is invalid.
+// `default_format` applies when the query has no format parameter.
+fn parse_lyrics_query(query: &std::collections::HashMap<String, String>, default_format: &str, debug_endpoints: bool) -> Result<LyricsQuery, HttpResponse> {
+    // Get format parameter, accepting any case such as "LRC"
+    let format = query.get("format").map_or(default_format.to_string(), |format| format.to_ascii_lowercase());
+    
+    // The untransformed Spotify response exposes internal structure, so it only exists in debug mode
+    if format == "raw" && !debug_endpoints {
+        return Err(HttpResponse::NotFound()
+            .json(error_body("DEBUG_DISABLED", "format 'raw' is only available when debug_endpoints is enabled!")));
     }
     
-    info!("Getting lyrics for track: {}, format: {}", track_id, format);
+    // Only accept the supported formats, plus "raw" in debug mode
+    let supported_formats = supported_formats(debug_endpoints);
+    if !supported_formats.contains(&format.as_str()) {
+        let mut body = error_body("INVALID_FORMAT", &format!("format parameter must be one of {}!", quoted_list(&supported_formats)));
+        body["supported_formats"] = json!(supported_formats);
+        
+        return Err(HttpResponse::BadRequest().json(body));
+    }
     
-    match spotify.get_formatted_lyrics(&track_id, &format).await {
-        Ok(lyrics_json) => {
-            HttpResponse::Ok().json(lyrics_json)
+    // Optional timestamp shift in milliseconds, positive or negative
+    let offset_ms = match query.get("offset") {
+        Some(offset) => match offset.parse::<i64>() {
+            Ok(offset_ms) => offset_ms,
+            Err(_) => {
+                return Err(HttpResponse::BadRequest()
+                    .json(error_body("INVALID_PARAM", "offset parameter must be an integer number of milliseconds!")));
+            }
         },
-        Err(e) => {
-            match e {
-                SpotifyException::Generic(ref message) if message == "lyrics for this track is not available on spotify!" => {
-                    HttpResponse::NotFound()
-                        .json(json!({
-                            "error": true,
-                            "message": "lyrics for this track is not available on spotify!"
-                        }))
-                },
-                _ => {
-                    eprintln!("Error fetching lyrics: {}", e);
-                    HttpResponse::InternalServerError()
-                        .json(json!({
-                            "error": true,
-                            "message": format!("Failed to fetch lyrics: {}", e)
-                        }))
-                }
+        None => 0,
+    };
+    
+    // Spotify may serve lyrics from a different provider when vocal removal is requested
+    let vocal_removal = match query_param(query, "vocalRemoval").map(String::as_str) {
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "vocalRemoval parameter must be either 'true' or 'false'!")));
+        }
+    };
+    
+    // Market must be an ISO 3166-1 alpha-2 country code, defaulting to the token's market
+    let market = match query.get("market") {
+        Some(market) if market.len() == 2 && market.chars().all(|c| c.is_ascii_alphabetic()) => {
+            market.to_ascii_uppercase()
+        },
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "market parameter must be a two-letter ISO 3166-1 country code!")));
+        },
+        None => "from_token".to_string(),
+    };
+    
+    // Optional translation language, e.g. "en" or "pt-BR"
+    let lang = match query.get("lang") {
+        Some(lang) if !lang.is_empty() && lang.len() <= 16 && lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => {
+            Some(lang.clone())
+        },
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "lang parameter must be a language code like 'en' or 'pt-BR'!")));
+        },
+        None => None,
+    };
+    
+    // Optional JSONP callback; only plain (optionally dotted) identifiers are allowed to prevent XSS
+    let callback = match query.get("callback") {
+        Some(callback) if is_safe_callback(callback) => Some(callback.clone()),
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "callback parameter must be a valid JavaScript identifier!")));
+        },
+        None => None,
+    };
+    
+    // Indented JSON for reading in a browser or terminal
+    let pretty = match query.get("pretty").map(String::as_str) {
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "pretty parameter must be either 'true' or 'false'!")));
+        }
+    };
+    
+    // Title, artists, album and duration can only be merged into JSON responses
+    let include_metadata = match query_param(query, "includeMetadata").map(String::as_str) {
+        Some("true") if is_text_format(&format) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "includeMetadata is only available for JSON formats!")));
+        },
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "includeMetadata parameter must be either 'true' or 'false'!")));
+        }
+    };
+    
+    // Words are returned exactly as Spotify sends them unless the client asks for trimming
+    let trim_words = match query_param(query, "trimWords").map(String::as_str) {
+        Some("true") if is_text_format(&format) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "trimWords is only available for JSON formats!")));
+        },
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "trimWords parameter must be either 'true' or 'false'!")));
+        }
+    };
+    
+    // Interlude markers are kept unless the client asks for them to be left out
+    let skip_empty = match query_param(query, "skipEmpty").map(String::as_str) {
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "skipEmpty parameter must be either 'true' or 'false'!")));
+        }
+    };
+    
+    // Optional window of lines for paging through long lyrics; subtitle documents are never sliced
+    let line_offset = match query_param(query, "lineOffset").map(|offset| offset.parse::<usize>()) {
+        Some(Ok(line_offset)) => Some(line_offset),
+        Some(Err(_)) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "lineOffset parameter must be a non-negative integer!")));
+        },
+        None => None,
+    };
+    
+    let line_limit = match query_param(query, "lineLimit").map(|limit| limit.parse::<usize>()) {
+        Some(Ok(line_limit)) if line_limit > 0 => Some(line_limit),
+        Some(_) => {
+            return Err(HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "lineLimit parameter must be a positive integer!")));
+        },
+        None => None,
+    };
+    
+    let page = if line_offset.is_none() && line_limit.is_none() {
+        None
+    } else if is_text_format(&format) {
+        return Err(HttpResponse::BadRequest()
+            .json(error_body("INVALID_PARAM", "lineOffset and lineLimit are only available for JSON formats!")));
+    } else {
+        Some(LinePage {
+            offset: line_offset.unwrap_or(0),
+            limit: line_limit,
+        })
+    };
+    
+    // ID tags for LRC output, taking precedence over the fetched track metadata
+    let lrc_tags = LrcTags {
+        title: query.get("title").cloned(),
+        artist: query.get("artist").cloned(),
+        album: query.get("album").cloned(),
+        length_ms: None,
+    };
+    
+    Ok(LyricsQuery {
+        format,
+        offset_ms,
+        vocal_removal,
+        market,
+        lang,
+        callback,
+        pretty,
+        include_metadata,
+        trim_words,
+        skip_empty,
+        page,
+        lrc_tags,
+    })
+}
+
+// Looks up a camelCase query parameter, also accepting its snake_case spelling,
+// e.g. include_metadata for includeMetadata
+fn query_param<'a>(query: &'a std::collections::HashMap<String, String>, name: &str) -> Option<&'a String> {
+    query.get(name).or_else(|| query.get(&to_snake_case(name)))
+}
+
+// Picks the lyrics format from the Accept header, used when the query has no format parameter.
+// The known media type with the highest q-value wins, ties going to the one listed first;
+// types refused with q=0 are skipped.
+fn negotiated_format(req: &HttpRequest) -> &'static str {
+    let accept = req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    
+    let mut candidates: Vec<(f32, &'static str)> = accept.split(',')
+        .filter_map(|media_range| {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next()?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            
+            if quality <= 0.0 {
+                return None;
+            }
+            
+            let format = match media_type.to_ascii_lowercase().as_str() {
+                "application/json" => "id3",
+                "application/x-subrip" => "srt",
+                "text/vtt" => "vtt",
+                "application/ttml+xml" => "ttml",
+                "application/x-ndjson" => "json-lines",
+                "text/plain" => "text",
+                _ => return None,
+            };
+            
+            Some((quality, format))
+        })
+        .collect();
+    
+    // A stable sort keeps the listed order among equal q-values
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    candidates.first().map_or("id3", |(_, format)| format)
+}
+
+// Renames the keys of a JSON lyrics body to snake_case when the server is configured for it,
+// e.g. startTimeMs to start_time_ms. Values such as LINE_SYNCED are left alone.
+fn apply_response_case(body: &mut serde_json::Value, response_case: ResponseCase) {
+    if response_case == ResponseCase::Snake {
+        snake_case_keys(body);
+    }
+}
+
+fn snake_case_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    snake_case_keys(&mut value);
+                    (to_snake_case(&key), value)
+                })
+                .collect();
+        },
+        serde_json::Value::Array(items) => items.iter_mut().for_each(snake_case_keys),
+        _ => {},
+    }
+}
+
+fn to_snake_case(key: &str) -> String {
+    let mut snake = String::with_capacity(key.len() + 4);
+    for (i, c) in key.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                snake.push('_');
             }
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
         }
     }
+    snake
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    // Initialize the logger
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+// Renders formatted lyrics with their content type: subtitle, NDJSON and text formats are
+// plain-text documents, everything else goes through render_json
+fn render_lyrics(format: &str, body: serde_json::Value, callback: Option<&str>, pretty: bool) -> (&'static str, String) {
+    match body {
+        serde_json::Value::String(document) => {
+            let content_type = match format {
+                "srt" => "application/x-subrip; charset=utf-8",
+                "ttml" => "application/ttml+xml; charset=utf-8",
+                "json-lines" => "application/x-ndjson",
+                "text" => "text/plain; charset=utf-8",
+                _ => "text/vtt; charset=utf-8",
+            };
+            
+            (content_type, document)
+        },
+        body => render_json(&body, callback, pretty),
+    }
+}
+
+// Renders a JSON body, optionally indented and wrapped as JSONP when the client asked
+// for a callback, returning the content type to send along with it
+fn render_json(body: &serde_json::Value, callback: Option<&str>, pretty: bool) -> (&'static str, String) {
+    let body = if pretty {
+        serde_json::to_string_pretty(body).unwrap_or_else(|_| body.to_string())
+    } else {
+        body.to_string()
+    };
     
-    // Load configuration from file or environment variables
-    let config = Config::load();
+    match callback {
+        Some(callback) => ("application/javascript; charset=utf-8", format!("{}({});", callback, body)),
+        None => ("application/json", body),
+    }
+}
+
+// Lyrics never change for a given track and format, so browsers and CDNs may cache them for a day,
+// or just browsers when `private`. The ETag is a hash of the body, and a matching If-None-Match gets an empty 304.
+fn cacheable_response(req: &HttpRequest, content_type: &str, body: String, private: bool) -> HttpResponse {
+    let etag = format!("\"{}\"", Sha1::digest(body.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>());
     
-    if !config.is_valid() {
-        error!("No SP_DC token found. Please set it in your config file or environment variable.");
-        error!("Create a config file at one of these locations:");
-        error!("  - ./config.toml");
-        error!("  - ~/.config/spotifylyricsapi/config.toml");
-        error!("  - /etc/spotifylyricsapi/config.toml");
-        error!("With the content: sp_dc = \"your_spotify_cookie_value\"");
-        error!("Or set the SP_DC environment variable.");
-        std::process::exit(1);
+    let not_modified = req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+    
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    
+    response
+        .insert_header((header::CACHE_CONTROL, if private { PRIVATE_LYRICS_CACHE_CONTROL } else { LYRICS_CACHE_CONTROL }))
+        .insert_header((header::ETAG, etag))
+        // The format may have been negotiated from Accept, so shared caches must key on it
+        .insert_header((header::VARY, "Accept"));
+    
+    if not_modified {
+        response.finish()
+    } else {
+        response.content_type(content_type).body(body)
+    }
+}
+
+// Validates the shared query parameters and fetches the lyrics of a track or transcript of an episode
+async fn lyrics_response(
+    req: &HttpRequest,
+    item: &SpotifyItem,
+    query: &std::collections::HashMap<String, String>,
+    data: &AppState
+) -> HttpResponse {
+    // Get the spotify client from state
+    let spotify = &data.spotify;
+    
+    let mut options = match parse_lyrics_query(query, negotiated_format(req), data.debug_endpoints) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    
+    // Spotify's lyrics response is passed through byte for byte, skipping the line-limit guard
+    if let (true, SpotifyItem::Track(track_id)) = (options.format == "raw", item) {
+        info!("Getting raw lyrics for track: {}, vocalRemoval: {}, market: {}", loggable(track_id), options.vocal_removal, options.market);
+        
+        return match spotify.get_lyrics(track_id, options.vocal_removal, &options.market).await {
+            Ok(raw_lyrics) => {
+                req.extensions_mut().insert(LyricsLogFields {
+                    track_id: track_id.clone(),
+                    format: options.format,
+                    cache_hit: false,
+                });
+                
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(raw_lyrics)
+            },
+            Err(e) => spotify_error_response(&e, "Failed to fetch lyrics"),
+        };
     }
     
-    info!("Starting server at http://127.0.0.1:{}", config.port);
+    // Transcripts have no vocal removal, market or translations, so those options only apply to tracks
+    let lyrics = match item {
+        SpotifyItem::Track(track_id) => {
+            info!("Getting lyrics for track: {}, format: {}, offset: {}ms, vocalRemoval: {}, market: {}", loggable(track_id), options.format, options.offset_ms, options.vocal_removal, options.market);
+            spotify.get_formatted_lyrics(track_id, &options.format, options.vocal_removal, &options.market, options.format_options(), options.page).await
+        },
+        SpotifyItem::Episode(episode_id) => {
+            info!("Getting transcript for episode: {}, format: {}, offset: {}ms", loggable(episode_id), options.format, options.offset_ms);
+            spotify.get_formatted_transcript(episode_id, &options.format, options.format_options(), options.page).await
+        },
+    };
+    
+    match lyrics {
+        Ok(lyrics) => {
+            req.extensions_mut().insert(LyricsLogFields {
+                track_id: item.id().to_string(),
+                format: options.format.clone(),
+                cache_hit: lyrics.cache_hit,
+            });
+            
+            let mut body = lyrics.body;
+            if options.trim_words {
+                trim_line_words(&mut body);
+            }
+            if let (true, SpotifyItem::Track(track_id)) = (options.include_metadata, item) {
+                match add_track_metadata(spotify, track_id, &mut body).await {
+                    Ok(metadata) => options.lrc_tags.fill_from(&metadata),
+                    Err(e) => return spotify_error_response(&e, "Failed to fetch track metadata"),
+                }
+            }
+            options.lrc_tags.apply(&mut body, &options.format);
+            apply_response_case(&mut body, data.response_case);
+            
+            let (content_type, body) = render_lyrics(&options.format, body, options.callback.as_deref(), options.pretty);
+            cacheable_response(req, content_type, body, data.api_key.is_some())
+        },
+        Err(e) => spotify_error_response(&e, "Failed to fetch lyrics"),
+    }
+}
 
-    // Create a new Spotify client
-    let spotify = Spotify::new(config.sp_dc);
+// Adds the track's title, artists, album and duration to a JSON lyrics body under `track`,
+// returning them for the LRC ID tags
+async fn add_track_metadata(spotify: &Spotify, track_id: &str, body: &mut serde_json::Value) -> Result<TrackMetadata, SpotifyException> {
+    let metadata = spotify.get_track_metadata(track_id).await?;
     
-    // Create application state
-    let app_state = web::Data::new(AppState {
-        spotify: Mutex::new(spotify),
+    if let Some(body) = body.as_object_mut() {
+        body.insert("track".to_string(), json!(metadata));
+    }
+    
+    Ok(metadata)
+}
+
+// Handler for /album/{album_id}, returning the lyrics of every track on the album
+async fn get_album_lyrics(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let options = match parse_lyrics_query(&query, "id3", data.debug_endpoints) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    
+    let album_id = path.into_inner();
+    let spotify = &data.spotify;
+    
+    let track_ids = match spotify.get_album_track_ids(&album_id).await {
+        Ok(track_ids) => track_ids,
+        Err(e) => return spotify_error_response(&e, "Failed to fetch album tracks"),
+    };
+    
+    info!("Getting lyrics for {} tracks of album: {}, format: {}", track_ids.len(), loggable(&album_id), options.format);
+    
+    let tracks = track_list_lyrics(spotify, track_ids, &options).await;
+    
+    let mut body = json!({
+        "error": false,
+        "albumId": album_id,
+        "tracks": tracks
     });
+    
+    apply_response_case(&mut body, data.response_case);
+    
+    let (content_type, body) = render_json(&body, options.callback.as_deref(), options.pretty);
+    
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .body(body)
+}
 
-    // Start the HTTP server
-    HttpServer::new(move || {
-        // Configure CORS
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
-        
-        App::new()
-            .wrap(Logger::default())
-            .wrap(cors)
-            .app_data(app_state.clone())
-            .route("/", web::get().to(get_lyrics))
+// Handler for /playlist/{playlist_id}, returning the lyrics of the tracks on a playlist
+async fn get_playlist_lyrics(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let options = match parse_lyrics_query(&query, "id3", data.debug_endpoints) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    
+    let playlist_id = path.into_inner();
+    let spotify = &data.spotify;
+    
+    let mut track_ids = match spotify.get_playlist_track_ids(&playlist_id, MAX_PLAYLIST_TRACKS).await {
+        Ok(track_ids) => track_ids,
+        Err(e) => return spotify_error_response(&e, "Failed to fetch playlist tracks"),
+    };
+    
+    let truncated = track_ids.len() > MAX_PLAYLIST_TRACKS;
+    track_ids.truncate(MAX_PLAYLIST_TRACKS);
+    
+    info!("Getting lyrics for {} tracks of playlist: {}, format: {}", track_ids.len(), loggable(&playlist_id), options.format);
+    
+    let tracks = track_list_lyrics(spotify, track_ids, &options).await;
+    
+    let mut body = json!({
+        "error": false,
+        "playlistId": playlist_id,
+        "tracks": tracks
+    });
+    if truncated {
+        body["truncated"] = json!(true);
+    }
+    
+    apply_response_case(&mut body, data.response_case);
+    
+    let (content_type, body) = render_json(&body, options.callback.as_deref(), options.pretty);
+    
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .body(body)
+}
+
+// Fetches the lyrics of an album's or playlist's tracks a few at a time, keeping their order.
+// A track without lyrics gets its own error object rather than failing the whole list.
+async fn track_list_lyrics(spotify: &Spotify, track_ids: Vec<String>, options: &LyricsQuery) -> Vec<serde_json::Value> {
+    stream::iter(track_ids)
+        .map(|track_id| {
+            async move {
+                let lyrics = async {
+                    let mut body = spotify.get_formatted_lyrics(&track_id, &options.format, options.vocal_removal, &options.market, options.format_options(), options.page).await?.body;
+                    if options.trim_words {
+                        trim_line_words(&mut body);
+                    }
+                    if options.include_metadata {
+                        // Title and artist overrides name a single track, so listed tracks only get their own tags
+                        let metadata = add_track_metadata(spotify, &track_id, &mut body).await?;
+                        let mut lrc_tags = LrcTags::default();
+                        lrc_tags.fill_from(&metadata);
+                        lrc_tags.apply(&mut body, &options.format);
+                    }
+                    Ok::<_, SpotifyException>(body)
+                };
+                
+                match lyrics.await {
+                    Ok(lyrics) => json!({
+                        "trackId": track_id,
+                        "error": false,
+                        "lyrics": lyrics
+                    }),
+                    Err(e) => json!({
+                        "trackId": track_id,
+                        "error": true,
+                        "code": e.code(),
+                        "message": e.to_string()
+                    }),
+                }
+            }
+        })
+        .buffered(TRACK_LIST_FETCH_CONCURRENCY)
+        .collect()
+        .await
+}
+
+// Handler for /stream/{track_id}, sending each synced line as a server-sent event when it
+// comes up in playback. Playback started at `start` (Unix ms), or when the client connected.
+async fn stream_lyrics(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let options = match parse_lyrics_query(&query, "id3", data.debug_endpoints) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    
+    let now_ms = chrono::Utc::now().timestamp_millis().max(0) as u64;
+    let start_ms = match query.get("start") {
+        Some(start) => match start.parse::<u64>() {
+            Ok(start_ms) => start_ms,
+            Err(_) => {
+                return HttpResponse::BadRequest()
+                    .json(error_body("INVALID_PARAM", "start must be a Unix timestamp in milliseconds!"));
+            }
+        },
+        None => now_ms,
+    };
+    
+    let track_id = path.into_inner();
+    info!("Streaming lyrics for track: {}, offset: {}ms, start: {}", loggable(&track_id), options.offset_ms, start_ms);
+    
+    let mut lyrics = match data.spotify.get_formatted_lyrics(&track_id, "id3", options.vocal_removal, &options.market, options.format_options(), None).await {
+        Ok(lyrics) => lyrics.body,
+        Err(e) => return spotify_error_response(&e, "Failed to fetch lyrics"),
+    };
+    
+    if lyrics["syncType"] == "UNSYNCED" {
+        return HttpResponse::BadRequest()
+            .json(error_body("UNSYNCED_LYRICS", "lyrics for this track are not time-synced!"));
+    }
+    
+    // Line times are relative to the start of playback, which may be in the past or future
+    let now = tokio::time::Instant::now();
+    let playback_start = if start_ms <= now_ms {
+        now.checked_sub(Duration::from_millis(now_ms - start_ms)).unwrap_or(now)
+    } else {
+        now + Duration::from_millis(start_ms - now_ms)
+    };
+    
+    let lines = match lyrics["lines"].take() {
+        serde_json::Value::Array(lines) => lines,
+        _ => Vec::new(),
+    };
+    let response_case = data.response_case;
+    
+    // Lines that are already due go out immediately. When the client disconnects actix drops
+    // the stream, which cancels the pending sleep.
+    let events = stream::iter(lines)
+        .then(move |mut line| async move {
+            let start_time_ms = line["startTimeMs"].as_str()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(0);
+            tokio::time::sleep_until(playback_start + Duration::from_millis(start_time_ms)).await;
+            
+            apply_response_case(&mut line, response_case);
+            Ok::<_, actix_web::Error>(web::Bytes::from(format!("data: {}\n\n", line)))
+        })
+        // Tell EventSource clients the song is over, so they close instead of reconnecting
+        .chain(stream::once(async {
+            Ok(web::Bytes::from_static(b"event: end\ndata: {}\n\n"))
+        }));
+    
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Compression would buffer events until enough data has accumulated
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(events)
+}
+
+// Every format the lyrics endpoints accept, which includes "raw" only in debug mode
+fn supported_formats(debug_endpoints: bool) -> Vec<&'static str> {
+    let mut formats = SUPPORTED_FORMATS.to_vec();
+    if debug_endpoints {
+        formats.push("raw");
+    }
+    formats
+}
+
+// Renders ["a", "b", "c"] as "'a', 'b' or 'c'" for error messages
+fn quoted_list(items: &[&str]) -> String {
+    let quoted: Vec<String> = items.iter().map(|item| format!("'{}'", item)).collect();
+    match quoted.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => quoted.concat(),
+    }
+}
+
+// Checks that a JSONP callback is a JavaScript identifier path like `cb` or `app.onLyrics`
+fn is_safe_callback(callback: &str) -> bool {
+    callback.len() <= 64
+        && callback.split('.').all(|part| {
+            let mut chars = part.chars();
+            matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
+// Error JSON with a stable `code` for clients and a `message` for humans
+fn error_body(code: &str, message: &str) -> serde_json::Value {
+    json!({
+        "error": true,
+        "code": code,
+        "message": message
     })
-    .bind(("0.0.0.0", config.port))?
-    .run()
-    .await
+}
+
+// Maps a Spotify client error to the matching HTTP error response
+fn spotify_error_response(e: &SpotifyException, context: &str) -> HttpResponse {
+    match e {
+        SpotifyException::NotFound(message)
+        | SpotifyException::TrackNotFound(message)
+        | SpotifyException::TranscriptNotFound(message)
+        | SpotifyException::AlbumNotFound(message)
+        | SpotifyException::PlaylistNotFound(message) => {
+            HttpResponse::NotFound()
+                .json(error_body(e.code(), message))
+        },
+        SpotifyException::InvalidUrl(message) => {
+            HttpResponse::BadRequest()
+                .json(error_body(e.code(), message))
+        },
+        SpotifyException::AuthError(_) => {
+            // A bad sp_dc is a configuration problem on our side, not a client error
+            error!("{}: {}", context, e);
+            HttpResponse::BadGateway()
+                .json(error_body(e.code(), "lyrics backend authentication failed; check SP_DC"))
+        },
+        SpotifyException::InvalidResponse(_) => {
+            error!("{}: {}", context, e);
+            HttpResponse::BadGateway()
+                .json(error_body(e.code(), "spotify returned an unexpected lyrics response!"))
+        },
+        SpotifyException::RateLimited(retry_after) => {
+            warn!("{}: {}", context, e);
+            HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, (retry_after.as_secs_f64().ceil().max(1.0) as u64).to_string()))
+                .json(error_body(e.code(), "spotify is rate limiting requests, please try again later!"))
+        },
+        SpotifyException::Timeout(_) => {
+            warn!("{}: {}", context, e);
+            HttpResponse::GatewayTimeout()
+                .json(error_body(e.code(), "timed out waiting for spotify, please try again!"))
+        },
+        _ => {
+            error!("{}: {}", context, e);
+            HttpResponse::InternalServerError()
+                .json(error_body(e.code(), &format!("{}: {}", context, e)))
+        }
+    }
+}
+
+// Handler for /version, identifying the running build so deploys can be confirmed
+async fn version() -> impl Responder {
+    let build_timestamp = env!("BUILD_TIMESTAMP").parse::<i64>().ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|built_at| built_at.to_rfc3339());
+    
+    HttpResponse::Ok().json(json!({
+        "error": false,
+        "version": env!("CARGO_PKG_VERSION"),
+        "gitHash": env!("BUILD_GIT_HASH"),
+        "buildTimestamp": build_timestamp
+    }))
+}
+
+// Handler for /health. By default it only confirms the process is up, for load balancer
+// checks; with verbose=true it also checks Spotify, the access token and the disk caches,
+// answering 503 when lyrics can't currently be served.
+async fn health(
+    req: HttpRequest,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    match query.get("verbose").map(String::as_str) {
+        Some("true") => {},
+        Some("false") | None => {
+            return HttpResponse::Ok().json(json!({
+                "error": false,
+                "status": "ok"
+            }));
+        },
+        Some(_) => {
+            return HttpResponse::BadRequest()
+                .json(error_body("INVALID_PARAM", "verbose parameter must be either 'true' or 'false'!"));
+        }
+    }
+    
+    // The verbose checks call Spotify, so they are only open to authorized clients
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let report = data.spotify.health_check().await;
+    let healthy = report.upstream_reachable && report.token_valid && report.cache_writable != Some(false);
+    
+    let mut response = if healthy {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    
+    response.json(json!({
+        "error": false,
+        "status": if healthy { "ok" } else { "degraded" },
+        "upstreamReachable": report.upstream_reachable,
+        "tokenValid": report.token_valid,
+        "cacheWritable": report.cache_writable
+    }))
+}
+
+// Handler for /stats, a JSON summary of token and cache state for quick ops checks
+async fn stats(
+    req: HttpRequest,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let expiration_ms = data.spotify.token_expiration_ms();
+    let now_ms = chrono::Utc::now().timestamp_millis().max(0) as u64;
+    let expires_in_secs = expiration_ms.map(|expiration_ms| expiration_ms.saturating_sub(now_ms) / 1000);
+    
+    HttpResponse::Ok().json(json!({
+        "error": false,
+        "accessTokenExpirationTimestampMs": expiration_ms,
+        "accessTokenExpiresInSecs": expires_in_secs,
+        "lyricsCacheEntries": data.spotify.lyrics_cache_len(),
+        "lyricsCacheHits": METRICS.lyrics_cache_hits_total.get(),
+        "lyricsCacheMisses": METRICS.lyrics_cache_misses_total.get(),
+        "requestsTotal": METRICS.requests_served()
+    }))
+}
+
+// Handler for looking up tracks by name and artist
+async fn search(
+    req: HttpRequest,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let q = match query.get("q") {
+        Some(q) if !q.trim().is_empty() => q,
+        _ => {
+            return HttpResponse::BadRequest()
+                .json(error_body("MISSING_PARAM", "q parameter is required!"));
+        }
+    };
+    
+    info!("Searching tracks for: {}", loggable(q));
+    
+    match data.spotify.search_tracks(q, 10).await {
+        Ok(tracks) => {
+            HttpResponse::Ok().json(SearchResponse {
+                error: false,
+                tracks,
+            })
+        },
+        Err(e) => spotify_error_response(&e, "Failed to search tracks"),
+    }
+}
+
+// Handler for discarding the cached access token and fetching a new one, e.g. after rotating SP_DC.
+// Without an API key anyone could force refreshes, so the route only exists when one is configured.
+async fn refresh_token(
+    req: HttpRequest,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if data.api_key.is_none() {
+        return HttpResponse::NotFound()
+            .json(error_body("ADMIN_DISABLED", "admin endpoints are only available when an API key is configured!"));
+    }
+    
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    match data.spotify.force_refresh_token().await {
+        Ok(expiration_ms) => {
+            HttpResponse::Ok().json(json!({
+                "error": false,
+                "accessTokenExpirationTimestampMs": expiration_ms
+            }))
+        },
+        Err(e) => spotify_error_response(&e, "Failed to refresh token"),
+    }
+}
+
+// Handler for POST /convert, formatting lyrics JSON the client already has, in the shape of
+// Spotify's color-lyrics response, without fetching anything from Spotify
+async fn convert(
+    req: HttpRequest,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    body: web::Bytes,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let options = match parse_lyrics_query(&query, "id3", true) {
+        Ok(options) => options,
+        Err(response) => return response,
+    };
+    
+    // raw would only echo the body back
+    if options.format == "raw" {
+        return HttpResponse::BadRequest()
+            .json(error_body("INVALID_PARAM", "format 'raw' is not available for conversion!"));
+    }
+    
+    // Without a track ID there is nothing to look the metadata up by
+    if options.include_metadata {
+        return HttpResponse::BadRequest()
+            .json(error_body("INVALID_PARAM", "includeMetadata is not available for conversion!"));
+    }
+    
+    let lyrics_data = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(lyrics_data) => lyrics_data,
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .json(error_body("INVALID_BODY", "body must be Spotify's lyrics JSON, like {\"lyrics\": {\"lines\": [...]}}!"));
+        }
+    };
+    
+    info!("Converting lyrics to format: {}", options.format);
+    
+    let mut body = match data.spotify.convert_lyrics(lyrics_data, &options.format, options.format_options(), options.page) {
+        Ok(body) => body,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .json(error_body("INVALID_BODY", &e.to_string()));
+        }
+    };
+    
+    if options.trim_words {
+        trim_line_words(&mut body);
+    }
+    options.lrc_tags.apply(&mut body, &options.format);
+    apply_response_case(&mut body, data.response_case);
+    
+    let (content_type, body) = render_lyrics(&options.format, body, options.callback.as_deref(), options.pretty);
+    
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .body(body)
+}
+
+// Body of POST /preload
+#[derive(serde::Deserialize)]
+struct PreloadRequest {
+    trackids: Vec<String>,
+}
+
+// Handler for warming the lyrics cache before playback, e.g. for the tracks of a playlist.
+// Tracks are fetched in the background with the default options, so a later plain
+// GET /?trackid=... is a cache hit; the response only says how many were queued.
+async fn preload(
+    req: HttpRequest,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    body: web::Bytes,
+    data: web::Data<AppState>
+) -> impl Responder {
+    if !auth::is_authorized(&req, &query, data.api_key.as_deref()) {
+        return unauthorized();
+    }
+    
+    let track_ids = match serde_json::from_slice::<PreloadRequest>(&body) {
+        Ok(request) => request.trackids,
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .json(error_body("INVALID_BODY", "body must be a JSON object like {\"trackids\": [\"...\"]}!"));
+        }
+    };
+    
+    if track_ids.len() > MAX_PRELOAD_TRACKS {
+        return HttpResponse::BadRequest()
+            .json(error_body("INVALID_BODY", &format!("at most {} trackids can be preloaded at once!", MAX_PRELOAD_TRACKS)));
+    }
+    
+    let queued = track_ids.len();
+    info!("Preloading lyrics for {} tracks", queued);
+    
+    // Failures only matter to the requests that come later, which will retry the fetch
+    actix_web::rt::spawn(async move {
+        stream::iter(track_ids)
+            .for_each_concurrent(PRELOAD_CONCURRENCY, |track_id| {
+                let data = data.clone();
+                async move {
+                    if let Err(e) = data.spotify.get_formatted_lyrics(&track_id, "id3", false, "from_token", FormatOptions::default(), None).await {
+                        warn!("Failed to preload lyrics for track {}: {}", loggable(&track_id), e);
+                    }
+                }
+            })
+            .await;
+    });
+    
+    HttpResponse::Accepted().json(json!({
+        "error": false,
+        "queued": queued
+    }))
+}
+
+// actix's access logger, logging routes rather than request lines in no_store mode
+fn text_access_logger(no_store: bool) -> Logger {
+    if !no_store {
+        return Logger::new(ACCESS_LOG_FORMAT).custom_request_replace("client", clientip::client_ip);
+    }
+    
+    Logger::new(REDACTED_ACCESS_LOG_FORMAT).custom_response_replace("route", |res| {
+        let req = res.request();
+        format!("{} {}", req.method(), req.match_pattern().unwrap_or_else(|| "-".to_string()))
+    })
+}
+
+// An empty allowlist or an explicit "*" entry keeps CORS open to every origin
+fn allows_any_origin(allowed_origins: &[String]) -> bool {
+    allowed_origins.is_empty() || allowed_origins.iter().any(|o| o == "*")
+}
+
+// CORS policy restricted to `allowed_origins` unless they allow any origin
+fn cors(allowed_origins: &[String]) -> Cors {
+    let mut cors = Cors::default()
+        .allow_any_method()
+        .allow_any_header()
+        .expose_headers([requestid::REQUEST_ID_HEADER])
+        .max_age(3600);
+    
+    if allows_any_origin(allowed_origins) {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+    
+    cors
+}
+
+// The requested listen addresses that didn't end up bound, e.g. the IPv4 half of `dual` where
+// IPv6 sockets already accept IPv4. Port 0 matches whatever port was assigned.
+fn unbound_addrs(requested: &[std::net::SocketAddr], bound: &[std::net::SocketAddr]) -> Vec<std::net::SocketAddr> {
+    requested.iter()
+        .filter(|addr| !bound.iter().any(|bound| {
+            bound.ip() == addr.ip() && (addr.port() == 0 || bound.port() == addr.port())
+        }))
+        .copied()
+        .collect()
+}
+
+// Points the client at the Spotify endpoints the config overrides, e.g. a regional proxy
+fn with_endpoint_overrides(mut spotify: Spotify, config: &Config) -> Spotify {
+    if let Some(url) = &config.token_url {
+        spotify = spotify.with_token_url(url.clone());
+    }
+    if let Some(url) = &config.lyrics_url {
+        spotify = spotify.with_lyrics_url(url.clone());
+    }
+    if let Some(url) = &config.server_time_url {
+        spotify = spotify.with_server_time_url(url.clone());
+    }
+    if let Some(url) = &config.web_player_url {
+        spotify = spotify.with_web_player_url(url.clone());
+    }
+    if let Some(url) = &config.tracks_url {
+        spotify = spotify.with_tracks_url(url.clone());
+    }
+    if let Some(url) = &config.playlist_url {
+        spotify = spotify.with_playlist_url(url.clone());
+    }
+    if let Some(url) = &config.transcript_url {
+        spotify = spotify.with_transcript_url(url.clone());
+    }
+    
+    spotify
+}
+
+// Resolves once the process receives SIGINT (Ctrl+C) or, on Unix, SIGTERM
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+    }
+    
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Every route, under base_path, which is empty unless the server is hosted at a subpath
+fn api_scope(base_path: &str) -> actix_web::Scope {
+    web::scope(base_path)
+        // HEAD runs the same lookup so clients can check for lyrics without downloading them
+        .route("/", web::get().to(get_lyrics))
+        .route("/", web::head().to(get_lyrics))
+        .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        .route("/lyrics/{track_id}", web::head().to(get_lyrics_by_path))
+        .route("/episode/{episode_id}", web::get().to(get_transcript_by_path))
+        .route("/episode/{episode_id}", web::head().to(get_transcript_by_path))
+        .route("/album/{album_id}", web::get().to(get_album_lyrics))
+        .route("/playlist/{playlist_id}", web::get().to(get_playlist_lyrics))
+        .route("/stream/{track_id}", web::get().to(stream_lyrics))
+        .route("/search", web::get().to(search))
+        .route("/metrics", web::get().to(metrics::metrics_endpoint))
+        .route("/openapi.json", web::get().to(openapi::openapi_endpoint))
+        .route("/stats", web::get().to(stats))
+        .route("/health", web::get().to(health))
+        .route("/version", web::get().to(version))
+        .route("/preload", web::post().to(preload))
+        .route("/convert", web::post().to(convert))
+        .route("/admin/refresh-token", web::post().to(refresh_token))
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Initialize the logger
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    
+    let cli = Cli::parse();
+    
+    // Load configuration from file or environment variables
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    cli.apply_overrides(&mut config);
+    
+    if config.no_store {
+        privacy::enable_log_redaction();
+        info!("no_store enabled: nothing is written to disk and IDs are left out of logs");
+    }
+    
+    if !config.is_valid() {
+        error!("No SP_DC token found. Please set it in your config file or environment variable.");
+        error!("Create a config file at one of these locations:");
+        for path in config::config_search_paths() {
+            error!("  - {}", path.display());
+        }
+        error!("  - or any path passed with --config or CONFIG_PATH");
+        error!("With the content: sp_dc = \"your_spotify_cookie_value\"");
+        error!("Or set the SP_DC environment variable.");
+        std::process::exit(1);
+    }
+    
+    // TLS needs both halves; a lone cert or key is almost certainly a deployment mistake
+    let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => match tls::load_server_config(cert_path, key_path) {
+            Ok(tls_config) => Some(tls_config),
+            Err(e) => {
+                error!("Failed to load TLS certificate: {}", e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("Both tls_cert_path and tls_key_path must be set to enable TLS.");
+            std::process::exit(1);
+        }
+    };
+    
+    let listen_addrs = match config.listen_addrs() {
+        Ok(listen_addrs) => listen_addrs,
+        Err(e) => {
+            error!("Invalid host {}: {}", config.host, e);
+            std::process::exit(1);
+        }
+    };
+    
+    let trusted_proxies = match TrustedProxies::parse(&config.trusted_proxies) {
+        Ok(trusted_proxies) => trusted_proxies,
+        Err(entry) => {
+            error!("Invalid trusted_proxies entry: {}", entry);
+            std::process::exit(1);
+        }
+    };
+    
+    if !trusted_proxies.is_empty() {
+        info!("Taking client addresses from X-Forwarded-For for proxies in {}", config.trusted_proxies.join(", "));
+    }
+    
+    let trusted_proxies = web::Data::new(trusted_proxies);
+    
+    let totp_secret = match decode_totp_secret(&config.totp_secret) {
+        Ok(totp_secret) => totp_secret,
+        Err(e) => {
+            error!("Invalid totp_secret: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+
+    // Create a new Spotify client
+    let mut spotify = Spotify::new(config.sp_dc.clone())
+        .with_request_timeout(Duration::from_secs(config.request_timeout_secs))
+        .with_client_identity(config.user_agent.clone(), config.app_platform.clone(), config.spotify_app_version.clone())
+        .with_lyrics_cache(
+            Duration::from_secs(config.lyrics_cache_ttl_secs),
+            config.lyrics_cache_capacity,
+        )
+        .with_missing_lyrics_cache(
+            Duration::from_secs(config.negative_cache_ttl_secs),
+            config.lyrics_cache_capacity,
+        )
+        .with_metadata_cache(
+            Duration::from_secs(config.lyrics_cache_ttl_secs),
+            config.lyrics_cache_capacity,
+        )
+        .with_retry_policy(config.max_retries, Duration::from_millis(config.base_backoff_ms))
+        .with_server_time_attempts(config.server_time_attempts)
+        .with_totp(totp_secret, config.totp_version)
+        .with_token_expiry_margin(Duration::from_secs(config.token_expiry_margin_secs))
+        .with_max_lyric_lines(config.max_lyric_lines);
+
+    // Stateless mode keeps everything in memory so nothing is written to disk
+    if config.disable_file_cache || config.no_store {
+        info!("File cache disabled, keeping the access token in memory");
+        spotify = spotify.without_token_file();
+        
+        if config.lyrics_cache_dir.is_some() {
+            warn!("Ignoring lyrics_cache_dir because disable_file_cache or no_store is set");
+        }
+    } else if let Some(dir) = &config.lyrics_cache_dir {
+        info!("Persisting lyrics cache to {}", dir);
+        spotify = spotify.with_disk_cache(PathBuf::from(dir), Duration::from_secs(config.lyrics_cache_ttl_secs));
+    }
+    spotify = with_endpoint_overrides(spotify, &config);
+    if config.lrclib_fallback {
+        let url = config.lrclib_url.unwrap_or_else(|| DEFAULT_LRCLIB_URL.to_string());
+        info!("Falling back to LRCLIB at {} for tracks without Spotify lyrics", url);
+        spotify = spotify.with_lrclib_fallback(url);
+    }
+    
+    if config.allow_anonymous {
+        spotify = spotify.with_anonymous_tokens();
+    }
+    if config.debug_upstream {
+        warn!("Logging every Spotify request and response; keep debug_upstream off in production");
+        spotify = spotify.with_upstream_logging();
+    }
+    
+    if config.discover_app_version {
+        spotify = spotify.with_discovered_app_version().await;
+    }
+    
+    // Exit before binding if Spotify rejects the configured sp_dc
+    if config.validate_token_on_startup {
+        info!("Validating SP_DC token before starting");
+        
+        if let Err(e) = spotify.force_refresh_token().await {
+            error!("SP_DC validation failed: {}", e);
+            error!("Check that the sp_dc cookie is current and belongs to a logged-in account.");
+            std::process::exit(1);
+        }
+        
+        info!("SP_DC token is valid");
+    }
+    
+    // Create application state
+    if config.api_key.is_some() {
+        info!("API key authentication enabled");
+    }
+    
+    let app_state = web::Data::new(AppState {
+        spotify,
+        api_key: config.api_key,
+        debug_endpoints: config.debug_endpoints,
+        response_case: config.response_case,
+    });
+    
+    if config.rate_limit_per_minute > 0 {
+        info!("Rate limiting clients to {} requests per minute", config.rate_limit_per_minute);
+    }
+    
+    let rate_limiter = web::Data::new(RateLimiter::new(config.rate_limit_per_minute));
+    
+    if config.request_signing_secret.is_some() {
+        info!("Request signature verification enabled");
+    }
+    
+    let request_verifier = web::Data::new(RequestVerifier::new(config.request_signing_secret));
+    let json_logs = config.log_format == LogFormat::Json;
+    let no_store = config.no_store;
+    
+    let base_path = config.base_path;
+    if !base_path.is_empty() {
+        info!("Serving routes under {}", base_path);
+    }
+    
+    let allowed_origins = config.allowed_origins;
+    if !allows_any_origin(&allowed_origins) {
+        info!("Restricting CORS to origins: {}", allowed_origins.join(", "));
+    }
+
+    // Start the HTTP server
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap(from_fn(signature::verify_signature))
+            .wrap(from_fn(ratelimit::rate_limit))
+            .wrap(from_fn(metrics::track_requests))
+            // Compress responses according to the client's Accept-Encoding (gzip, br, deflate, zstd)
+            .wrap(Compress::default())
+            // Inside the access log middleware so every log line carries the request ID
+            .wrap(from_fn(requestid::request_id))
+            // Access logs are either actix's human-readable lines or JSON objects
+            .wrap(Condition::new(!json_logs, text_access_logger(no_store)))
+            .wrap(Condition::new(json_logs, from_fn(accesslog::json_access_log)))
+            .wrap(cors(&allowed_origins))
+            .app_data(app_state.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(request_verifier.clone())
+            .service(api_scope(&base_path))
+    })
+    .shutdown_timeout(config.shutdown_timeout_secs)
+    .disable_signals();
+    
+    // Containers with CPU limits still report every host core, so the count can be pinned
+    if config.workers > 0 {
+        info!("Using {} worker threads", config.workers);
+        server = server.workers(config.workers);
+    }
+    
+    // Binding only fails when no address could be bound, so the rest are checked here
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23(listen_addrs.as_slice(), tls_config)?,
+        None => server.bind(listen_addrs.as_slice())?,
+    };
+    
+    let bound_addrs = server.addrs();
+    for addr in unbound_addrs(&listen_addrs, &bound_addrs) {
+        warn!("Failed to bind {}, not listening there", addr);
+    }
+    
+    let urls: Vec<String> = bound_addrs.iter().map(|addr| format!("{}://{}", scheme, addr)).collect();
+    info!("Starting server at {}", urls.join(", "));
+    
+    let server = server.run();
+    
+    // Stop accepting new connections on shutdown and let in-flight requests drain
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!("Shutdown signal received, draining in-flight requests");
+        handle.stop(true).await;
+    });
+    
+    server.await?;
+    
+    // The token cache is written as soon as a token is fetched, so there is nothing left to flush
+    info!("Server stopped");
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test as actix_test;
+    use wiremock::matchers::{method, path, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const TRACK_ID: &str = "4cOdK2wGLETKBW3PvgPWqT";
+
+    // A Spotify client pointed at a mock server that hands out access tokens
+    async fn mock_spotify() -> (MockServer, Spotify) {
+        let server = MockServer::start().await;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+
+        Mock::given(method("GET"))
+            .and(path("/api/server-time"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "serverTime": now_ms / 1000 })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "accessToken": "test-access-token",
+                "accessTokenExpirationTimestampMs": now_ms + 3_600_000,
+                "isAnonymous": false
+            })))
+            .mount(&server)
+            .await;
+
+        let spotify = Spotify::new(vec!["test-sp-dc".to_string()])
+            .without_token_file()
+            .with_server_time_url(format!("{}/api/server-time", server.uri()))
+            .with_token_url(format!("{}/api/token", server.uri()))
+            .with_lyrics_url(format!("{}/lyrics/", server.uri()));
+
+        (server, spotify)
+    }
+
+    async fn mount_lyrics(server: &MockServer, body: &str) {
+        Mock::given(method("GET"))
+            .and(path_regex("^/lyrics/"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body.to_string(), "application/json"))
+            .mount(server)
+            .await;
+    }
+
+    fn state(spotify: Spotify) -> AppState {
+        AppState {
+            spotify,
+            api_key: None,
+            debug_endpoints: true,
+            response_case: ResponseCase::Camel,
+        }
+    }
+
+    fn app_state(spotify: Spotify) -> web::Data<AppState> {
+        web::Data::new(state(spotify))
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> std::collections::HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    async fn json_body(response: HttpResponse) -> serde_json::Value {
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // The 400 response parse_lyrics_query rejects the query with
+    fn query_error(pairs: &[(&str, &str)]) -> HttpResponse {
+        match parse_lyrics_query(&query(pairs), "id3", false) {
+            Ok(_) => panic!("expected {:?} to be rejected", pairs),
+            Err(response) => response,
+        }
+    }
+
+    #[actix_web::test]
+    async fn invalid_format_lists_supported_formats() {
+        let response = query_error(&[("format", "xml")]);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "INVALID_FORMAT");
+        assert_eq!(body["supported_formats"], json!(SUPPORTED_FORMATS));
+    }
+
+    async fn mount_track(server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path(format!("/v1/tracks/{}", TRACK_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "name": "Song",
+                "artists": [{ "name": "First Artist" }, { "name": "Second Artist" }],
+                "album": {
+                    "name": "Album",
+                    "images": [
+                        { "url": "https://i.scdn.co/image/large", "width": 640, "height": 640 },
+                        { "url": "https://i.scdn.co/image/small", "width": 64, "height": 64 },
+                        { "url": "https://i.scdn.co/image/medium", "width": 300, "height": 300 }
+                    ]
+                },
+                "duration_ms": 215000
+            })))
+            .mount(server)
+            .await;
+    }
+
+    const LYRICS_FIXTURE: &str = r#"{"lyrics":{"syncType":"LINE_SYNCED","provider":"MusixMatch","language":"en","lines":[
+        {"startTimeMs":"1000","words":" First line ","syllables":[],"endTimeMs":"0"},
+        {"startTimeMs":"2000","words":"♪","syllables":[],"endTimeMs":"0"},
+        {"startTimeMs":"3000","words":"Second line","syllables":[],"endTimeMs":"0"},
+        {"startTimeMs":"4000","words":"","syllables":[],"endTimeMs":"0"},
+        {"startTimeMs":"5000","words":"Third line","syllables":[],"endTimeMs":"0"}
+    ]}}"#;
+
+    // Serves the lyrics fixture and the track details, returning the JSON body of GET `uri`
+    async fn get_json(uri: &str) -> serde_json::Value {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+        mount_track(&server).await;
+
+        let spotify = spotify
+            .with_tracks_url(format!("{}/v1/tracks/", server.uri()))
+            .with_lyrics_cache(Duration::from_secs(60), 100);
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_state(spotify))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+
+        let req = actix_test::TestRequest::get().uri(uri).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK, "GET {}", uri);
+        actix_test::read_body_json(res).await
+    }
+
+    #[actix_web::test]
+    async fn include_metadata_merges_track_details() {
+        for param in ["includeMetadata", "include_metadata"] {
+            let body = get_json(&format!("/lyrics/{}?{}=true", TRACK_ID, param)).await;
+
+            assert_eq!(body["track"]["name"], "Song", "{}", param);
+            assert_eq!(body["track"]["artists"], json!(["First Artist", "Second Artist"]));
+            assert_eq!(body["track"]["album"], "Album");
+            assert_eq!(body["track"]["durationMs"], 215000);
+            assert_eq!(body["track"]["cover_url"], "https://i.scdn.co/image/medium");
+            assert_eq!(body["lines"][0]["words"], " First line ");
+        }
+    }
+
+    #[actix_web::test]
+    async fn metadata_is_left_out_by_default() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_state(spotify))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}", TRACK_ID)).to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+
+        assert!(body.get("track").is_none());
+    }
+
+    #[test]
+    fn include_metadata_is_only_for_json_formats() {
+        assert_eq!(query_error(&[("format", "srt"), ("include_metadata", "true")]).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(query_error(&[("include_metadata", "yes")]).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn track_metadata_is_cached() {
+        let (server, spotify) = mock_spotify().await;
+        mount_track(&server).await;
+
+        let spotify = spotify
+            .with_tracks_url(format!("{}/v1/tracks/", server.uri()))
+            .with_lyrics_cache(Duration::from_secs(60), 100)
+            .with_metadata_cache(Duration::from_secs(60), 100);
+
+        let first = spotify.get_track_metadata(TRACK_ID).await.unwrap();
+        let second = spotify.get_track_metadata(TRACK_ID).await.unwrap();
+
+        assert_eq!(first.name, second.name);
+        // Metadata neither takes lyrics cache slots nor shows up in its entry count
+        assert_eq!(spotify.lyrics_cache_len(), 0);
+        let track_requests = server.received_requests().await.unwrap()
+            .into_iter()
+            .filter(|request| request.url.path().starts_with("/v1/tracks/"))
+            .count();
+        assert_eq!(track_requests, 1);
+    }
+
+    #[test]
+    fn reports_addresses_that_did_not_bind() {
+        let v6: std::net::SocketAddr = "[::]:8080".parse().unwrap();
+        let v4: std::net::SocketAddr = "0.0.0.0:8080".parse().unwrap();
+
+        assert_eq!(unbound_addrs(&[v6, v4], &[v6]), vec![v4]);
+        assert!(unbound_addrs(&[v6, v4], &[v6, v4]).is_empty());
+
+        let any_port: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        assert!(unbound_addrs(&[any_port], &["127.0.0.1:41234".parse().unwrap()]).is_empty());
+    }
+
+    fn negotiated(accept: &str) -> &'static str {
+        negotiated_format(&actix_test::TestRequest::get().insert_header((header::ACCEPT, accept)).to_http_request())
+    }
+
+    #[test]
+    fn negotiates_format_from_accept() {
+        assert_eq!(negotiated_format(&actix_test::TestRequest::get().to_http_request()), "id3");
+        assert_eq!(negotiated("application/json"), "id3");
+        assert_eq!(negotiated("application/x-subrip"), "srt");
+        assert_eq!(negotiated("text/vtt"), "vtt");
+        assert_eq!(negotiated("application/ttml+xml"), "ttml");
+        assert_eq!(negotiated("application/x-ndjson"), "json-lines");
+        assert_eq!(negotiated("text/plain"), "text");
+        assert_eq!(negotiated("TEXT/VTT; charset=utf-8"), "vtt");
+        assert_eq!(negotiated("text/html, */*"), "id3");
+    }
+
+    #[test]
+    fn negotiation_prefers_highest_q_value() {
+        assert_eq!(negotiated("application/json;q=0.1, text/vtt"), "vtt");
+        assert_eq!(negotiated("text/vtt;q=0.5, application/x-subrip;q=0.9"), "srt");
+        // Equal q-values keep the listed order
+        assert_eq!(negotiated("text/plain, text/vtt"), "text");
+        assert_eq!(negotiated("text/vtt;q=0, application/x-subrip;q=0.2"), "srt");
+        assert_eq!(negotiated("text/vtt;q=0"), "id3");
+    }
+
+    #[actix_web::test]
+    async fn serves_the_negotiated_format() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_state(spotify))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/lyrics/{}", TRACK_ID))
+            .insert_header((header::ACCEPT, "application/json;q=0.1, text/plain"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/plain; charset=utf-8");
+        assert_eq!(actix_test::read_body(res).await, " First line \n♪\nSecond line\n\nThird line\n");
+
+        // The format parameter takes precedence over Accept
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/lyrics/{}?format=lrc", TRACK_ID))
+            .insert_header((header::ACCEPT, "text/vtt"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let body: serde_json::Value = actix_test::read_body_json(res).await;
+        assert_eq!(body["lines"][0]["timeTag"], "00:01.00");
+    }
+
+    #[actix_web::test]
+    async fn line_paging_slices_lines_and_reports_total() {
+        let body = get_json(&format!("/lyrics/{}?lineOffset=1&lineLimit=2", TRACK_ID)).await;
+        assert_eq!(body["total"], 5);
+        assert_eq!(body["lineCount"], 5);
+        assert_eq!(body["lines"].as_array().unwrap().len(), 2);
+        assert_eq!(body["lines"][0]["startTimeMs"], "2000");
+        assert_eq!(body["lines"][1]["startTimeMs"], "3000");
+
+        let body = get_json(&format!("/lyrics/{}?line_offset=3", TRACK_ID)).await;
+        assert_eq!(body["total"], 5);
+        assert_eq!(body["lines"].as_array().unwrap().len(), 2);
+        assert_eq!(body["lines"][0]["startTimeMs"], "4000");
+    }
+
+    #[actix_web::test]
+    async fn words_are_verbatim_unless_trimming_is_requested() {
+        let body = get_json(&format!("/lyrics/{}", TRACK_ID)).await;
+        assert_eq!(body["lines"][0]["words"], " First line ");
+        assert_eq!(body["lines"][1]["words"], "♪");
+
+        for param in ["trimWords", "trim_words"] {
+            let body = get_json(&format!("/lyrics/{}?{}=true", TRACK_ID, param)).await;
+            assert_eq!(body["lines"][0]["words"], "First line", "{}", param);
+            assert_eq!(body["lines"][1]["words"], "♪", "{}", param);
+        }
+    }
+
+    #[actix_web::test]
+    async fn interlude_lines_are_kept_unless_skipped() {
+        let words = |body: &serde_json::Value| -> Vec<String> {
+            body["lines"].as_array().unwrap().iter().map(|line| line["words"].as_str().unwrap().to_string()).collect()
+        };
+
+        let body = get_json(&format!("/lyrics/{}", TRACK_ID)).await;
+        assert_eq!(words(&body), [" First line ", "♪", "Second line", "", "Third line"]);
+
+        for param in ["skipEmpty", "skip_empty"] {
+            let body = get_json(&format!("/lyrics/{}?{}=true&format=lrc", TRACK_ID, param)).await;
+            assert_eq!(words(&body), [" First line ", "Second line", "Third line"], "{}", param);
+            assert_eq!(body["lineCount"], 3, "{}", param);
+        }
+    }
+
+    #[actix_web::test]
+    async fn line_offset_past_the_end_returns_no_lines() {
+        let body = get_json(&format!("/lyrics/{}?line_offset=50&line_limit=10", TRACK_ID)).await;
+
+        assert_eq!(body["total"], 5);
+        assert_eq!(body["lines"], json!([]));
+    }
+
+    #[test]
+    fn rejects_invalid_line_paging() {
+        for pairs in [
+            &[("line_limit", "0")][..],
+            &[("lineLimit", "-1")],
+            &[("line_limit", "ten")],
+            &[("line_offset", "-5")],
+            &[("format", "srt"), ("line_limit", "10")],
+        ] {
+            assert_eq!(query_error(pairs).status(), StatusCode::BAD_REQUEST, "{:?}", pairs);
+        }
+    }
+
+    #[actix_web::test]
+    async fn raw_format_passes_spotify_response_through_verbatim() {
+        let (server, spotify) = mock_spotify().await;
+        // Odd spacing and key order, and more lines than the guard allows
+        let raw = r#"{"lyrics":  {"syncType":"LINE_SYNCED", "lines":[{"startTimeMs":"0","words":"a"},{"startTimeMs":"1","words":"b"}]},"hasVocalRemoval":false }"#;
+        mount_lyrics(&server, raw).await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_state(spotify.with_max_lyric_lines(1)))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?format=raw", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(actix_test::read_body(res).await, raw.as_bytes());
+    }
+
+    #[test]
+    fn maps_upstream_errors_to_statuses() {
+        let cases = [
+            (SpotifyException::NotFound("no lyrics".to_string()), StatusCode::NOT_FOUND),
+            (SpotifyException::InvalidUrl("bad url".to_string()), StatusCode::BAD_REQUEST),
+            (SpotifyException::AuthError("rejected".to_string()), StatusCode::BAD_GATEWAY),
+            (SpotifyException::InvalidResponse("odd".to_string()), StatusCode::BAD_GATEWAY),
+            (SpotifyException::RateLimited(Duration::from_secs(30)), StatusCode::SERVICE_UNAVAILABLE),
+            (SpotifyException::ApiError("boom".to_string()), StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+
+        for (e, status) in cases {
+            assert_eq!(spotify_error_response(&e, "test").status(), status, "{:?}", e);
+        }
+
+        // Clients are told when Spotify's rate limit window ends
+        let response = spotify_error_response(&SpotifyException::RateLimited(Duration::from_secs(30)), "test");
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "30");
+    }
+
+    #[actix_web::test]
+    async fn vocal_removal_must_be_a_boolean() {
+        let parse = |pairs: &[(&str, &str)]| parse_lyrics_query(&query(pairs), "id3", false).ok().unwrap();
+        assert!(!parse(&[]).vocal_removal);
+        assert!(parse(&[("vocalRemoval", "true")]).vocal_removal);
+        assert!(parse(&[("vocal_removal", "true")]).vocal_removal);
+
+        let response = query_error(&[("vocalRemoval", "yes")]);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(response).await["code"], "INVALID_PARAM");
+    }
+
+    #[actix_web::test]
+    async fn market_is_a_two_letter_country_code() {
+        let parse = |pairs: &[(&str, &str)]| parse_lyrics_query(&query(pairs), "id3", false).ok().unwrap();
+        assert_eq!(parse(&[]).market, "from_token");
+        assert_eq!(parse(&[("market", "de")]).market, "DE");
+
+        for market in ["USA", "D1", ""] {
+            let response = query_error(&[("market", market)]);
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{:?}", market);
+            assert_eq!(json_body(response).await["code"], "INVALID_PARAM");
+        }
+    }
+
+    #[actix_web::test]
+    async fn search_returns_top_matches() {
+        let (server, spotify) = mock_spotify().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/search"))
+            .and(wiremock::matchers::query_param("q", "bohemian rhapsody queen"))
+            .and(wiremock::matchers::query_param("type", "track"))
+            .and(wiremock::matchers::query_param("limit", "10"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "tracks": { "items": [{
+                    "id": "3z8h0TU7ReDPLIbEnYhWZb",
+                    "name": "Bohemian Rhapsody",
+                    "artists": [{ "name": "Queen" }],
+                    "album": { "name": "A Night At The Opera" }
+                }] }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let spotify = spotify.with_search_url(format!("{}/v1/search", server.uri()));
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/search", web::get().to(search))
+        ).await;
+        let req = actix_test::TestRequest::get().uri("/search?q=bohemian%20rhapsody%20queen").to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(body["tracks"], json!([{
+            "id": "3z8h0TU7ReDPLIbEnYhWZb",
+            "name": "Bohemian Rhapsody",
+            "artist": "Queen",
+            "album": "A Night At The Opera"
+        }]));
+
+        let req = actix_test::TestRequest::get().uri("/search?q=%20").to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn graceful_stop_drains_in_flight_requests() {
+        let started = std::sync::Arc::new(tokio::sync::Notify::new());
+        let handler_started = started.clone();
+        let server = HttpServer::new(move || {
+            let started = handler_started.clone();
+            App::new().route("/", web::get().to(move || {
+                let started = started.clone();
+                async move {
+                    started.notify_one();
+                    actix_web::rt::time::sleep(Duration::from_millis(300)).await;
+                    HttpResponse::Ok().body("done")
+                }
+            }))
+        })
+        .workers(1)
+        .shutdown_timeout(5)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        let running = actix_web::rt::spawn(server);
+
+        let request = actix_web::rt::spawn(async move {
+            let response = reqwest::get(format!("http://{}/", addr)).await?;
+            response.text().await
+        });
+        // Only stop once the request is in flight
+        started.notified().await;
+        handle.stop(true).await;
+
+        assert_eq!(request.await.unwrap().unwrap(), "done");
+        assert!(running.await.unwrap().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn lyrics_require_the_configured_api_key() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let state = AppState { api_key: Some("secret".to_string()), ..state(spotify) };
+        let app = actix_test::init_service(
+            App::new().app_data(web::Data::new(state)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/lyrics/{}", TRACK_ID))
+            .insert_header(("X-API-Key", "secret"))
+            .to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn path_route_matches_query_route() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_state(spotify))
+                .route("/", web::get().to(get_lyrics))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+
+        let by_query = actix_test::TestRequest::get().uri(&format!("/?trackid={}&format=lrc", TRACK_ID)).to_request();
+        let by_query = actix_test::call_and_read_body(&app, by_query).await;
+        let by_path = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?format=lrc", TRACK_ID)).to_request();
+        let by_path = actix_test::call_and_read_body(&app, by_path).await;
+
+        assert_eq!(by_path, by_query);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&by_path).unwrap()["lines"][0]["timeTag"], "00:01.00");
+    }
+
+    #[actix_web::test]
+    async fn compresses_json_and_text_formats_for_gzip_clients() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(Compress::default())
+                .app_data(app_state(spotify))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+
+        for format in ["id3", "srt", "text"] {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/lyrics/{}?format={}", TRACK_ID, format))
+                .insert_header((header::ACCEPT_ENCODING, "gzip"))
+                .to_request();
+            let res = actix_test::call_service(&app, req).await;
+
+            assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip", "{}", format);
+            // Gzip streams start with the magic bytes 1f 8b
+            assert_eq!(actix_test::read_body(res).await[..2], [0x1f, 0x8b], "{}", format);
+        }
+
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    async fn allowed_origin_header(allowed_origins: &[&str], origin: &str) -> Option<String> {
+        let allowed_origins: Vec<String> = allowed_origins.iter().map(|o| o.to_string()).collect();
+        let app = actix_test::init_service(
+            App::new().wrap(cors(&allowed_origins)).route("/", web::get().to(HttpResponse::Ok))
+        ).await;
+
+        let req = actix_test::TestRequest::get().uri("/").insert_header((header::ORIGIN, origin)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[actix_web::test]
+    async fn cors_allowlist_only_admits_listed_origins() {
+        let allowed = ["https://app.example.com"];
+        assert_eq!(allowed_origin_header(&allowed, "https://app.example.com").await.as_deref(), Some("https://app.example.com"));
+        assert_eq!(allowed_origin_header(&allowed, "https://evil.example.com").await, None);
+    }
+
+    #[actix_web::test]
+    async fn empty_or_wildcard_allowlist_admits_any_origin() {
+        assert!(allowed_origin_header(&[], "https://evil.example.com").await.is_some());
+        assert!(allowed_origin_header(&["*"], "https://evil.example.com").await.is_some());
+    }
+
+    #[actix_web::test]
+    async fn missing_lyrics_answer_404() {
+        let (server, spotify) = mock_spotify().await;
+        Mock::given(method("GET"))
+            .and(path_regex("^/lyrics/"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_state(spotify))
+                .route("/", web::get().to(get_lyrics))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/?trackid={}", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = actix_test::read_body_json(res).await;
+        assert_eq!(body["code"], "NO_LYRICS");
+        assert_eq!(body["error"], true);
+    }
+
+    #[actix_web::test]
+    async fn every_not_found_variant_answers_404_with_its_code() {
+        let cases = [
+            (SpotifyException::NotFound(String::new()), "NO_LYRICS"),
+            (SpotifyException::TrackNotFound(String::new()), "TRACK_NOT_FOUND"),
+            (SpotifyException::TranscriptNotFound(String::new()), "NO_TRANSCRIPT"),
+            (SpotifyException::AlbumNotFound(String::new()), "ALBUM_NOT_FOUND"),
+            (SpotifyException::PlaylistNotFound(String::new()), "PLAYLIST_NOT_FOUND"),
+        ];
+
+        for (e, code) in cases {
+            let response = spotify_error_response(&e, "test");
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{:?}", e);
+            assert_eq!(json_body(response).await["code"], code);
+        }
+    }
+
+    #[actix_web::test]
+    async fn auth_errors_point_at_sp_dc() {
+        let response = spotify_error_response(&SpotifyException::AuthError("anonymous token".to_string()), "test");
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "UPSTREAM_AUTH");
+        assert_eq!(body["message"], "lyrics backend authentication failed; check SP_DC");
+    }
+
+    // Value of the first sample in a Prometheus text exposition whose name and labels start with `series`
+    fn scraped(exposition: &str, series: &str) -> f64 {
+        exposition.lines()
+            .find(|line| line.starts_with(series))
+            .and_then(|line| line.rsplit(' ').next())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0.0)
+    }
+
+    // Metrics are process-wide and other tests run alongside, so counters are only checked for having moved
+    #[actix_web::test]
+    async fn metrics_count_requests_and_upstream_calls() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(from_fn(metrics::track_requests))
+                .app_data(app_state(spotify))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+                .route("/metrics", web::get().to(metrics::metrics_endpoint))
+        ).await;
+
+        let scrape = || async {
+            let req = actix_test::TestRequest::get().uri("/metrics").to_request();
+            String::from_utf8(actix_test::call_and_read_body(&app, req).await.to_vec()).unwrap()
+        };
+
+        let before = scrape().await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}", TRACK_ID)).to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+        let after = scrape().await;
+
+        for series in [
+            "requests_total{status=\"200\"}",
+            "token_refreshes_total",
+            "upstream_request_duration_seconds_count{endpoint=\"lyrics\"}",
+        ] {
+            assert!(scraped(&after, series) > scraped(&before, series), "{} didn't move", series);
+        }
+    }
+
+    #[actix_web::test]
+    async fn refresh_token_endpoint_returns_the_new_expiry() {
+        let (_server, spotify) = mock_spotify().await;
+
+        let state = AppState { api_key: Some("secret".to_string()), ..state(spotify) };
+        let app = actix_test::init_service(
+            App::new().app_data(web::Data::new(state)).route("/admin/refresh-token", web::post().to(refresh_token))
+        ).await;
+
+        let req = actix_test::TestRequest::post().uri("/admin/refresh-token").to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = actix_test::TestRequest::post().uri("/admin/refresh-token?apikey=secret").to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["error"], false);
+        assert!(body["accessTokenExpirationTimestampMs"].as_u64().unwrap() > chrono::Utc::now().timestamp_millis() as u64);
+    }
+
+    #[actix_web::test]
+    async fn refresh_token_endpoint_is_disabled_without_an_api_key() {
+        let (server, spotify) = mock_spotify().await;
+
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/admin/refresh-token", web::post().to(refresh_token))
+        ).await;
+        let req = actix_test::TestRequest::post().uri("/admin/refresh-token").to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = actix_test::read_body_json(res).await;
+        assert_eq!(body["code"], "ADMIN_DISABLED");
+        let token_requests = server.received_requests().await.unwrap()
+            .iter()
+            .filter(|request| request.url.path() == "/api/token")
+            .count();
+        assert_eq!(token_requests, 0);
+    }
+
+    #[actix_web::test]
+    async fn jsonp_wraps_the_body_in_the_callback() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?callback=widget.onLyrics", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/javascript; charset=utf-8");
+        let body = String::from_utf8(actix_test::read_body(res).await.to_vec()).unwrap();
+        let json = body.strip_prefix("widget.onLyrics(").and_then(|rest| rest.strip_suffix(");")).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(json).unwrap()["lines"][0]["startTimeMs"], "1000");
+    }
+
+    #[actix_web::test]
+    async fn rejects_unsafe_callbacks() {
+        for callback in ["alert(1)//", "a;b", "</script>", "1abc", "a..b", ""] {
+            assert!(!is_safe_callback(callback), "{:?}", callback);
+
+            let response = query_error(&[("callback", callback)]);
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(json_body(response).await["code"], "INVALID_PARAM");
+        }
+
+        assert!(is_safe_callback("jQuery_123.done"));
+    }
+
+    #[actix_web::test]
+    async fn configured_endpoint_urls_reach_the_client() {
+        let (server, _) = mock_spotify().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/proxy/lyrics/{}", TRACK_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(LYRICS_FIXTURE, "application/json"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+        std::fs::write(&config_file, format!(
+            "token_url = \"{0}/api/token\"\nlyrics_url = \"{0}/proxy/lyrics/\"\nserver_time_url = \"{0}/api/server-time\"\n",
+            server.uri()
+        )).unwrap();
+        let config = Config::load(Some(&config_file)).unwrap();
+
+        let spotify = with_endpoint_overrides(Spotify::new(vec!["test-sp-dc".to_string()]).without_token_file(), &config);
+        spotify.get_lyrics(TRACK_ID, false, "from_token").await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn album_lists_lyrics_per_track_across_pages() {
+        const SECOND_TRACK: &str = "7GhIk7Il098yCjg4BQjzvb";
+        let (server, spotify) = mock_spotify().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/albums/album123/tracks"))
+            .and(wiremock::matchers::query_param("offset", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "items": [{ "id": SECOND_TRACK }],
+                "next": null
+            })))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/albums/album123/tracks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "items": [{ "id": TRACK_ID }],
+                "next": format!("{}/v1/albums/album123/tracks?offset=1&limit=50", server.uri())
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/lyrics/{}", TRACK_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(LYRICS_FIXTURE, "application/json"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/lyrics/{}", SECOND_TRACK)))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let spotify = spotify.with_album_url(format!("{}/v1/albums/", server.uri()));
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/album/{album_id}", web::get().to(get_album_lyrics))
+        ).await;
+        let req = actix_test::TestRequest::get().uri("/album/album123?format=lrc").to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(body["albumId"], "album123");
+        let tracks = body["tracks"].as_array().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0]["trackId"], TRACK_ID);
+        assert_eq!(tracks[0]["lyrics"]["lines"][0]["timeTag"], "00:01.00");
+        assert_eq!(tracks[1]["trackId"], SECOND_TRACK);
+        assert_eq!(tracks[1]["error"], true);
+        assert_eq!(tracks[1]["code"], "NO_LYRICS");
+    }
+
+    #[actix_web::test]
+    async fn playlist_lists_lyrics_per_track_skipping_local_files_and_episodes() {
+        const SECOND_TRACK: &str = "7GhIk7Il098yCjg4BQjzvb";
+        let (server, spotify) = mock_spotify().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/playlists/playlist123/tracks"))
+            .and(wiremock::matchers::query_param("offset", "100"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "items": [{ "is_local": false, "track": { "id": SECOND_TRACK, "type": "track" } }],
+                "next": null
+            })))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/playlists/playlist123/tracks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "items": [
+                    { "is_local": false, "track": { "id": TRACK_ID, "type": "track" } },
+                    { "is_local": true, "track": { "id": null, "type": "track" } },
+                    { "is_local": false, "track": { "id": "episode123", "type": "episode" } }
+                ],
+                "next": format!("{}/v1/playlists/playlist123/tracks?offset=100&limit=100", server.uri())
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/lyrics/{}", TRACK_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(LYRICS_FIXTURE, "application/json"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/lyrics/{}", SECOND_TRACK)))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let spotify = spotify.with_playlist_url(format!("{}/v1/playlists/", server.uri()));
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/playlist/{playlist_id}", web::get().to(get_playlist_lyrics))
+        ).await;
+        let req = actix_test::TestRequest::get().uri("/playlist/playlist123?format=lrc").to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(body["playlistId"], "playlist123");
+        assert!(body.get("truncated").is_none());
+        let tracks = body["tracks"].as_array().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0]["trackId"], TRACK_ID);
+        assert_eq!(tracks[0]["lyrics"]["lines"][0]["timeTag"], "00:01.00");
+        assert_eq!(tracks[1]["trackId"], SECOND_TRACK);
+        assert_eq!(tracks[1]["error"], true);
+        assert_eq!(tracks[1]["code"], "NO_LYRICS");
+    }
+
+    #[actix_web::test]
+    async fn etag_round_trip_answers_304() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let uri = format!("/lyrics/{}?format=lrc", TRACK_ID);
+
+        let res = actix_test::call_service(&app, actix_test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.headers().get(header::CACHE_CONTROL).unwrap(), "public, max-age=86400");
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        let req = actix_test::TestRequest::get().uri(&uri).insert_header((header::IF_NONE_MATCH, etag.clone())).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+        assert!(actix_test::read_body(res).await.is_empty());
+
+        // Weak and listed tags match too, other tags don't
+        let listed = format!("\"other\", W/{}", etag.to_str().unwrap());
+        let req = actix_test::TestRequest::get().uri(&uri).insert_header((header::IF_NONE_MATCH, listed)).to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::NOT_MODIFIED);
+
+        let req = actix_test::TestRequest::get().uri(&uri).insert_header((header::IF_NONE_MATCH, "\"other\"")).to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // A different format is a different body with its own tag
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}", TRACK_ID)).insert_header((header::IF_NONE_MATCH, etag)).to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn key_protected_lyrics_are_only_cached_privately() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let state = AppState { api_key: Some("secret".to_string()), ..state(spotify) };
+        let app = actix_test::init_service(
+            App::new().app_data(web::Data::new(state)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/lyrics/{}", TRACK_ID))
+            .insert_header(("X-API-Key", "secret"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CACHE_CONTROL).unwrap(), "private, max-age=86400");
+    }
+
+    #[actix_web::test]
+    async fn serves_requests_over_tls() {
+        let tls_config = tls::load_server_config(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.crt"),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.key"),
+        ).unwrap();
+
+        let server = HttpServer::new(|| App::new().route("/version", web::get().to(version)))
+            .workers(1)
+            .disable_signals()
+            .bind_rustls_0_23("127.0.0.1:0", tls_config)
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let request = actix_web::rt::spawn(async move {
+            let client = reqwest::Client::builder().danger_accept_invalid_certs(true).build()?;
+            client.get(format!("https://localhost:{}/version", addr.port())).send().await?.json::<serde_json::Value>().await
+        });
+        let body = request.await.unwrap().unwrap();
+        handle.stop(false).await;
+
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn command_line_flags_override_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.toml");
+        std::fs::write(&file, "port = 9000\nhost = \"127.0.0.1\"\n").unwrap();
+
+        let cli = Cli::try_parse_from(["spotifylyricsapi", "--config", file.to_str().unwrap(), "--port", "9200", "--host", "::"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some(file.as_path()));
+        let mut config = Config::load(cli.config.as_deref()).unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        cli.apply_overrides(&mut config);
+        assert_eq!(config.port, 9200);
+        assert_eq!(config.host, "::");
+
+        // Flags that aren't given leave the config file's values alone
+        let cli = Cli::try_parse_from(["spotifylyricsapi", "--port", "9300"]).unwrap();
+        let mut config = Config::load(Some(&file)).unwrap();
+        cli.apply_overrides(&mut config);
+        assert_eq!(config.port, 9300);
+        assert_eq!(config.host, "127.0.0.1");
+
+        assert!(Cli::try_parse_from(["spotifylyricsapi", "--port", "http"]).is_err());
+        let version = Cli::try_parse_from(["spotifylyricsapi", "--version"]).err().unwrap();
+        assert_eq!(version.kind(), clap::error::ErrorKind::DisplayVersion);
+    }
+
+    #[actix_web::test]
+    async fn configured_user_agent_reaches_spotify() {
+        let (server, _) = mock_spotify().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/color-lyrics/v2/track/{}", TRACK_ID)))
+            .and(wiremock::matchers::header("user-agent", "test-agent"))
+            .and(wiremock::matchers::header("spotify-app-version", "1.2.3"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(LYRICS_FIXTURE, "application/json"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+        std::fs::write(&config_file, format!(
+            "user_agent = \"test-agent\"\nspotify_app_version = \"1.2.3\"\nlyrics_url = \"{0}/color-lyrics/v2/track/\"\ntoken_url = \"{0}/api/token\"\nserver_time_url = \"{0}/api/server-time\"\n",
+            server.uri()
+        )).unwrap();
+        let config = Config::load(Some(&config_file)).unwrap();
+
+        let spotify = Spotify::new(vec!["test-sp-dc".to_string()])
+            .without_token_file()
+            .with_client_identity(config.user_agent.clone(), config.app_platform.clone(), config.spotify_app_version.clone());
+        with_endpoint_overrides(spotify, &config).get_lyrics(TRACK_ID, false, "from_token").await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn pretty_indents_json_responses() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?pretty=true", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let pretty = String::from_utf8(actix_test::read_body(res).await.to_vec()).unwrap();
+        assert!(pretty.starts_with("{\n  \""), "{}", pretty);
+
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}", TRACK_ID)).to_request();
+        let compact = String::from_utf8(actix_test::read_body(actix_test::call_service(&app, req).await).await.to_vec()).unwrap();
+        assert!(!compact.contains('\n'));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&pretty).unwrap(), serde_json::from_str::<serde_json::Value>(&compact).unwrap());
+
+        // Documents that aren't JSON are left alone
+        assert_eq!(render_lyrics("text", json!("First line\n"), None, true).1, "First line\n");
+
+        let response = query_error(&[("pretty", "yes")]);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn stats_report_token_cache_and_request_totals() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let spotify = spotify.with_lyrics_cache(Duration::from_secs(60), 10);
+        let state = AppState { api_key: Some("secret".to_string()), ..state(spotify) };
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(from_fn(metrics::track_requests))
+                .app_data(web::Data::new(state))
+                .route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+                .route("/stats", web::get().to(stats))
+        ).await;
+
+        let req = actix_test::TestRequest::get().uri("/stats").to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = actix_test::TestRequest::get().uri("/stats?apikey=secret").to_request();
+        let before: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(before["accessTokenExpirationTimestampMs"].is_null());
+        assert_eq!(before["lyricsCacheEntries"], 0);
+
+        // A miss that fetches, then a hit
+        for _ in 0..2 {
+            let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?apikey=secret", TRACK_ID)).to_request();
+            assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+
+        let req = actix_test::TestRequest::get().uri("/stats?apikey=secret").to_request();
+        let after: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(after["accessTokenExpirationTimestampMs"].as_u64().unwrap() > 0);
+        let expires_in = after["accessTokenExpiresInSecs"].as_u64().unwrap();
+        assert!(expires_in > 3500 && expires_in <= 3600, "{}", expires_in);
+        assert_eq!(after["lyricsCacheEntries"], 1);
+
+        // Counters are shared with other tests, so only check they moved
+        let moved = |field: &str| after[field].as_u64().unwrap() > before[field].as_u64().unwrap();
+        assert!(moved("lyricsCacheHits"));
+        assert!(moved("lyricsCacheMisses"));
+        assert!(after["requestsTotal"].as_u64().unwrap() >= before["requestsTotal"].as_u64().unwrap() + 3);
+    }
+
+    #[actix_web::test]
+    async fn stream_sends_each_line_as_an_event_when_it_is_due() {
+        use actix_web::body::MessageBody;
+
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/stream/{track_id}", web::get().to(stream_lyrics))
+        ).await;
+        // Playback started 4s ago, so every line but the last one at 5s is already due
+        let start_ms = chrono::Utc::now().timestamp_millis() - 4000;
+        let req = actix_test::TestRequest::get().uri(&format!("/stream/{}?start={}", TRACK_ID, start_ms)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "text/event-stream");
+
+        let started = std::time::Instant::now();
+        let mut body = res.into_body();
+        let mut events = Vec::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_next(cx)).await {
+            events.push((started.elapsed(), String::from_utf8(chunk.unwrap().to_vec()).unwrap()));
+        }
+
+        let words: Vec<_> = events.iter()
+            .filter_map(|(_, event)| event.strip_prefix("data: ")?.strip_suffix("\n\n"))
+            .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap()["words"].clone())
+            .collect();
+        assert_eq!(words, [json!(" First line "), json!("♪"), json!("Second line"), json!(""), json!("Third line")]);
+        assert_eq!(events.last().unwrap().1, "event: end\ndata: {}\n\n");
+
+        // The due lines come at once, the last one waits for its time a second later
+        assert!(events[4].0 - events[3].0 >= Duration::from_millis(500), "{:?}", events);
+
+        let req = actix_test::TestRequest::get().uri(&format!("/stream/{}?start=soon", TRACK_ID)).to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn format_is_case_insensitive() {
+        for format in ["LRC", "Lrc"] {
+            let body = get_json(&format!("/lyrics/{}?format={}", TRACK_ID, format)).await;
+            assert_eq!(body["lines"][0]["timeTag"], "00:01.00", "{}", format);
+        }
+        let body = get_json(&format!("/lyrics/{}?format=Id3", TRACK_ID)).await;
+        assert_eq!(body["lines"][0]["startTimeMs"], "1000");
+
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?format=SRT", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/x-subrip; charset=utf-8");
+        assert!(actix_test::read_body(res).await.starts_with(b"1\n00:00:01,000 --> "));
+    }
+
+    #[actix_web::test]
+    async fn version_reports_the_build() {
+        let app = actix_test::init_service(App::new().route("/version", web::get().to(version))).await;
+        let req = actix_test::TestRequest::get().uri("/version").to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!body["version"].as_str().unwrap().is_empty());
+        assert!(!body["gitHash"].as_str().unwrap().is_empty());
+        let built_at = body["buildTimestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(built_at).is_ok(), "{}", built_at);
+    }
+
+    #[actix_web::test]
+    async fn preloaded_tracks_are_served_from_the_cache() {
+        const SECOND_TRACK: &str = "7GhIk7Il098yCjg4BQjzvb";
+        let (server, spotify) = mock_spotify().await;
+        // One fetch per preloaded track, none for the request afterwards
+        Mock::given(method("GET"))
+            .and(path_regex("^/lyrics/"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(LYRICS_FIXTURE, "application/json"))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let data = app_state(spotify.with_lyrics_cache(Duration::from_secs(60), 10));
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/", web::get().to(get_lyrics))
+                .route("/preload", web::post().to(preload))
+        ).await;
+
+        let req = actix_test::TestRequest::post().uri("/preload").set_payload("[]").to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = actix_test::TestRequest::post()
+            .uri("/preload")
+            .set_json(json!({ "trackids": [TRACK_ID, SECOND_TRACK] }))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(actix_test::read_body_json::<serde_json::Value, _>(res).await["queued"], 2);
+
+        for _ in 0..100 {
+            if data.spotify.lyrics_cache_len() == 2 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(data.spotify.lyrics_cache_len(), 2);
+
+        let req = actix_test::TestRequest::get().uri(&format!("/?trackid={}", TRACK_ID)).to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["lines"][0]["startTimeMs"], "1000");
+    }
+
+    #[actix_web::test]
+    async fn lrc_starts_with_id_tags_from_metadata_or_query() {
+        let body = get_json(&format!("/lyrics/{}?format=lrc&includeMetadata=true", TRACK_ID)).await;
+        assert_eq!(body["idTags"], json!(["[ti:Song]", "[ar:First Artist, Second Artist]", "[al:Album]", "[length:03:35]"]));
+
+        // Values passed by the client win over the metadata
+        let body = get_json(&format!("/lyrics/{}?format=lrc&includeMetadata=true&title=Other%20Title", TRACK_ID)).await;
+        assert_eq!(body["idTags"][0], "[ti:Other Title]");
+        assert_eq!(body["idTags"][1], "[ar:First Artist, Second Artist]");
+
+        let body = get_json(&format!("/lyrics/{}?format=lrc&artist=Someone", TRACK_ID)).await;
+        assert_eq!(body["idTags"], json!(["[ar:Someone]"]));
+
+        let body = get_json(&format!("/lyrics/{}?format=lrc", TRACK_ID)).await;
+        assert!(body.get("idTags").is_none());
+    }
+
+    #[actix_web::test]
+    async fn every_route_is_served_under_the_base_path() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        // base_path as it comes out of Config, normalized from e.g. "lyrics/"
+        let app = actix_test::init_service(App::new().app_data(app_state(spotify)).service(api_scope("/lyrics"))).await;
+
+        for uri in ["/lyrics/version", "/lyrics/health", "/lyrics/metrics", "/lyrics/openapi.json", &format!("/lyrics/?trackid={}", TRACK_ID), &format!("/lyrics/lyrics/{}", TRACK_ID)] {
+            let req = actix_test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK, "{}", uri);
+        }
+        for uri in ["/version", "/health", &format!("/?trackid={}", TRACK_ID)] {
+            let req = actix_test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn json_lines_is_served_as_ndjson() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?format=json-lines", TRACK_ID)).to_request();
+        let res = actix_test::call_service(&app, req).await;
+
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/x-ndjson");
+        let body = String::from_utf8(actix_test::read_body(res).await.to_vec()).unwrap();
+        let first: serde_json::Value = serde_json::from_str(body.lines().next().unwrap()).unwrap();
+        assert_eq!(first, json!({ "t": 1000, "words": " First line " }));
+        assert_eq!(body.lines().count(), 5);
+    }
+
+    #[actix_web::test]
+    async fn verbose_health_probes_spotify() {
+        let (_server, spotify) = mock_spotify().await;
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/health", web::get().to(health))
+        ).await;
+
+        let req = actix_test::TestRequest::get().uri("/health?verbose=true").to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = actix_test::read_body_json(res).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["upstreamReachable"], true);
+        assert_eq!(body["tokenValid"], true);
+        // The test client keeps its token in memory, so there is no cache to check
+        assert!(body["cacheWritable"].is_null());
+
+        let req = actix_test::TestRequest::get().uri("/health?verbose=maybe").to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn verbose_health_reports_an_unreachable_spotify() {
+        // Nothing listens on a port once its listener is dropped
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let spotify = Spotify::new(vec!["test-sp-dc".to_string()])
+            .without_token_file()
+            .with_server_time_attempts(1)
+            .with_server_time_url(format!("{}/api/server-time", unreachable))
+            .with_token_url(format!("{}/api/token", unreachable));
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/health", web::get().to(health))
+        ).await;
+
+        // The plain check only says the process is up
+        let req = actix_test::TestRequest::get().uri("/health").to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::get().uri("/health?verbose=true").to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = actix_test::read_body_json(res).await;
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["upstreamReachable"], false);
+        assert_eq!(body["tokenValid"], false);
+    }
+
+    #[test]
+    fn snake_case_renames_nested_keys_but_not_values() {
+        let mut body = json!({
+            "syncType": "LINE_SYNCED",
+            "lines": [{ "startTimeMs": "1000", "timeTag": "00:01.00", "words": "lowerCase words" }],
+            "lrc": { "idTags": ["[ti:Song]"] }
+        });
+        apply_response_case(&mut body, ResponseCase::Snake);
+
+        assert_eq!(body, json!({
+            "sync_type": "LINE_SYNCED",
+            "lines": [{ "start_time_ms": "1000", "time_tag": "00:01.00", "words": "lowerCase words" }],
+            "lrc": { "id_tags": ["[ti:Song]"] }
+        }));
+    }
+
+    #[actix_web::test]
+    async fn response_case_switches_json_keys() {
+        let (server, spotify) = mock_spotify().await;
+        mount_lyrics(&server, LYRICS_FIXTURE).await;
+
+        let state = AppState { response_case: ResponseCase::Snake, ..state(spotify) };
+        let app = actix_test::init_service(
+            App::new().app_data(web::Data::new(state)).route("/lyrics/{track_id}", web::get().to(get_lyrics_by_path))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(&format!("/lyrics/{}?format=lrc", TRACK_ID)).to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["sync_type"], "LINE_SYNCED");
+        assert_eq!(body["line_count"], 5);
+        assert_eq!(body["lines"][0]["time_tag"], "00:01.00");
+        assert!(body.get("syncType").is_none());
+
+        // camel stays the default
+        let body = get_json(&format!("/lyrics/{}?format=lrc", TRACK_ID)).await;
+        assert_eq!(body["lines"][0]["timeTag"], "00:01.00");
+    }
+
+    #[actix_web::test]
+    async fn convert_formats_supplied_lyrics_without_spotify() {
+        // Nothing is mounted, so any call to Spotify would fail
+        let server = MockServer::start().await;
+        let spotify = Spotify::new(vec!["test-sp-dc".to_string()])
+            .without_token_file()
+            .with_token_url(format!("{}/api/token", server.uri()));
+        let app = actix_test::init_service(
+            App::new().app_data(app_state(spotify)).route("/convert", web::post().to(convert))
+        ).await;
+
+        let convert_to = |format: &str| actix_test::TestRequest::post()
+            .uri(&format!("/convert?format={}", format))
+            .set_payload(LYRICS_FIXTURE)
+            .to_request();
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, convert_to("id3")).await;
+        assert_eq!(body["lines"][0]["startTimeMs"], "1000");
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, convert_to("lrc")).await;
+        assert_eq!(body["lines"][4], json!({ "timeTag": "00:05.00", "words": "Third line" }));
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, convert_to("both")).await;
+        assert_eq!(body["lrc"]["lines"][0]["timeTag"], "00:01.00");
+
+        let expected_starts = [
+            ("srt", "1\n00:00:01,000 --> "),
+            ("vtt", "WEBVTT\n"),
+            ("ttml", "<?xml"),
+            ("json-lines", "{\"t\":1000,"),
+            ("text", " First line \n"),
+        ];
+        for (format, start) in expected_starts {
+            let body = actix_test::call_and_read_body(&app, convert_to(format)).await;
+            assert!(body.starts_with(start.as_bytes()), "{}: {:?}", format, body);
+        }
+
+        for payload in ["not json", "{\"lyrics\": {}}"] {
+            let req = actix_test::TestRequest::post().uri("/convert?format=lrc").set_payload(payload).to_request();
+            let res = actix_test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", payload);
+            assert_eq!(actix_test::read_body_json::<serde_json::Value, _>(res).await["code"], "INVALID_BODY");
+        }
+        let req = actix_test::TestRequest::post().uri("/convert?format=raw").set_payload(LYRICS_FIXTURE).to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn head_reports_whether_lyrics_exist_without_a_body() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        const MISSING_ID: &str = "0000000000000000000000";
+        let (mock_server, spotify) = mock_spotify().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/lyrics/{}", MISSING_ID)))
+            .respond_with(ResponseTemplate::new(404))
+            .with_priority(1)
+            .mount(&mock_server)
+            .await;
+        mount_lyrics(&mock_server, LYRICS_FIXTURE).await;
+
+        // Over a real connection, since dropping the body for HEAD is up to the HTTP layer
+        let data = app_state(spotify);
+        let server = HttpServer::new(move || App::new().app_data(data.clone()).service(api_scope("")))
+            .workers(1)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let cases = [
+            (format!("/?trackid={}", TRACK_ID), "200"),
+            (format!("/lyrics/{}", TRACK_ID), "200"),
+            (format!("/?trackid={}", MISSING_ID), "404"),
+            (format!("/lyrics/{}", MISSING_ID), "404"),
+            (format!("/?trackid={}&format=docx", TRACK_ID), "400"),
+        ];
+        for (uri, status) in cases {
+            let mut stream = actix_web::rt::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("HEAD {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", uri).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+
+            assert!(response.starts_with(&format!("HTTP/1.1 {} ", status)), "{}: {}", uri, response);
+            assert!(response.ends_with("\r\n\r\n"), "{} has a body: {}", uri, response);
+        }
+        handle.stop(false).await;
+    }
 }
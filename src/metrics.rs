@@ -0,0 +1,109 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse};
+use prometheus::core::Collector;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use std::sync::LazyLock;
+
+/// Process-wide Prometheus collectors
+pub struct Metrics {
+    registry: Registry,
+    pub requests_total: IntCounterVec,
+    pub lyrics_cache_hits_total: IntCounter,
+    pub lyrics_cache_misses_total: IntCounter,
+    pub token_refreshes_total: IntCounter,
+    pub upstream_request_duration_seconds: HistogramVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests_total = IntCounterVec::new(
+            Opts::new("requests_total", "HTTP requests served, by response status"),
+            &["status"],
+        ).expect("valid requests_total metric");
+
+        let lyrics_cache_hits_total = IntCounter::new(
+            "lyrics_cache_hits_total",
+            "Lyrics requests answered from the in-memory cache",
+        ).expect("valid lyrics_cache_hits_total metric");
+
+        let lyrics_cache_misses_total = IntCounter::new(
+            "lyrics_cache_misses_total",
+            "Lyrics requests that had to be fetched from Spotify",
+        ).expect("valid lyrics_cache_misses_total metric");
+
+        let token_refreshes_total = IntCounter::new(
+            "token_refreshes_total",
+            "Access tokens fetched from Spotify",
+        ).expect("valid token_refreshes_total metric");
+
+        let upstream_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Duration of requests to Spotify, by endpoint",
+            ),
+            &["endpoint"],
+        ).expect("valid upstream_request_duration_seconds metric");
+
+        registry.register(Box::new(requests_total.clone())).expect("register requests_total");
+        registry.register(Box::new(lyrics_cache_hits_total.clone())).expect("register lyrics_cache_hits_total");
+        registry.register(Box::new(lyrics_cache_misses_total.clone())).expect("register lyrics_cache_misses_total");
+        registry.register(Box::new(token_refreshes_total.clone())).expect("register token_refreshes_total");
+        registry.register(Box::new(upstream_request_duration_seconds.clone())).expect("register upstream_request_duration_seconds");
+
+        Metrics {
+            registry,
+            requests_total,
+            lyrics_cache_hits_total,
+            lyrics_cache_misses_total,
+            token_refreshes_total,
+            upstream_request_duration_seconds,
+        }
+    }
+
+    /// Total HTTP requests served across every response status
+    pub fn requests_served(&self) -> u64 {
+        self.requests_total
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum()
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("encode metrics");
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Middleware counting every response by status code
+pub async fn track_requests<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let res = next.call(req).await?;
+
+    METRICS.requests_total
+        .with_label_values(&[res.status().as_str()])
+        .inc();
+
+    Ok(res)
+}
+
+/// Handler exposing the metrics for Prometheus to scrape
+pub async fn metrics_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
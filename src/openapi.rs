@@ -0,0 +1,390 @@
+use actix_web::HttpResponse;
+use serde_json::{json, Value};
+use crate::spotify::SUPPORTED_FORMATS;
+
+/// Handler serving the OpenAPI description of the lyrics endpoint, for generating client SDKs
+pub async fn openapi_endpoint() -> HttpResponse {
+    HttpResponse::Ok().json(spec())
+}
+
+/// Hand-maintained OpenAPI 3.0 document. Keep it in step with the handlers in main.rs and
+/// the response structs in spotify.rs when either changes.
+fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Spotify Lyrics API",
+            "description": "Synchronized lyrics for Spotify tracks",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/": {
+                "get": {
+                    "summary": "Get the lyrics of a track",
+                    "description": "One of `trackid`, `episodeid` or `url` is required.",
+                    "operationId": "getLyrics",
+                    // The API key is only required when the server is configured with one
+                    "security": [{}, { "apiKeyHeader": [] }, { "apiKeyQuery": [] }],
+                    "parameters": lyrics_parameters(),
+                    "responses": lyrics_responses()
+                },
+                "head": {
+                    "summary": "Check whether a track has lyrics",
+                    "description": "Runs the same lookup as GET and returns its status and headers without a body.",
+                    "operationId": "checkLyrics",
+                    "security": [{}, { "apiKeyHeader": [] }, { "apiKeyQuery": [] }],
+                    "parameters": lyrics_parameters(),
+                    "responses": {
+                        "200": { "description": "The track has lyrics" },
+                        "304": { "description": "The lyrics match the ETag sent in If-None-Match" },
+                        "400": { "description": "Missing or invalid query parameter" },
+                        "401": { "description": "Missing or wrong API key" },
+                        "404": { "description": "Spotify has no lyrics for the track" }
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "LyricLine": {
+                    "type": "object",
+                    "required": ["startTimeMs", "words", "syllables", "endTimeMs"],
+                    "properties": {
+                        "startTimeMs": { "type": "string", "example": "960" },
+                        "words": { "type": "string" },
+                        "syllables": { "type": "array", "items": { "type": "string" } },
+                        "endTimeMs": { "type": "string", "example": "0" }
+                    }
+                },
+                "LrcLine": {
+                    "type": "object",
+                    "required": ["words"],
+                    "properties": {
+                        "timeTag": {
+                            "type": "string",
+                            "description": "Absent for unsynced lyrics",
+                            "example": "00:00.96"
+                        },
+                        "words": { "type": "string" }
+                    }
+                },
+                "TrackMetadata": {
+                    "type": "object",
+                    "required": ["name", "artists", "album", "durationMs"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "artists": { "type": "array", "items": { "type": "string" } },
+                        "album": { "type": "string" },
+                        "durationMs": { "type": "integer", "format": "int64" },
+                        "cover_url": {
+                            "type": "string",
+                            "format": "uri",
+                            "description": "Medium-size album art, absent when Spotify has none"
+                        }
+                    }
+                },
+                "LyricsColors": {
+                    "type": "object",
+                    "description": "Theme colors as signed ARGB integers",
+                    "required": ["background", "text", "highlightText"],
+                    "properties": {
+                        "background": { "type": "integer", "format": "int64" },
+                        "text": { "type": "integer", "format": "int64" },
+                        "highlightText": { "type": "integer", "format": "int64" }
+                    }
+                },
+                "Id3Response": lyrics_schema(json!({ "$ref": "#/components/schemas/LyricLine" }), true),
+                "LrcResponse": lrc_schema(),
+                "BothResponse": {
+                    "type": "object",
+                    "required": ["error", "type", "syncType", "instrumental", "id3", "lrc"],
+                    "properties": {
+                        "error": { "type": "boolean", "enum": [false] },
+                        "type": item_type_schema(),
+                        "syncType": sync_type_schema(),
+                        "instrumental": {
+                            "type": "boolean",
+                            "description": "Spotify returned no lines, as it does for instrumental tracks"
+                        },
+                        "requestedLanguageAvailable": requested_language_schema(),
+                        "warnings": warnings_schema(),
+                        "id3": { "$ref": "#/components/schemas/Id3Response" },
+                        "lrc": { "$ref": "#/components/schemas/LrcResponse" }
+                    }
+                },
+                "ErrorResponse": {
+                    "type": "object",
+                    "required": ["error", "code", "message"],
+                    "properties": {
+                        "error": { "type": "boolean", "enum": [true] },
+                        "code": {
+                            "type": "string",
+                            "description": "Stable error code to branch on",
+                            "example": "NO_LYRICS"
+                        },
+                        "message": { "type": "string", "description": "Human-readable; may change" }
+                    }
+                }
+            },
+            "securitySchemes": {
+                "apiKeyHeader": { "type": "apiKey", "in": "header", "name": "X-API-Key" },
+                "apiKeyQuery": { "type": "apiKey", "in": "query", "name": "apikey" }
+            }
+        }
+    })
+}
+
+fn lyrics_parameters() -> Value {
+    json!([
+        query_parameter("trackid", "Spotify track ID", json!({ "type": "string" })),
+        query_parameter("episodeid", "Spotify podcast episode ID, returning its transcript", json!({ "type": "string" })),
+        query_parameter("url", "Spotify track or episode URL, spotify: URI, or share link", json!({ "type": "string" })),
+        query_parameter("format", "Response format", json!({
+            "type": "string",
+            "enum": SUPPORTED_FORMATS,
+            "default": "id3"
+        })),
+        query_parameter("offset", "Milliseconds added to every timestamp, may be negative", json!({
+            "type": "integer",
+            "format": "int64",
+            "default": 0
+        })),
+        query_parameter("vocalRemoval", "Request lyrics for the instrumental version; also accepted as vocal_removal", json!({
+            "type": "boolean",
+            "default": false
+        })),
+        query_parameter("market", "Two-letter country code, or from_token for the account's market", json!({
+            "type": "string",
+            "default": "from_token"
+        })),
+        query_parameter("lang", "Language of a translation to return instead of the original lyrics", json!({
+            "type": "string",
+            "maxLength": 16
+        })),
+        query_parameter("callback", "Wrap JSON responses in this JavaScript function (JSONP)", json!({
+            "type": "string",
+            "maxLength": 64
+        })),
+        query_parameter("pretty", "Indent JSON responses", json!({
+            "type": "boolean",
+            "default": false
+        })),
+        query_parameter("title", "Title for the LRC ID tags, overriding the track metadata", json!({ "type": "string" })),
+        query_parameter("artist", "Artist for the LRC ID tags, overriding the track metadata", json!({ "type": "string" })),
+        query_parameter("album", "Album for the LRC ID tags, overriding the track metadata", json!({ "type": "string" })),
+        query_parameter("trimWords", "Trim whitespace around each line's words, which are otherwise verbatim; also accepted as trim_words; not available with srt, vtt, ttml, json-lines or text", json!({
+            "type": "boolean",
+            "default": false
+        })),
+        query_parameter("skipEmpty", "Leave out musical interlude lines, whose words are empty or only ♪; also accepted as skip_empty", json!({
+            "type": "boolean",
+            "default": false
+        })),
+        query_parameter("lineOffset", "Index of the first line to return; also accepted as line_offset; not available with srt, vtt, ttml, json-lines or text", json!({
+            "type": "integer",
+            "minimum": 0,
+            "default": 0
+        })),
+        query_parameter("lineLimit", "Maximum number of lines to return; also accepted as line_limit; not available with srt, vtt, ttml, json-lines or text", json!({
+            "type": "integer",
+            "minimum": 1
+        })),
+        query_parameter("includeMetadata", "Add the track's title, artists, album and duration as `track`; also accepted as include_metadata; not available with srt, vtt, ttml, json-lines or text", json!({
+            "type": "boolean",
+            "default": false
+        }))
+    ])
+}
+
+fn lyrics_responses() -> Value {
+    json!({
+        "200": {
+            "description": "The lyrics in the requested format",
+            "content": {
+                "application/json": {
+                    "schema": {
+                        "oneOf": [
+                            { "$ref": "#/components/schemas/Id3Response" },
+                            { "$ref": "#/components/schemas/LrcResponse" },
+                            { "$ref": "#/components/schemas/BothResponse" }
+                        ]
+                    }
+                },
+                "application/x-subrip": { "schema": { "type": "string" } },
+                "text/vtt": { "schema": { "type": "string" } },
+                "application/ttml+xml": { "schema": { "type": "string" } },
+                "application/x-ndjson": {
+                    "schema": {
+                        "type": "string",
+                        "description": "One {\"t\": startMs, \"words\": \"...\"} object per line"
+                    }
+                },
+                "text/plain": {
+                    "schema": {
+                        "type": "string",
+                        "description": "The words of each line, one per line"
+                    }
+                }
+            }
+        },
+        "304": { "description": "The lyrics match the ETag sent in If-None-Match" },
+        "400": error_response("Missing or invalid query parameter"),
+        "401": error_response("Missing or wrong API key"),
+        "404": error_response("Spotify has no lyrics for the track"),
+        "429": error_response("Too many requests from this client"),
+        "500": error_response("Spotify returned an unexpected error"),
+        "502": error_response("Spotify rejected the SP_DC or returned a malformed response"),
+        "503": error_response("Spotify is rate limiting the server; retry after the Retry-After header"),
+        "504": error_response("Spotify didn't answer in time")
+    })
+}
+
+fn lyrics_schema(line: Value, with_colors: bool) -> Value {
+    let mut schema = json!({
+        "type": "object",
+        "required": ["error", "type", "syncType", "provider", "language", "lineCount", "totalDurationMs", "instrumental", "lines"],
+        "properties": {
+            "error": { "type": "boolean", "enum": [false] },
+            "type": item_type_schema(),
+            "syncType": sync_type_schema(),
+            "provider": { "type": "string" },
+            "language": { "type": "string" },
+            "lineCount": { "type": "integer" },
+            "totalDurationMs": {
+                "type": "integer",
+                "format": "int64",
+                "description": "Start time of the last line, or 0 for unsynced lyrics"
+            },
+            "instrumental": {
+                "type": "boolean",
+                "description": "Spotify returned no lines, as it does for instrumental tracks"
+            },
+            "requestedLanguageAvailable": requested_language_schema(),
+            "warnings": warnings_schema(),
+            "source": {
+                "type": "string",
+                "enum": ["lrclib"],
+                "description": "Only present when the lyrics came from the LRCLIB fallback instead of Spotify"
+            },
+            "total": {
+                "type": "integer",
+                "description": "Only present when lineOffset or lineLimit was requested; number of lines before slicing"
+            },
+            "track": {
+                "$ref": "#/components/schemas/TrackMetadata",
+                "description": "Only present when includeMetadata was requested"
+            },
+            "lines": { "type": "array", "items": line }
+        }
+    });
+
+    if with_colors {
+        schema["properties"]["colors"] = json!({ "$ref": "#/components/schemas/LyricsColors" });
+    }
+
+    schema
+}
+
+fn lrc_schema() -> Value {
+    let mut schema = lyrics_schema(json!({ "$ref": "#/components/schemas/LrcLine" }), false);
+    schema["properties"]["idTags"] = json!({
+        "type": "array",
+        "items": { "type": "string" },
+        "description": "LRC ID tags such as [ti:Title], only present when the track metadata is known",
+        "example": ["[ti:Title]", "[ar:Artist]", "[al:Album]", "[length:03:33]"]
+    });
+
+    schema
+}
+
+fn item_type_schema() -> Value {
+    json!({
+        "type": "string",
+        "enum": ["track", "episode"],
+        "description": "track for song lyrics, episode for a podcast transcript"
+    })
+}
+
+fn sync_type_schema() -> Value {
+    json!({
+        "type": "string",
+        "description": "Spotify's sync type passed through verbatim, currently LINE_SYNCED, SYLLABLE_SYNCED or UNSYNCED",
+        "example": "LINE_SYNCED"
+    })
+}
+
+fn requested_language_schema() -> Value {
+    json!({
+        "type": "boolean",
+        "description": "Only present when lang was requested; false means the default lyrics were returned"
+    })
+}
+
+fn warnings_schema() -> Value {
+    json!({
+        "type": "array",
+        "items": { "type": "string" },
+        "description": "Only present when some lines were malformed, e.g. a start time that couldn't be read and was taken as 0"
+    })
+}
+
+fn query_parameter(name: &str, description: &str, schema: Value) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": schema
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": { "$ref": "#/components/schemas/ErrorResponse" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn serves_an_openapi_3_document() {
+        let response = openapi_endpoint().await;
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let spec: Value = serde_json::from_slice(&body).unwrap();
+
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3.0"));
+        assert_eq!(spec["info"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(spec["paths"]["/"]["get"]["operationId"], "getLyrics");
+        assert_eq!(spec["components"]["schemas"]["ErrorResponse"]["required"], json!(["error", "code", "message"]));
+    }
+
+    #[test]
+    fn documents_every_query_parameter_and_format() {
+        let spec = spec();
+        let parameters = spec["paths"]["/"]["get"]["parameters"].as_array().unwrap();
+        let names: Vec<_> = parameters.iter().map(|parameter| parameter["name"].as_str().unwrap()).collect();
+
+        for name in ["trackid", "episodeid", "url", "format", "offset", "lang", "callback", "pretty"] {
+            assert!(names.contains(&name), "{} is not documented", name);
+        }
+        let format = parameters.iter().find(|parameter| parameter["name"] == "format").unwrap();
+        assert_eq!(format["schema"]["enum"], json!(SUPPORTED_FORMATS));
+    }
+
+    #[test]
+    fn every_reference_resolves() {
+        let spec = spec();
+        let text = spec.to_string();
+        for reference in text.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(spec["components"]["schemas"].get(name).is_some(), "{} is not defined", name);
+        }
+    }
+}
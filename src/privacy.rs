@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once at startup in no_store mode
+static REDACT_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Keep Spotify IDs, search queries and URLs out of every log line from now on
+pub fn enable_log_redaction() {
+    REDACT_LOGS.store(true, Ordering::Relaxed);
+}
+
+pub fn is_redacting_logs() -> bool {
+    REDACT_LOGS.load(Ordering::Relaxed)
+}
+
+/// Returns the value to log in place of an ID or query, which is hidden in no_store mode
+pub fn loggable(value: &str) -> &str {
+    redacted_if(value, is_redacting_logs())
+}
+
+fn redacted_if(value: &str, redact: bool) -> &str {
+    if redact {
+        "[redacted]"
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Redaction is process-wide once enabled, so the switch itself is tested without flipping it
+    #[test]
+    fn hides_values_only_when_redacting() {
+        assert_eq!(redacted_if("4cOdK2wGLETKBW3PvgPWqT", false), "4cOdK2wGLETKBW3PvgPWqT");
+        assert_eq!(redacted_if("4cOdK2wGLETKBW3PvgPWqT", true), "[redacted]");
+    }
+
+    #[test]
+    fn logs_values_by_default() {
+        assert!(!is_redacting_logs());
+        assert_eq!(loggable("some query"), "some query");
+    }
+}
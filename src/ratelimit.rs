@@ -0,0 +1,146 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use serde_json::json;
+use crate::clientip::client_ip;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Above this many tracked clients, idle buckets are pruned
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Per-client token bucket rate limiter allowing a burst of `per_minute` requests
+/// that refills evenly over a minute
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Create a new limiter. A limit of zero disables rate limiting.
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for the client, returning how long to wait if none are left
+    pub fn check(&self, client: &str) -> std::result::Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    // `check` as of `now`, so refilling can be tested without waiting
+    fn check_at(&self, client: &str, now: Instant) -> std::result::Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+
+        let capacity = self.per_minute as f64;
+        let refill_per_sec = capacity / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // A bucket idle for a full minute has refilled completely, so forgetting it changes nothing
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated_at) < Duration::from_secs(60));
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill_per_sec))
+        }
+    }
+}
+
+/// Middleware rejecting clients that exceed the configured rate with 429 and a `Retry-After` header
+pub async fn rate_limit<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>().cloned() {
+        let client_ip = client_ip(&req);
+
+        if let Err(retry_after) = limiter.check(&client_ip) {
+            let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let response = HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after_secs.to_string()))
+                .json(json!({
+                    "error": true,
+                    "code": "RATE_LIMITED",
+                    "message": "rate limit exceeded, please slow down!"
+                }));
+
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test as actix_test;
+    use actix_web::{App, HttpResponse};
+
+    #[test]
+    fn refills_evenly_over_a_minute() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(2);
+        assert!(limiter.check_at("client", start).is_ok());
+        assert!(limiter.check_at("client", start).is_ok());
+        assert_eq!(limiter.check_at("client", start), Err(Duration::from_secs(30)));
+
+        // Half a minute buys back one of the two tokens
+        assert!(limiter.check_at("client", start + Duration::from_secs(30)).is_ok());
+        assert!(limiter.check_at("client", start + Duration::from_secs(30)).is_err());
+
+        // Other clients have buckets of their own
+        assert!(limiter.check_at("other", start).is_ok());
+    }
+
+    #[test]
+    fn zero_disables_limiting() {
+        let limiter = RateLimiter::new(0);
+        assert!((0..100).all(|_| limiter.check("client").is_ok()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_the_request_over_the_limit() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(RateLimiter::new(3)))
+                .wrap(from_fn(rate_limit))
+                .route("/", web::get().to(HttpResponse::Ok))
+        ).await;
+
+        for _ in 0..3 {
+            let res = actix_test::call_service(&app, actix_test::TestRequest::get().uri("/").to_request()).await;
+            assert_eq!(res.status(), 200);
+        }
+
+        let res = actix_test::call_service(&app, actix_test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(res.headers().get("Retry-After").unwrap(), "20");
+    }
+}
@@ -0,0 +1,94 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer incoming IDs are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Correlation ID of the current request, stored in the request extensions for the access log
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Middleware tagging every request with the caller's X-Request-Id, or a new UUID when it
+/// didn't send a usable one, and echoing it back in the response headers
+pub async fn request_id<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<B>, Error> {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid_request_id(value))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut res = next.call(req).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    Ok(res)
+}
+
+/// Accepts IDs of printable ASCII without spaces, so they can't break log lines
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test as actix_test;
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+
+    // Echoes the ID the handler sees, to check it matches the response header
+    async fn handler_id(req: HttpRequest) -> HttpResponse {
+        let id = req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+        HttpResponse::Ok().body(id)
+    }
+
+    async fn call(incoming_id: Option<&str>) -> (String, String) {
+        let app = actix_test::init_service(
+            App::new().wrap(from_fn(request_id)).route("/", web::get().to(handler_id))
+        ).await;
+
+        let mut req = actix_test::TestRequest::get().uri("/");
+        if let Some(id) = incoming_id {
+            req = req.insert_header((REQUEST_ID_HEADER, id));
+        }
+        let res = actix_test::call_service(&app, req.to_request()).await;
+        let header = res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        let body = String::from_utf8(actix_test::read_body(res).await.to_vec()).unwrap();
+        (header, body)
+    }
+
+    #[actix_web::test]
+    async fn echoes_the_callers_request_id() {
+        let (header, seen) = call(Some("trace-abc-123")).await;
+        assert_eq!(header, "trace-abc-123");
+        assert_eq!(seen, "trace-abc-123");
+    }
+
+    #[actix_web::test]
+    async fn generates_an_id_when_absent_or_unusable() {
+        let (header, seen) = call(None).await;
+        assert!(Uuid::parse_str(&header).is_ok(), "{}", header);
+        assert_eq!(seen, header);
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for id in ["has space", too_long.as_str()] {
+            let (header, _) = call(Some(id)).await;
+            assert!(Uuid::parse_str(&header).is_ok(), "{:?} was kept", id);
+        }
+    }
+}
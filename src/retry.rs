@@ -0,0 +1,117 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Never wait longer than this between attempts; a longer Retry-After gives up instead
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Retry policy for transient upstream failures (5xx). 429s aren't retried here, since retrying
+/// each request on its own only prolongs the rate limit.
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, base_backoff: Duration) -> Self {
+        RetryPolicy {
+            max_retries,
+            base_backoff,
+        }
+    }
+
+    /// A policy that never retries
+    pub fn none() -> Self {
+        RetryPolicy::new(0, Duration::ZERO)
+    }
+
+    /// Returns how long to wait before retrying a response with the given status, or `None`
+    /// if it should not be retried. `retries` is the number of retries already made.
+    pub fn next_delay(&self, status: StatusCode, retries: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if !status.is_server_error() || retries >= self.max_retries {
+            return None;
+        }
+
+        // Honor the server's explicit wait when it gives one
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= MAX_BACKOFF).then_some(retry_after);
+        }
+
+        // Exponential backoff with up to 50% jitter so concurrent retries spread out
+        let backoff = self.base_backoff
+            .saturating_mul(2u32.saturating_pow(retries))
+            .min(MAX_BACKOFF);
+        let jitter_range = backoff.as_millis() as u64 / 2 + 1;
+        let jitter = Duration::from_millis(jitter_seed() % jitter_range);
+
+        Some(backoff + jitter)
+    }
+}
+
+/// Parses a `Retry-After` header given in seconds
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers.get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+// Cheap source of jitter; it only needs to differ between concurrent callers
+fn jitter_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const BASE: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let policy = RetryPolicy::new(5, BASE);
+
+        for (retries, backoff) in [(0, 100), (1, 200), (2, 400)] {
+            let delay = policy.next_delay(StatusCode::SERVICE_UNAVAILABLE, retries, None).unwrap();
+            let backoff = Duration::from_millis(backoff);
+            assert!(delay >= backoff && delay <= backoff + backoff / 2, "retry {}: {:?}", retries, delay);
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries_and_on_client_errors() {
+        let policy = RetryPolicy::new(2, BASE);
+        assert!(policy.next_delay(StatusCode::BAD_GATEWAY, 1, None).is_some());
+        assert!(policy.next_delay(StatusCode::BAD_GATEWAY, 2, None).is_none());
+        assert!(policy.next_delay(StatusCode::TOO_MANY_REQUESTS, 0, None).is_none());
+        assert!(policy.next_delay(StatusCode::NOT_FOUND, 0, None).is_none());
+        assert!(RetryPolicy::none().next_delay(StatusCode::BAD_GATEWAY, 0, None).is_none());
+    }
+
+    #[test]
+    fn honors_retry_after_up_to_the_cap() {
+        let policy = RetryPolicy::new(2, BASE);
+        assert_eq!(policy.next_delay(StatusCode::SERVICE_UNAVAILABLE, 0, Some(Duration::from_secs(3))), Some(Duration::from_secs(3)));
+        assert_eq!(policy.next_delay(StatusCode::SERVICE_UNAVAILABLE, 0, Some(Duration::from_secs(31))), None);
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(" 12 "));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(12)));
+
+        // HTTP dates aren't supported and count as absent
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+}
@@ -0,0 +1,137 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use crate::auth::constant_time_eq;
+
+/// Header carrying the hex-encoded HMAC-SHA256 of the request's path and query string
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Verifies requests signed by a gateway in front of the server with a shared secret
+pub struct RequestVerifier {
+    secret: Option<Vec<u8>>,
+}
+
+impl RequestVerifier {
+    /// Create a new verifier. Without a secret every request is accepted.
+    pub fn new(secret: Option<String>) -> Self {
+        RequestVerifier {
+            secret: secret.map(String::into_bytes),
+        }
+    }
+
+    /// Checks a signature against the canonical request, the path plus the query string
+    /// exactly as sent (e.g. `/lyrics/abc?format=lrc`)
+    pub fn verify(&self, path_and_query: &str, signature: Option<&str>) -> bool {
+        let secret = match &self.secret {
+            Some(secret) => secret,
+            None => return true,
+        };
+
+        let signature = match signature {
+            Some(signature) => signature.trim().to_ascii_lowercase(),
+            None => return false,
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take key of any size");
+        mac.update(path_and_query.as_bytes());
+        let expected = mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        constant_time_eq(signature.as_bytes(), expected.as_bytes())
+    }
+}
+
+/// Middleware rejecting requests whose `X-Signature` doesn't match with 401
+pub async fn verify_signature<B: MessageBody + 'static>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    if let Some(verifier) = req.app_data::<web::Data<RequestVerifier>>().cloned() {
+        let path_and_query = req.uri()
+            .path_and_query()
+            .map_or(req.path(), |path_and_query| path_and_query.as_str())
+            .to_string();
+        let signature = req.headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok());
+
+        if !verifier.verify(&path_and_query, signature) {
+            let response = HttpResponse::Unauthorized().json(json!({
+                "error": true,
+                "code": "INVALID_SIGNATURE",
+                "message": "missing or invalid request signature!"
+            }));
+
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test as actix_test;
+    use actix_web::App;
+
+    const SECRET: &str = "shared-secret";
+
+    fn sign(path_and_query: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(path_and_query.as_bytes());
+        mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn accepts_only_the_signature_of_the_exact_request() {
+        let verifier = RequestVerifier::new(Some(SECRET.to_string()));
+        let signature = sign("/lyrics/abc?format=lrc");
+
+        assert!(verifier.verify("/lyrics/abc?format=lrc", Some(&signature)));
+        assert!(verifier.verify("/lyrics/abc?format=lrc", Some(&signature.to_ascii_uppercase())));
+        assert!(!verifier.verify("/lyrics/abc?format=srt", Some(&signature)));
+        assert!(!verifier.verify("/lyrics/abd?format=lrc", Some(&signature)));
+        assert!(!verifier.verify("/lyrics/abc?format=lrc", Some(&signature[..63])));
+        assert!(!verifier.verify("/lyrics/abc?format=lrc", None));
+    }
+
+    #[test]
+    fn accepts_everything_without_a_secret() {
+        assert!(RequestVerifier::new(None).verify("/anything", None));
+    }
+
+    #[actix_web::test]
+    async fn middleware_rejects_tampered_requests() {
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(from_fn(verify_signature))
+                .app_data(web::Data::new(RequestVerifier::new(Some(SECRET.to_string()))))
+                .route("/lyrics/{id}", web::get().to(HttpResponse::Ok))
+        ).await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/lyrics/abc?format=lrc")
+            .insert_header((SIGNATURE_HEADER, sign("/lyrics/abc?format=lrc")))
+            .to_request();
+        assert_eq!(actix_test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/lyrics/abc?format=srt")
+            .insert_header((SIGNATURE_HEADER, sign("/lyrics/abc?format=lrc")))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = actix_test::read_body_json(res).await;
+        assert_eq!(body["code"], "INVALID_SIGNATURE");
+    }
+}
@@ -1,18 +1,93 @@
+use crate::cache::LyricsCache;
+use crate::diskcache::DiskCache;
+use crate::metrics::METRICS;
+use crate::privacy::loggable;
+use crate::retry::{self, RetryPolicy};
 use crate::spotifyexception::SpotifyException;
+use crate::upstreamlog;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, COOKIE, ORIGIN, REFERER, USER_AGENT};
 use sha1::Sha1;
 use base32::Alphabet;
-use log::{error, info, debug};
+use log::{error, info, debug, warn};
 
 type Result<T> = std::result::Result<T, SpotifyException>;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Upper bound on establishing a connection, regardless of the overall request timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Overall timeout for a single outbound request unless configured otherwise
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Refresh access tokens this long before they expire unless configured otherwise
+const DEFAULT_TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+// Lyrics with more lines than this are rejected as an upstream anomaly unless configured otherwise
+const DEFAULT_MAX_LYRIC_LINES: usize = 10000;
+
+// How long lyrics requests are held back after a 429 that came without a Retry-After
+const DEFAULT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+// Server time requests are attempted this many times unless configured otherwise
+const DEFAULT_SERVER_TIME_ATTEMPTS: u32 = 3;
+
+// Pause before retrying the server time, multiplied by the attempt number
+const SERVER_TIME_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+// Each check behind the verbose health endpoint gives up after this long
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Subtitle cues are pushed apart so each one stays on screen at least this long
+const MIN_CUE_DURATION_MS: u64 = 100;
+
+// The last subtitle cue has no following line to end it, so it is shown for this long
+const LAST_CUE_DURATION_MS: u64 = 5000;
+
+/// Formats `get_formatted_lyrics` produces. `raw` is handled separately since it's a debug-only passthrough.
+pub const SUPPORTED_FORMATS: [&str; 9] = ["id3", "lrc", "elrc", "both", "srt", "vtt", "ttml", "json-lines", "text"];
+
+/// Formats returned as a plain-text document rather than a JSON object
+pub fn is_text_format(format: &str) -> bool {
+    matches!(format, "srt" | "vtt" | "ttml" | "json-lines" | "text")
+}
+
+// Sent as Referer and Origin so requests look like they come from the web player
+const WEB_PLAYER_ORIGIN: &str = "https://open.spotify.com/";
+
+// Short-link hosts used by the mobile app's share sheet
+const SHARE_URL_HOSTS: [&str; 2] = ["spotify.link", "spotify.app.link"];
+
+/// LRCLIB's lookup endpoint, used when the LRCLIB fallback is enabled
+pub const DEFAULT_LRCLIB_URL: &str = "https://lrclib.net/api/get";
+
+// LRCLIB asks clients to identify themselves rather than pose as a browser
+const LRCLIB_USER_AGENT: &str = concat!("spotifylyricsapi/", env!("CARGO_PKG_VERSION"));
+
+/// User agent sent with every request, identifying as a browser running the web player
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:124.0) Gecko/20100101 Firefox/124.0";
+
+/// Value of the `app-platform` header sent with every request
+pub const DEFAULT_APP_PLATFORM: &str = "WebPlayer";
+
+/// Web player version sent as `spotify-app-version`; Spotify may reject versions that are too old
+pub const DEFAULT_APP_VERSION: &str = "1.2.61.20.g3b4cd5b2";
+
+/// Base32 TOTP secret used by the web player, as published in the original PHP implementation
+pub const DEFAULT_TOTP_SECRET: &str = "GU2TANZRGQ2TQNJTGQ4DONBZHE2TSMRSGQ4DMMZQGMZDSMZUG4";
+
+/// Version of the TOTP secret, sent as `totpVer` with token requests
+pub const DEFAULT_TOTP_VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct CacheData {
     #[serde(skip_serializing_if = "Option::is_none")]
     access_token: Option<String>,
@@ -35,107 +110,793 @@ pub struct LyricLine {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LrcLine {
-    #[serde(rename = "timeTag")]
-    pub time_tag: String,
+    /// Absent for unsynced lyrics, so LRC parsers don't place every line at time zero
+    #[serde(rename = "timeTag", skip_serializing_if = "Option::is_none")]
+    pub time_tag: Option<String>,
     pub words: String,
 }
 
+/// Theme colors Spotify uses to render the lyrics view, as signed ARGB integers
 #[derive(Serialize, Deserialize, Debug)]
-pub struct Id3Response {
+pub struct LyricsColors {
+    pub background: i64,
+    pub text: i64,
+    #[serde(rename = "highlightText")]
+    pub highlight_text: i64,
+}
+
+/// A subtitle cue with its display window in milliseconds
+#[derive(Debug)]
+struct Cue {
+    start_ms: u64,
+    end_ms: u64,
+    text: String,
+}
+
+/// Fields every JSON lyrics response starts with, whatever its line format
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LyricsStatus {
     pub error: bool,
+    /// `track` for song lyrics, `episode` for a podcast transcript
+    #[serde(rename = "type")]
+    pub item_type: String,
+    /// Spotify's sync type verbatim: `LINE_SYNCED`, `SYLLABLE_SYNCED` (lines also carry word timings)
+    /// or `UNSYNCED`, which is also used when Spotify sent none or there are no lines
     #[serde(rename = "syncType")]
     pub sync_type: String,
+    /// True when Spotify returned a lyrics object with no lines, as it does for instrumental tracks.
+    /// `lines` is then empty and `syncType` is UNSYNCED, with `error` still false.
+    pub instrumental: bool,
+    /// Only present when a `lang` was requested; false means the default lyrics were returned
+    #[serde(rename = "requestedLanguageAvailable", skip_serializing_if = "Option::is_none")]
+    pub requested_language_available: Option<bool>,
+    /// Problems with individual lines that were worked around, such as a malformed start time
+    /// taken as 0. Only present when there are any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Id3Response {
+    #[serde(flatten)]
+    pub status: LyricsStatus,
+    pub provider: String,
+    pub language: String,
+    #[serde(rename = "lineCount")]
+    pub line_count: usize,
+    /// Start time of the last line, or 0 for unsynced lyrics
+    #[serde(rename = "totalDurationMs")]
+    pub total_duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colors: Option<LyricsColors>,
     pub lines: Vec<LyricLine>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LrcResponse {
-    pub error: bool,
-    #[serde(rename = "syncType")]
-    pub sync_type: String,
+    #[serde(flatten)]
+    pub status: LyricsStatus,
+    pub provider: String,
+    pub language: String,
+    #[serde(rename = "lineCount")]
+    pub line_count: usize,
+    /// Start time of the last line, or 0 for unsynced lyrics
+    #[serde(rename = "totalDurationMs")]
+    pub total_duration_ms: u64,
     pub lines: Vec<LrcLine>,
 }
 
+/// Both JSON representations of the same lyrics, for `format=both`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BothResponse {
+    #[serde(flatten)]
+    pub status: LyricsStatus,
+    pub id3: Id3Response,
+    pub lrc: LrcResponse,
+}
+
+/// Metadata shared by every JSON representation of a track's lyrics
+#[derive(Clone)]
+struct LyricsMetadata {
+    item_type: &'static str,
+    provider: String,
+    language: String,
+    line_count: usize,
+    total_duration_ms: u64,
+    instrumental: bool,
+    requested_language_available: Option<bool>,
+    warnings: Vec<String>,
+}
+
+impl LyricsMetadata {
+    fn from_lyrics(lyrics_data: &serde_json::Value, sync_type: &str, offset_ms: i64) -> Self {
+        // Lyric span for progress bars, measured to the last line's (shifted) start
+        let lyrics_lines = lyrics_data["lyrics"]["lines"].as_array();
+        let total_duration_ms = if sync_type == "UNSYNCED" {
+            0
+        } else {
+            lyrics_lines
+                .and_then(|lines| lines.last())
+                .and_then(|line| line["startTimeMs"].as_str())
+                .and_then(|start| start.parse::<u64>().ok())
+                .map_or(0, |start| start.saturating_add_signed(offset_ms))
+        };
+
+        LyricsMetadata {
+            item_type: "track",
+            // Provider and language, so clients can credit the source and pick fonts
+            provider: lyrics_data["lyrics"]["provider"].as_str().unwrap_or("").to_string(),
+            language: lyrics_data["lyrics"]["language"].as_str().unwrap_or("").to_string(),
+            line_count: lyrics_lines.map_or(0, |lines| lines.len()),
+            total_duration_ms,
+            instrumental: lyrics_lines.is_none_or(|lines| lines.is_empty()),
+            requested_language_available: None,
+            warnings: line_warnings(lyrics_data, sync_type),
+        }
+    }
+
+    fn status(&self, sync_type: &str) -> LyricsStatus {
+        LyricsStatus {
+            error: false,
+            item_type: self.item_type.to_string(),
+            sync_type: sync_type.to_string(),
+            instrumental: self.instrumental,
+            requested_language_available: self.requested_language_available,
+            warnings: self.warnings.clone(),
+        }
+    }
+}
+
+/// Describes lines whose start time can't be read and is taken as 0, which would otherwise
+/// silently misplace them. Unsynced lyrics have no timings to get wrong.
+fn line_warnings(lyrics_data: &serde_json::Value, sync_type: &str) -> Vec<String> {
+    if sync_type == "UNSYNCED" {
+        return Vec::new();
+    }
+
+    let lines = lyrics_data["lyrics"]["lines"].as_array().map_or(&[][..], Vec::as_slice);
+    lines.iter()
+        .enumerate()
+        .filter_map(|(index, line)| match &line["startTimeMs"] {
+            serde_json::Value::String(start) if start.parse::<u64>().is_ok() => None,
+            serde_json::Value::Null => Some(format!("line {} has no startTimeMs, using 0", index + 1)),
+            start => Some(format!("line {} has an invalid startTimeMs {}, using 0", index + 1, start)),
+        })
+        .collect()
+}
+
+/// A track returned by the search endpoint
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchTrack {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub album: String,
+}
+
+/// Title, artists, album and length of a track, for clients displaying lyrics alongside them
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrackMetadata {
+    pub name: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    #[serde(rename = "durationMs")]
+    pub duration_ms: u64,
+    /// Medium-size album art, absent when Spotify lists no images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_url: Option<String>,
+}
+
+/// Values for the ID tags that open an LRC file, e.g. `[ti:Title]`; tags without a value are left out
+#[derive(Debug, Clone, Default)]
+pub struct LrcTags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub length_ms: Option<u64>,
+}
+
+impl LrcTags {
+    /// Fills the tags not already set from the track's metadata, so values passed by the client win
+    pub fn fill_from(&mut self, metadata: &TrackMetadata) {
+        self.title.get_or_insert_with(|| metadata.name.clone());
+        self.artist.get_or_insert_with(|| metadata.artists.join(", "));
+        self.album.get_or_insert_with(|| metadata.album.clone());
+        if metadata.duration_ms > 0 {
+            self.length_ms.get_or_insert(metadata.duration_ms);
+        }
+    }
+
+    /// The tags in the order media players expect them, e.g. `["[ti:Title]", "[length:03:33]"]`
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for (tag, value) in [("ti", &self.title), ("ar", &self.artist), ("al", &self.album)] {
+            // A closing bracket would end the tag early
+            if let Some(value) = value.as_deref().map(|value| value.replace(']', ")")).filter(|value| !value.is_empty()) {
+                lines.push(format!("[{}:{}]", tag, value));
+            }
+        }
+
+        if let Some(length_ms) = self.length_ms {
+            let total_seconds = length_ms / 1000;
+            lines.push(format!("[length:{:02}:{:02}]", total_seconds / 60, total_seconds % 60));
+        }
+
+        lines
+    }
+
+    /// Adds the tags as `idTags` to the LRC response in a JSON lyrics body, or to the `lrc`
+    /// half of a `both` body. Other formats are left unchanged.
+    pub fn apply(&self, body: &mut serde_json::Value, format: &str) {
+        let lines = self.to_lines();
+        if lines.is_empty() {
+            return;
+        }
+
+        let response = match format {
+            "lrc" | "elrc" => Some(body),
+            "both" => body.get_mut("lrc"),
+            _ => None,
+        };
+
+        if let Some(response) = response.and_then(serde_json::Value::as_object_mut) {
+            response.insert("idTags".to_string(), json!(lines));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchResponse {
+    pub error: bool,
+    pub tracks: Vec<SearchTrack>,
+}
+
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ErrorResponse {
     pub error: bool,
     pub message: String,
 }
 
+/// Outcome of the active checks behind the verbose health endpoint
+pub struct HealthReport {
+    /// Spotify's server time endpoint answered successfully
+    pub upstream_reachable: bool,
+    /// A valid access token is cached or could be fetched
+    pub token_valid: bool,
+    /// The token file and disk cache directories accept writes, `None` when nothing is kept on disk
+    pub cache_writable: Option<bool>,
+}
+
+/// Formatted lyrics along with whether they were served from the in-memory cache
+pub struct FormattedLyrics {
+    pub body: serde_json::Value,
+    pub cache_hit: bool,
+}
+
+impl FormattedLyrics {
+    /// Wraps a formatted body, cutting its lines down to `page` when one was requested.
+    /// The cache always holds the full lyrics, so every page is served from the same entry.
+    fn paged(mut body: serde_json::Value, cache_hit: bool, page: Option<LinePage>) -> Self {
+        if let Some(page) = page {
+            page.apply(&mut body);
+        }
+
+        FormattedLyrics { body, cache_hit }
+    }
+}
+
+/// A window of lines for clients paging through very long lyrics
+#[derive(Debug, Clone, Copy)]
+pub struct LinePage {
+    /// Index of the first line to return
+    pub offset: usize,
+    /// Maximum number of lines to return, or every remaining line
+    pub limit: Option<usize>,
+}
+
+impl LinePage {
+    /// Slices the `lines` of a JSON lyrics body, recording the unsliced count next to them as
+    /// `total`. An offset past the end leaves `lines` empty rather than failing.
+    fn apply(self, body: &mut serde_json::Value) {
+        if body.get("lines").is_some() {
+            self.slice(body);
+        } else {
+            // `both` nests an id3 and an lrc response, each with its own lines
+            for key in ["id3", "lrc"] {
+                if let Some(response) = body.get_mut(key) {
+                    self.slice(response);
+                }
+            }
+        }
+    }
+
+    fn slice(self, response: &mut serde_json::Value) {
+        if let Some(lines) = response["lines"].as_array_mut() {
+            let total = lines.len();
+            let start = self.offset.min(total);
+            let end = self.limit.map_or(total, |limit| start.saturating_add(limit).min(total));
+
+            lines.truncate(end);
+            lines.drain(..start);
+            response["total"] = json!(total);
+        }
+    }
+}
+
+/// Writes and removes a probe file to find out whether files can be created in `dir`
+fn is_dir_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".health-probe-{}", std::process::id()));
+    let writable = File::create(&probe).and_then(|mut file| file.write_all(b"ok")).is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+/// Trims leading and trailing whitespace from the `words` of every line of a JSON lyrics body.
+/// Formatting copies Spotify's words verbatim, so this only runs when a client asks for it,
+/// after the cache.
+pub fn trim_line_words(body: &mut serde_json::Value) {
+    let responses = if body.get("lines").is_some() {
+        vec![body]
+    } else {
+        // `both` nests an id3 and an lrc response, each with its own lines
+        body.as_object_mut()
+            .map(|body| body.iter_mut().filter(|(key, _)| *key == "id3" || *key == "lrc").map(|(_, response)| response).collect())
+            .unwrap_or_default()
+    };
+
+    for response in responses {
+        for line in response["lines"].as_array_mut().into_iter().flatten() {
+            if let Some(words) = line["words"].as_str() {
+                line["words"] = json!(words.trim());
+            }
+        }
+    }
+}
+
+/// What a Spotify link points to, for the kinds of content this API returns lines for
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpotifyItem {
+    Track(String),
+    Episode(String),
+}
+
+impl SpotifyItem {
+    pub fn id(&self) -> &str {
+        match self {
+            SpotifyItem::Track(id) | SpotifyItem::Episode(id) => id,
+        }
+    }
+}
+
+/// How outbound requests identify themselves to Spotify
+struct ClientIdentity {
+    user_agent: String,
+    app_platform: String,
+    app_version: String,
+}
+
+impl Default for ClientIdentity {
+    fn default() -> Self {
+        ClientIdentity {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            app_platform: DEFAULT_APP_PLATFORM.to_string(),
+            app_version: DEFAULT_APP_VERSION.to_string(),
+        }
+    }
+}
+
+/// How a request to Spotify authenticates: the sp_dc cookie for token requests, the access token for everything else
+enum Credentials<'a> {
+    SpDc(&'a str),
+    Bearer(&'a str),
+}
+
 pub struct Spotify {
+    client: reqwest::Client,
+    request_timeout: Duration,
+    client_identity: ClientIdentity,
     token_url: String,
     lyrics_url: String,
     server_time_url: String,
-    sp_dc: String,
-    cache_file: PathBuf,
+    /// Page the web player version is discovered from
+    web_player_url: String,
+    search_url: String,
+    album_url: String,
+    tracks_url: String,
+    playlist_url: String,
+    transcript_url: String,
+    /// Set when the LRCLIB fallback is enabled
+    lrclib_url: Option<String>,
+    sp_dc_tokens: Vec<String>,
+    current_sp_dc: AtomicUsize,
+    /// Where the access token is persisted across restarts, unless the token file is disabled
+    cache_file: Option<PathBuf>,
+    /// The access token every worker reads, filled from the token file on first use
+    token: RwLock<Option<CacheData>>,
+    lyrics_cache: Option<Mutex<LyricsCache>>,
+    /// Tracks Spotify recently had no lyrics for, holding the not-found message
+    missing_lyrics_cache: Option<Mutex<LyricsCache>>,
+    /// Track metadata for `include_metadata`, kept apart so it never evicts lyrics
+    metadata_cache: Option<Mutex<LyricsCache>>,
+    disk_cache: Option<DiskCache>,
+    retry_policy: RetryPolicy,
+    /// Decoded TOTP secret bytes
+    totp_secret: Vec<u8>,
+    totp_version: u32,
+    token_expiry_margin: Duration,
+    max_lyric_lines: usize,
+    server_time_attempts: u32,
+    /// Log every request to Spotify with its response, for debugging
+    debug_upstream: bool,
+    /// Use an anonymous token when no sp_dc yields a logged-in one
+    allow_anonymous: bool,
+    token_refresh_lock: tokio::sync::Mutex<()>,
+    /// Set when Spotify rate-limits every sp_dc; lyrics requests fail fast until then
+    rate_limited_until: Mutex<Option<Instant>>,
 }
 
 impl Spotify {
-    /// Create a new Spotify instance with the provided sp_dc cookie value
-    pub fn new(sp_dc: String) -> Self {
+    /// Create a new Spotify instance with the provided sp_dc cookie values.
+    /// The first token is used until Spotify rejects or rate-limits it, then the next one.
+    pub fn new(sp_dc_tokens: Vec<String>) -> Self {
         let cache_file = std::env::temp_dir().join("spotify_token.json");
         
         Spotify {
+            client: build_client(DEFAULT_REQUEST_TIMEOUT, &ClientIdentity::default()),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            client_identity: ClientIdentity::default(),
             token_url: "https://open.spotify.com/api/token".to_string(),
             lyrics_url: "https://spclient.wg.spotify.com/color-lyrics/v2/track/".to_string(),
             server_time_url: "https://open.spotify.com/api/server-time".to_string(),
-            sp_dc,
-            cache_file,
+            web_player_url: WEB_PLAYER_ORIGIN.to_string(),
+            search_url: "https://api.spotify.com/v1/search".to_string(),
+            album_url: "https://api.spotify.com/v1/albums/".to_string(),
+            tracks_url: "https://api.spotify.com/v1/tracks/".to_string(),
+            playlist_url: "https://api.spotify.com/v1/playlists/".to_string(),
+            transcript_url: "https://spclient.wg.spotify.com/transcript-read-along/v2/episode/".to_string(),
+            lrclib_url: None,
+            sp_dc_tokens,
+            current_sp_dc: AtomicUsize::new(0),
+            cache_file: Some(cache_file),
+            token: RwLock::new(None),
+            lyrics_cache: None,
+            missing_lyrics_cache: None,
+            metadata_cache: None,
+            disk_cache: None,
+            retry_policy: RetryPolicy::none(),
+            totp_secret: decode_totp_secret(DEFAULT_TOTP_SECRET).expect("default TOTP secret is valid base32"),
+            totp_version: DEFAULT_TOTP_VERSION,
+            token_expiry_margin: DEFAULT_TOKEN_EXPIRY_MARGIN,
+            max_lyric_lines: DEFAULT_MAX_LYRIC_LINES,
+            server_time_attempts: DEFAULT_SERVER_TIME_ATTEMPTS,
+            debug_upstream: false,
+            allow_anonymous: false,
+            token_refresh_lock: tokio::sync::Mutex::new(()),
+            rate_limited_until: Mutex::new(None),
         }
     }
 
-    /// Loads the cache file and returns the data
-    fn load_cache_file(&self) -> Result<CacheData> {
-        if self.cache_file.exists() {
-            let mut file = File::open(&self.cache_file)?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            
-            let data = serde_json::from_str(&contents)?;
-            Ok(data)
-        } else {
-            Ok(CacheData {
-                access_token: None,
-                client_id: None,
-                access_token_expiration_timestamp_ms: None,
-            })
+    /// Enable the in-memory lyrics cache with the given TTL and maximum entry count
+    pub fn with_lyrics_cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.lyrics_cache = Some(Mutex::new(LyricsCache::new(ttl, capacity)));
+        self
+    }
+
+    /// Remember tracks without lyrics for `ttl`, answering repeat requests with the same 404
+    /// instead of asking Spotify again. Kept apart from the lyrics cache so the TTL can be shorter.
+    pub fn with_missing_lyrics_cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.missing_lyrics_cache = Some(Mutex::new(LyricsCache::new(ttl, capacity)));
+        self
+    }
+
+    /// Remember track metadata for `ttl` in memory only; it is a cheap request, so it has no disk cache
+    pub fn with_metadata_cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.metadata_cache = Some(Mutex::new(LyricsCache::new(ttl, capacity)));
+        self
+    }
+
+    /// Also keep formatted lyrics as files in `dir` for `ttl`, so the cache survives restarts
+    pub fn with_disk_cache(mut self, dir: PathBuf, ttl: Duration) -> Self {
+        self.disk_cache = Some(DiskCache::new(dir, ttl));
+        self
+    }
+
+    /// Abort outbound requests to Spotify that take longer than `timeout`
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self.client = build_client(timeout, &self.client_identity);
+        self
+    }
+
+    /// Override the user agent, `app-platform` and `spotify-app-version` headers,
+    /// for when Spotify starts rejecting the built-in web player version
+    pub fn with_client_identity(mut self, user_agent: String, app_platform: String, app_version: String) -> Self {
+        self.client_identity = ClientIdentity {
+            user_agent,
+            app_platform,
+            app_version,
+        };
+        self.client = build_client(self.request_timeout, &self.client_identity);
+        self
+    }
+
+    /// Replace the `spotify-app-version` with the one the web player currently runs, read from its
+    /// page, so the header doesn't drift out of date. The version is kept for the life of the
+    /// process; when it can't be found the configured version stays.
+    pub async fn with_discovered_app_version(mut self) -> Self {
+        match self.discover_app_version().await {
+            Ok(app_version) => {
+                info!("Discovered web player version {}", app_version);
+                self.client_identity.app_version = app_version;
+                self.client = build_client(self.request_timeout, &self.client_identity);
+            },
+            Err(e) => {
+                warn!("Failed to discover the web player version, keeping {}: {}", self.client_identity.app_version, e);
+            }
         }
+        self
     }
 
-    /// Saves the cache data to the cache file
-    fn save_cache_file(&self, data: &CacheData) -> Result<()> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.cache_file)?;
-            
-        let json = serde_json::to_string(data)?;
-        file.write_all(json.as_bytes())?;
+    /// Fetches the web player page and picks the version out of it
+    async fn discover_app_version(&self) -> Result<String> {
+        let response = self.send_timed("web_player", self.client.get(&self.web_player_url)).await?;
         
-        Ok(())
+        if !response.status().is_success() {
+            return Err(SpotifyException::ApiError(format!(
+                "Web player page request failed: HTTP status {}",
+                response.status()
+            )));
+        }
+        
+        let page = response.text().await?;
+        find_app_version(&page)
+            .ok_or_else(|| SpotifyException::InvalidResponse("no version found on the web player page".to_string()))
+    }
+
+    /// Override the TOTP secret and version, for when Spotify rotates them.
+    /// The secret is the output of `decode_totp_secret`.
+    pub fn with_totp(mut self, secret: Vec<u8>, version: u32) -> Self {
+        self.totp_secret = secret;
+        self.totp_version = version;
+        self
+    }
+
+    /// Treat access tokens as expired once they are within `margin` of their expiry,
+    /// so a request never starts with a token that runs out mid-flight
+    pub fn with_token_expiry_margin(mut self, margin: Duration) -> Self {
+        self.token_expiry_margin = margin;
+        self
+    }
+
+    /// Reject lyrics with more than `max_lines` lines instead of formatting them
+    pub fn with_max_lyric_lines(mut self, max_lines: usize) -> Self {
+        self.max_lyric_lines = max_lines;
+        self
+    }
+
+    /// Try the server time request up to `attempts` times before falling back to the local clock.
+    /// Zero skips the request and always uses the local clock.
+    pub fn with_server_time_attempts(mut self, attempts: u32) -> Self {
+        self.server_time_attempts = attempts;
+        self
+    }
+
+    /// Log the URL, headers, status and start of the body of every request to Spotify.
+    /// Credentials are redacted, but lyrics and track details are logged verbatim.
+    pub fn with_upstream_logging(mut self) -> Self {
+        self.debug_upstream = true;
+        self
+    }
+
+    /// Accept the anonymous token Spotify returns for an sp_dc that isn't a logged-in session,
+    /// rather than failing. Each configured sp_dc is still tried first. Lyrics requests usually
+    /// fail with an anonymous token, but some public data can be read with it.
+    pub fn with_anonymous_tokens(mut self) -> Self {
+        self.allow_anonymous = true;
+        self
+    }
+
+    /// Retry lyrics requests that fail with 5xx up to `max_retries` times with exponential backoff.
+    /// A 429 pauses lyrics requests for its Retry-After instead, see `check_rate_limit`.
+    pub fn with_retry_policy(mut self, max_retries: u32, base_backoff: Duration) -> Self {
+        self.retry_policy = RetryPolicy::new(max_retries, base_backoff);
+        self
+    }
+
+    /// Send token requests to `url` instead of open.spotify.com, e.g. a proxy or a mock server
+    pub fn with_token_url(mut self, url: String) -> Self {
+        self.token_url = url;
+        self
+    }
+
+    /// Send lyrics requests to `url` instead of spclient. The track ID is appended to it,
+    /// so it should end with a slash
+    pub fn with_lyrics_url(mut self, url: String) -> Self {
+        self.lyrics_url = url;
+        self
+    }
+
+    /// Send server time requests to `url` instead of open.spotify.com
+    pub fn with_server_time_url(mut self, url: String) -> Self {
+        self.server_time_url = url;
+        self
+    }
+
+    /// Discover the web player version from `url` instead of open.spotify.com
+    pub fn with_web_player_url(mut self, url: String) -> Self {
+        self.web_player_url = url;
+        self
+    }
+
+    /// Send album requests to `url` instead of api.spotify.com
+    pub fn with_album_url(mut self, url: String) -> Self {
+        self.album_url = url;
+        self
+    }
+
+    /// Send track metadata requests to `url` instead of api.spotify.com
+    pub fn with_tracks_url(mut self, url: String) -> Self {
+        self.tracks_url = url;
+        self
+    }
+
+    /// Send search requests to `url` instead of api.spotify.com
+    pub fn with_search_url(mut self, url: String) -> Self {
+        self.search_url = url;
+        self
+    }
+
+    /// Send playlist requests to `url` instead of api.spotify.com
+    pub fn with_playlist_url(mut self, url: String) -> Self {
+        self.playlist_url = url;
+        self
+    }
+
+    /// Send episode transcript requests to `url` instead of spclient.wg.spotify.com
+    pub fn with_transcript_url(mut self, url: String) -> Self {
+        self.transcript_url = url;
+        self
+    }
+
+    /// Look up tracks Spotify has no lyrics for on LRCLIB's `/api/get` endpoint at `url`
+    pub fn with_lrclib_fallback(mut self, url: String) -> Self {
+        self.lrclib_url = Some(url);
+        self
+    }
+
+    /// Keep the access token only in memory, never touching the token file.
+    /// For read-only or ephemeral filesystems; the token is refetched after every restart.
+    pub fn without_token_file(mut self) -> Self {
+        self.cache_file = None;
+        self
     }
 
-    /// Generates a Time-based One-Time Password (TOTP) using the server time
-    fn generate_totp(&self, server_time_seconds: u64) -> String {
-        // Using the hardcoded secret from the PHP code
-        let secret_base32 = "GU2TANZRGQ2TQNJTGQ4DONBZHE2TSMRSGQ4DMMZQGMZDSMZUG4";
+    /// Returns the shared access token, reading the token file the first time it is needed
+    fn load_token(&self) -> Result<CacheData> {
+        if let Some(token) = self.token.read().unwrap().as_ref() {
+            return Ok(token.clone());
+        }
+        
+        let mut token = self.token.write().unwrap();
+        
+        // Another worker may have read the file while we were waiting for the lock
+        if token.is_none() {
+            *token = Some(self.read_token_file()?);
+        }
+        
+        Ok(token.clone().unwrap_or_default())
+    }
+
+    /// Reads the token persisted by an earlier run, if any
+    fn read_token_file(&self) -> Result<CacheData> {
+        let cache_file = match &self.cache_file {
+            Some(cache_file) if cache_file.exists() => cache_file,
+            _ => return Ok(CacheData::default()),
+        };
+        
+        let mut file = File::open(cache_file)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        
+        match serde_json::from_str(&contents) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                // A truncated or corrupt cache is treated as empty so the next call refetches
+                warn!("Ignoring corrupt token cache file {}: {}", cache_file.display(), e);
+                Ok(CacheData::default())
+            }
+        }
+    }
+
+    /// Replaces the shared access token and persists it to the token file. The new token is
+    /// used even if it can't be written, it just won't survive a restart.
+    fn save_token(&self, data: &CacheData) {
+        *self.token.write().unwrap() = Some(data.clone());
+        
+        if let Some(cache_file) = &self.cache_file {
+            if let Err(e) = write_token_file(cache_file, data) {
+                warn!("Failed to write token cache file {}: {}", cache_file.display(), e);
+            }
+        }
+    }
+
+    /// Starts a GET request to Spotify carrying the web player's headers and the given credentials.
+    /// The user agent, app-platform and spotify-app-version are defaults on the shared client.
+    fn spotify_get(&self, url: &str, credentials: Credentials) -> reqwest::RequestBuilder {
+        let request = self.client.get(url)
+            .header(REFERER, WEB_PLAYER_ORIGIN)
+            .header(ORIGIN, WEB_PLAYER_ORIGIN)
+            .header(ACCEPT, "application/json");
+        
+        match credentials {
+            Credentials::SpDc(sp_dc) => request.header(COOKIE, format!("sp_dc={}", sp_dc)),
+            Credentials::Bearer(token) => request.header(AUTHORIZATION, format!("Bearer {}", token)),
+        }
+    }
+
+    /// Sends a request to Spotify, recording its duration under the given endpoint label
+    async fn send_timed(&self, endpoint: &str, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let started = Instant::now();
+        let response = if self.debug_upstream {
+            upstreamlog::send_logged(&self.client, endpoint, request).await
+        } else {
+            request.send().await
+        };
+        
+        METRICS.upstream_request_duration_seconds
+            .with_label_values(&[endpoint])
+            .observe(started.elapsed().as_secs_f64());
+        
+        Ok(response?)
+    }
+
+    /// Discards the shared access token and deletes the token file so the next request fetches a fresh one
+    fn clear_token_cache(&self) {
+        *self.token.write().unwrap() = Some(CacheData::default());
         
-        // Decode base32 secret
-        let secret = base32::decode(
-            Alphabet::RFC4648 { padding: false },
-            secret_base32,
-        ).unwrap_or_default();
+        let Some(cache_file) = &self.cache_file else {
+            return;
+        };
         
+        if cache_file.exists() {
+            if let Err(e) = std::fs::remove_file(cache_file) {
+                error!("Failed to remove token cache file: {}", e);
+            } else {
+                debug!("Removed token cache file to force refresh");
+            }
+        }
+    }
+
+    /// Index of the sp_dc token currently in use
+    fn current_sp_dc_index(&self) -> usize {
+        self.current_sp_dc.load(Ordering::SeqCst) % self.sp_dc_tokens.len()
+    }
+
+    /// Moves on to the token after `failed_index`, unless another request already rotated past it
+    fn rotate_sp_dc(&self, failed_index: usize) {
+        let next_index = (failed_index + 1) % self.sp_dc_tokens.len();
+        if self.current_sp_dc
+            .compare_exchange(failed_index, next_index, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            warn!("Rotating from sp_dc token #{} to #{}", failed_index + 1, next_index + 1);
+        }
+    }
+
+    /// Generates a 6-digit RFC 6238 Time-based One-Time Password (HMAC-SHA1, 30 second step)
+    /// from the raw secret bytes and a Unix time in seconds
+    fn generate_totp(secret: &[u8], time_seconds: u64) -> String {
         // Calculate the counter value (number of time steps since epoch)
         let time_step = 30; // seconds
-        let counter = server_time_seconds / time_step;
+        let counter = time_seconds / time_step;
         
         // Create a byte array for the counter (8 bytes, big-endian)
         let counter_bytes = counter.to_be_bytes();
         
         // Calculate HMAC-SHA1
-        let mut mac = Hmac::<Sha1>::new_from_slice(&secret)
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret)
             .expect("HMAC can take key of any size");
         mac.update(&counter_bytes);
         let result = mac.finalize().into_bytes();
@@ -152,20 +913,37 @@ impl Spotify {
         format!("{:06}", otp)
     }
 
-    /// Retrieves the server time and returns the parameters needed for the token request
-    async fn get_server_time_params(&self) -> Result<HashMap<String, String>> {
-        let client = reqwest::Client::new();
-        
-        let response = client.get(&self.server_time_url)
-            .header("referer", "https://open.spotify.com/")
-            .header("origin", "https://open.spotify.com/")
-            .header("accept", "application/json")
-            .header("app-platform", "WebPlayer")
-            .header("spotify-app-version", "1.2.61.20.g3b4cd5b2")
-            .header("user-agent", "Mozilla/5.0 (X11; Linux x86_64; rv:124.0) Gecko/20100101 Firefox/124.0")
-            .header("cookie", format!("sp_dc={}", self.sp_dc))
-            .send()
-            .await?;
+    /// Spotify's current time in seconds, which the TOTP is computed from. Transient failures are
+    /// retried; when every attempt fails the local clock is used instead, which is close enough
+    /// for a 30 second TOTP step unless it is badly off.
+    async fn server_time_seconds(&self, sp_dc: &str) -> u64 {
+        for attempt in 1..=self.server_time_attempts {
+            match self.fetch_server_time(sp_dc).await {
+                Ok(server_time_seconds) => return server_time_seconds,
+                Err(e) => {
+                    warn!("Failed to fetch server time (attempt {} of {}): {}", attempt, self.server_time_attempts, e);
+                    if attempt < self.server_time_attempts {
+                        tokio::time::sleep(SERVER_TIME_RETRY_DELAY * attempt).await;
+                    }
+                }
+            }
+        }
+        
+        if self.server_time_attempts > 0 {
+            warn!("Using the local clock for the TOTP since Spotify's server time is unavailable");
+        }
+        
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs()
+    }
+
+    /// Fetches the current time from Spotify's server time endpoint, in seconds
+    async fn fetch_server_time(&self, sp_dc: &str) -> Result<u64> {
+        let request = self.spotify_get(&self.server_time_url, Credentials::SpDc(sp_dc));
+        
+        let response = self.send_timed("server_time", request).await?;
             
         if !response.status().is_success() {
             return Err(SpotifyException::ApiError(format!(
@@ -176,11 +954,16 @@ impl Spotify {
         
         let server_time_data: serde_json::Value = response.json().await?;
         
-        let server_time_seconds = server_time_data["serverTime"]
+        server_time_data["serverTime"]
             .as_u64()
-            .ok_or_else(|| SpotifyException::new("Invalid server time response"))?;
-            
-        let totp = self.generate_totp(server_time_seconds);
+            .ok_or_else(|| SpotifyException::new("Invalid server time response"))
+    }
+
+    /// Retrieves the server time and returns the parameters needed for the token request
+    async fn get_server_time_params(&self, sp_dc: &str) -> Result<HashMap<String, String>> {
+        let server_time_seconds = self.server_time_seconds(sp_dc).await;
+        
+        let totp = Spotify::generate_totp(&self.totp_secret, server_time_seconds);
         let time_str = server_time_seconds.to_string();
         
         let mut params = HashMap::new();
@@ -188,50 +971,24 @@ impl Spotify {
         params.insert("productType".to_string(), "web-player".to_string());
         params.insert("totp".to_string(), totp.clone());
         params.insert("totpServer".to_string(), totp);
-        params.insert("totpVer".to_string(), "5".to_string());
+        params.insert("totpVer".to_string(), self.totp_version.to_string());
         params.insert("sTime".to_string(), time_str.clone());
         params.insert("cTime".to_string(), format!("{}420", time_str));
         
         Ok(params)
     }
 
-    /// Retrieves an access token from Spotify and stores it in a file
+    /// Retrieves an access token from Spotify and stores it in a file, rotating through
+    /// the configured sp_dc tokens when one is rejected
     pub async fn get_token(&self) -> Result<()> {
-        if self.sp_dc.is_empty() {
-            return Err(SpotifyException::new("Please set SP_DC as an environmental variable."));
+        if self.sp_dc_tokens.iter().all(|t| t.is_empty()) {
+            return Err(SpotifyException::AuthError("Please set SP_DC as an environmental variable.".to_string()));
         }
         
-        let params = self.get_server_time_params().await?;
-        let client = reqwest::Client::new();
+        let token_json = self.request_token_rotating().await?;
+        METRICS.token_refreshes_total.inc();
         
-        let url = format!("{}?{}", self.token_url, serde_urlencoded::to_string(&params)?);
-        
-        let response = client.get(&url)
-            .header("referer", "https://open.spotify.com/")
-            .header("origin", "https://open.spotify.com/")
-            .header("accept", "application/json")
-            .header("app-platform", "WebPlayer")
-            .header("spotify-app-version", "1.2.61.20.g3b4cd5b2")
-            .header("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:124.0) Gecko/20100101 Firefox/124.0")
-            .header("Cookie", format!("sp_dc={}", self.sp_dc))
-            .send()
-            .await?;
-            
-        if !response.status().is_success() {
-            return Err(SpotifyException::ApiError(format!(
-                "Token request failed: HTTP status {}", 
-                response.status()
-            )));
-        }
-        
-        let token_json: serde_json::Value = response.json().await?;
-        
-        // Check if token is anonymous (invalid sp_dc)
-        if token_json.get("isAnonymous").map_or(false, |v| v.as_bool().unwrap_or(false)) {
-            return Err(SpotifyException::new("The SP_DC set seems to be invalid, please correct it!"));
-        }
-        
-        let mut cache_data = self.load_cache_file()?;
+        let mut cache_data = self.load_token()?;
         
         cache_data.access_token = token_json["accessToken"].as_str().map(String::from);
         cache_data.access_token_expiration_timestamp_ms = token_json["accessTokenExpirationTimestampMs"].as_u64();
@@ -241,96 +998,402 @@ impl Spotify {
             cache_data.client_id = Some(client_id.to_string());
         }
         
-        self.save_cache_file(&cache_data)?;
+        self.save_token(&cache_data);
         
         Ok(())
     }
 
-    /// Checks if the access token and client token are expired and retrieves new ones if needed
-    async fn check_tokens_expire(&self) -> Result<()> {
-        let cache_exists = self.cache_file.exists();
+    /// Requests a token with the current sp_dc, moving to the next one on 401 or an anonymous
+    /// token until every configured token has been tried once
+    async fn request_token_rotating(&self) -> Result<serde_json::Value> {
+        let token_count = self.sp_dc_tokens.len();
         
-        let cache_data = if cache_exists {
-            self.load_cache_file()?
-        } else {
-            debug!("No token cache file found, creating new one");
-            CacheData {
-                access_token: None,
-                client_id: None,
-                access_token_expiration_timestamp_ms: None,
+        for attempt in 1..=token_count {
+            let index = self.current_sp_dc_index();
+            let sp_dc = &self.sp_dc_tokens[index];
+            
+            let params = self.get_server_time_params(sp_dc).await?;
+            
+            let url = format!("{}?{}", self.token_url, serde_urlencoded::to_string(&params)?);
+            
+            let request = self.spotify_get(&url, Credentials::SpDc(sp_dc));
+            
+            let response = self.send_timed("token", request).await?;
+            
+            let status = response.status();
+            let has_spare_token = attempt < token_count;
+            
+            if status.as_u16() == 401 && has_spare_token {
+                warn!("sp_dc token #{} was rejected with 401", index + 1);
+                self.rotate_sp_dc(index);
+                continue;
             }
-        };
+            
+            if status.as_u16() == 401 {
+                return Err(SpotifyException::AuthError(format!(
+                    "Token request failed: HTTP status {}", 
+                    status
+                )));
+            }
+            
+            if !status.is_success() {
+                return Err(SpotifyException::ApiError(format!(
+                    "Token request failed: HTTP status {}", 
+                    status
+                )));
+            }
+            
+            let token_json: serde_json::Value = response.json().await?;
+            
+            // Check if token is anonymous (invalid sp_dc)
+            if token_json.get("isAnonymous").is_some_and(|v| v.as_bool().unwrap_or(false)) {
+                if has_spare_token {
+                    warn!("sp_dc token #{} returned an anonymous token", index + 1);
+                    self.rotate_sp_dc(index);
+                    continue;
+                }
+                
+                if !self.allow_anonymous {
+                    return Err(SpotifyException::AuthError("The SP_DC set seems to be invalid, please correct it!".to_string()));
+                }
+                
+                warn!("Using an anonymous access token since allow_anonymous is set; most lyrics requests will fail");
+            }
+            
+            return Ok(token_json);
+        }
+        
+        Err(SpotifyException::AuthError("Please set SP_DC as an environmental variable.".to_string()))
+    }
+
+    /// Checks if the access token and client token are expired and retrieves new ones if needed.
+    /// Concurrent callers share a single refresh: one fetches the token while the others wait for it.
+    async fn check_tokens_expire(&self) -> Result<()> {
+        if !self.access_token_needs_refresh()? {
+            return Ok(());
+        }
+        
+        let _refresh_guard = self.token_refresh_lock.lock().await;
+        
+        // Another request may have refreshed the token while we were waiting for the lock
+        if self.access_token_needs_refresh()? {
+            info!("Access token expired or not found, retrieving new token");
+            self.get_token().await?;
+        }
+        
+        Ok(())
+    }
+
+    /// Returns whether the cached access token is missing or about to expire
+    fn access_token_needs_refresh(&self) -> Result<bool> {
+        let cache_data = self.load_token()?;
         
         let current_time_ms = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis() as u64;
             
-        let need_access_token = !cache_exists 
-            || cache_data.access_token.is_none() 
+        let need_access_token = cache_data.access_token.is_none() 
             || cache_data.access_token_expiration_timestamp_ms.is_none()
-            || cache_data.access_token_expiration_timestamp_ms.unwrap() < current_time_ms;
+            || cache_data.access_token_expiration_timestamp_ms.unwrap() < current_time_ms + self.token_expiry_margin.as_millis() as u64;
             
-        if need_access_token {
-            info!("Access token expired or not found, retrieving new token");
-            self.get_token().await?;
-        } else {
+        if !need_access_token {
             debug!("Using cached access token (valid until {})", 
                    cache_data.access_token_expiration_timestamp_ms.unwrap_or(0));
         }
         
-        Ok(())
+        Ok(need_access_token)
+    }
+
+    /// Discards the cached access token and fetches a new one, returning its expiry timestamp in ms
+    pub async fn force_refresh_token(&self) -> Result<u64> {
+        let _refresh_guard = self.token_refresh_lock.lock().await;
+        
+        info!("Forcing access token refresh");
+        self.clear_token_cache();
+        self.get_token().await?;
+        
+        let cache_data = self.load_token()?;
+        Ok(cache_data.access_token_expiration_timestamp_ms.unwrap_or(0))
+    }
+
+    /// Returns a valid access token, refreshing it first if it has expired
+    async fn get_access_token(&self) -> Result<String> {
+        self.check_tokens_expire().await?;
+        
+        let cache_data = self.load_token()?;
+        cache_data.access_token.ok_or_else(|| SpotifyException::new("Access token not found"))
+    }
+
+    /// Searches Spotify for tracks matching the query, returning at most `limit` results
+    pub async fn search_tracks(&self, query: &str, limit: u32) -> Result<Vec<SearchTrack>> {
+        let token = self.get_access_token().await?;
+        
+        let request = self.spotify_get(&self.search_url, Credentials::Bearer(&token))
+            .query(&[("q", query), ("type", "track"), ("limit", &limit.to_string())]);
+        
+        let response = self.send_timed("search", request).await?;
+            
+        if !response.status().is_success() {
+            return Err(SpotifyException::ApiError(format!(
+                "Search request failed: HTTP status {}", 
+                response.status()
+            )));
+        }
+        
+        let search_json: serde_json::Value = response.json().await?;
+        
+        let mut tracks = Vec::new();
+        
+        if let Some(items) = search_json["tracks"]["items"].as_array() {
+            for item in items {
+                let artist = item["artists"]
+                    .as_array()
+                    .map(|artists| {
+                        artists.iter()
+                            .filter_map(|a| a["name"].as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .unwrap_or_default();
+                
+                tracks.push(SearchTrack {
+                    id: item["id"].as_str().unwrap_or("").to_string(),
+                    name: item["name"].as_str().unwrap_or("").to_string(),
+                    artist,
+                    album: item["album"]["name"].as_str().unwrap_or("").to_string(),
+                });
+            }
+        }
+        
+        Ok(tracks)
+    }
+
+    /// Looks up a track's title, artists, album and duration, cached like formatted lyrics
+    pub async fn get_track_metadata(&self, track_id: &str) -> Result<TrackMetadata> {
+        let cached = self.metadata_cache.as_ref().and_then(|cache| cache.lock().unwrap().get(track_id));
+        if let Some(metadata) = cached.and_then(|cached| serde_json::from_value(cached).ok()) {
+            debug!("Serving metadata for track {} from cache", loggable(track_id));
+            return Ok(metadata);
+        }
+        
+        let token = self.get_access_token().await?;
+        let url = format!("{}{}", self.tracks_url, track_id);
+        
+        let response = self.send_timed("track", self.spotify_get(&url, Credentials::Bearer(&token))).await?;
+        
+        let status = response.status();
+        
+        // Spotify answers 400 for malformed IDs and 404 for unknown ones
+        if status.as_u16() == 400 || status.as_u16() == 404 {
+            return Err(SpotifyException::TrackNotFound("track not found on spotify!".to_string()));
+        }
+        
+        if !status.is_success() {
+            return Err(SpotifyException::ApiError(format!(
+                "Track request failed: HTTP status {}", 
+                status
+            )));
+        }
+        
+        let track_json: serde_json::Value = response.json().await?;
+        
+        let metadata = TrackMetadata {
+            name: track_json["name"].as_str().unwrap_or("").to_string(),
+            artists: track_json["artists"]
+                .as_array()
+                .map(|artists| {
+                    artists.iter()
+                        .filter_map(|a| a["name"].as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            album: track_json["album"]["name"].as_str().unwrap_or("").to_string(),
+            duration_ms: track_json["duration_ms"].as_u64().unwrap_or(0),
+            cover_url: medium_image_url(&track_json["album"]["images"]),
+        };
+        
+        if let Some(cache) = &self.metadata_cache {
+            cache.lock().unwrap().insert(track_id.to_string(), serde_json::to_value(&metadata)?);
+        }
+        
+        Ok(metadata)
+    }
+
+    /// Lists the track IDs of an album in disc and track order, following Spotify's pagination
+    pub async fn get_album_track_ids(&self, album_id: &str) -> Result<Vec<String>> {
+        let token = self.get_access_token().await?;
+        
+        let mut track_ids = Vec::new();
+        let mut next_url = Some(format!("{}{}/tracks?limit=50", self.album_url, album_id));
+        
+        while let Some(url) = next_url {
+            let request = self.spotify_get(&url, Credentials::Bearer(&token));
+            
+            let response = self.send_timed("album", request).await?;
+            
+            // Spotify answers 400 for malformed IDs and 404 for unknown ones
+            if response.status() == reqwest::StatusCode::NOT_FOUND || response.status() == reqwest::StatusCode::BAD_REQUEST {
+                return Err(SpotifyException::AlbumNotFound("album not found on spotify!".to_string()));
+            }
+            
+            if !response.status().is_success() {
+                return Err(SpotifyException::ApiError(format!(
+                    "Album request failed: HTTP status {}",
+                    response.status()
+                )));
+            }
+            
+            let page: serde_json::Value = response.json().await?;
+            
+            if let Some(items) = page["items"].as_array() {
+                track_ids.extend(items.iter().filter_map(|item| item["id"].as_str()).map(String::from));
+            }
+            
+            next_url = page["next"].as_str().map(String::from);
+        }
+        
+        Ok(track_ids)
     }
 
-    /// Retrieves the lyrics of a track from Spotify
-    pub async fn get_lyrics(&self, track_id: &str) -> Result<String> {
-        // Try up to 2 times in case token needs to be refreshed
-        for attempt in 1..=2 {
-            self.check_tokens_expire().await?;
+    /// Lists the track IDs of a playlist in playlist order, following Spotify's pagination.
+    /// Local files, episodes and removed tracks are skipped. Paging stops once more than
+    /// `max_tracks` IDs were found, so the caller can tell the playlist was longer.
+    pub async fn get_playlist_track_ids(&self, playlist_id: &str, max_tracks: usize) -> Result<Vec<String>> {
+        let token = self.get_access_token().await?;
+        
+        let mut track_ids = Vec::new();
+        let mut next_url = Some(format!(
+            "{}{}/tracks?limit=100&fields=next,items(is_local,track(id,type))",
+            self.playlist_url, playlist_id
+        ));
+        
+        while let Some(url) = next_url {
+            let request = self.spotify_get(&url, Credentials::Bearer(&token));
+            
+            let response = self.send_timed("playlist", request).await?;
             
-            let cache_data = self.load_cache_file()?;
-            let token = cache_data.access_token.ok_or_else(|| SpotifyException::new("Access token not found"))?;
+            // Spotify answers 400 for malformed IDs and 404 for unknown or private ones
+            if response.status() == reqwest::StatusCode::NOT_FOUND || response.status() == reqwest::StatusCode::BAD_REQUEST {
+                return Err(SpotifyException::PlaylistNotFound("playlist not found on spotify!".to_string()));
+            }
+            
+            if !response.status().is_success() {
+                return Err(SpotifyException::ApiError(format!(
+                    "Playlist request failed: HTTP status {}",
+                    response.status()
+                )));
+            }
+            
+            let page: serde_json::Value = response.json().await?;
+            
+            if let Some(items) = page["items"].as_array() {
+                track_ids.extend(
+                    items.iter()
+                        .filter(|item| !item["is_local"].as_bool().unwrap_or(false) && item["track"]["type"] == "track")
+                        .filter_map(|item| item["track"]["id"].as_str())
+                        .map(String::from)
+                );
+            }
+            
+            if track_ids.len() > max_tracks {
+                break;
+            }
+            
+            next_url = page["next"].as_str().map(String::from);
+        }
+        
+        Ok(track_ids)
+    }
+
+    /// Retrieves the lyrics of a track from Spotify for the given market (`from_token` uses the account's market)
+    pub async fn get_lyrics(&self, track_id: &str, vocal_removal: bool, market: &str) -> Result<String> {
+        // While Spotify is rate limiting us, more requests would only extend the ban
+        self.check_rate_limit()?;
+        
+        // Allow one forced token refresh on 401, one rotation per spare sp_dc token on 429,
+        // and then backoff retries for 5xx according to the retry policy
+        let mut refreshed = false;
+        let mut rotations = 0;
+        let mut retries = 0;
+        let mut attempt = 0;
+        
+        loop {
+            attempt += 1;
+            let token = self.get_access_token().await?;
             
             let formatted_url = format!(
-                "{}{}?format=json&vocalRemoval=false&market=from_token", 
+                "{}{}?format=json&vocalRemoval={}&market={}", 
                 self.lyrics_url, 
-                track_id
+                track_id,
+                vocal_removal,
+                market
             );
             
-            debug!("Requesting lyrics for track {} (attempt {})", track_id, attempt);
+            debug!("Requesting lyrics for track {} (attempt {})", loggable(track_id), attempt);
+            
+            let request = self.spotify_get(&formatted_url, Credentials::Bearer(&token));
             
-            let client = reqwest::Client::new();
-            let response = client.get(&formatted_url)
-                .header("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:124.0) Gecko/20100101 Firefox/124.0")
-                .header("referer", "https://open.spotify.com/")
-                .header("origin", "https://open.spotify.com/")
-                .header("accept", "application/json")
-                .header("app-platform", "WebPlayer")
-                .header("spotify-app-version", "1.2.61.20.g3b4cd5b2")
-                .header("authorization", format!("Bearer {}", token))
-                .send()
-                .await?;
+            let response = self.send_timed("lyrics", request).await?;
             
             let status = response.status();
             
             if status.is_success() {
+                let content_type = response.headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
                 let result = response.text().await?;
+                
+                // During outages Spotify sometimes serves an HTML error page with a 200 status
+                if is_html_body(content_type.as_deref(), &result) {
+                    return Err(SpotifyException::ApiError("unexpected non-JSON response from Spotify".to_string()));
+                }
+                
                 return Ok(result);
-            } else if status.as_u16() == 401 && attempt == 1 {
-                // If we get a 401 on the first attempt, force token refresh
+            } else if status.as_u16() == 404 {
+                // Spotify answers 404 both for unknown tracks and for tracks without lyrics
+                return Err(SpotifyException::NotFound("lyrics for this track is not available on spotify!".to_string()));
+            } else if status.as_u16() == 401 && !refreshed {
+                // If we get a 401 for the first time, force token refresh
                 error!("Received 401 Unauthorized, forcing token refresh");
+                refreshed = true;
                 
                 // Delete the token file to force a complete refresh
-                if self.cache_file.exists() {
-                    if let Err(e) = std::fs::remove_file(&self.cache_file) {
-                        error!("Failed to remove token cache file: {}", e);
-                    } else {
-                        debug!("Removed token cache file to force refresh");
-                    }
-                }
+                self.clear_token_cache();
                 
                 // Continue to the next attempt
                 continue;
+            } else if status.as_u16() == 401 {
+                // A freshly fetched token was rejected too, so the sp_dc itself is the problem
+                return Err(SpotifyException::AuthError("Spotify rejected a freshly fetched access token".to_string()));
+            } else if status.as_u16() == 429 && rotations + 1 < self.sp_dc_tokens.len() {
+                // The current sp_dc is being rate limited, switch to another one
+                warn!("Received 429 Too Many Requests, switching sp_dc token");
+                rotations += 1;
+                
+                self.rotate_sp_dc(self.current_sp_dc_index());
+                self.clear_token_cache();
+                
+                continue;
+            } else if status.as_u16() == 429 {
+                // Every sp_dc is rate limited, so hold back all lyrics requests rather than retry each one
+                let window = retry::parse_retry_after(response.headers()).unwrap_or(DEFAULT_RATE_LIMIT_WINDOW);
+                warn!("Received 429 Too Many Requests, pausing lyrics requests for {}s", window.as_secs());
+                *self.rate_limited_until.lock().unwrap() = Some(Instant::now() + window);
+                
+                return Err(SpotifyException::RateLimited(window));
+            } else if let Some(delay) = self.retry_policy.next_delay(status, retries, retry::parse_retry_after(response.headers())) {
+                retries += 1;
+                warn!(
+                    "Lyrics request returned HTTP status {}, retrying in {}ms (retry {})",
+                    status.as_u16(),
+                    delay.as_millis(),
+                    retries
+                );
+                
+                tokio::time::sleep(delay).await;
+                continue;
             } else {
                 return Err(SpotifyException::ApiError(format!(
                     "Lyrics request failed: HTTP status {} {}", 
@@ -339,109 +1402,1708 @@ impl Spotify {
                 )));
             }
         }
+    }
+
+    /// Fails with the time left when a recent 429 paused lyrics requests, clearing the pause once it has passed
+    fn check_rate_limit(&self) -> Result<()> {
+        let mut rate_limited_until = self.rate_limited_until.lock().unwrap();
+        
+        match *rate_limited_until {
+            Some(until) if until > Instant::now() => Err(SpotifyException::RateLimited(until - Instant::now())),
+            Some(_) => {
+                info!("Rate limit window passed, resuming lyrics requests");
+                *rate_limited_until = None;
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Extract the track or podcast episode from an open.spotify.com link or a `spotify:` URI.
+    /// The error says what was wrong with the link, e.g. that it points to an album.
+    pub fn extract_item_result(url: &str) -> Result<SpotifyItem> {
+        let invalid = |message: &str| SpotifyException::InvalidUrl(message.to_string());
+        
+        let parsed = reqwest::Url::parse(url.trim())
+            .map_err(|_| invalid("url parameter is not a valid URL!"))?;
+        
+        // Web links are /{kind}/{id}, optionally behind a locale segment like /intl-de/
+        let (kind, id) = match parsed.scheme() {
+            "spotify" => {
+                let mut parts = parsed.path().split(':');
+                (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+            },
+            "http" | "https" if matches!(parsed.host_str(), Some("open.spotify.com" | "play.spotify.com")) => {
+                let mut segments = parsed.path_segments()
+                    .into_iter()
+                    .flatten()
+                    .skip_while(|segment| segment.starts_with("intl-"));
+                (segments.next().unwrap_or(""), segments.next().unwrap_or(""))
+            },
+            _ => return Err(invalid("url parameter is not a Spotify link!")),
+        };
+        
+        let valid_id = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+        
+        match kind {
+            "track" if valid_id => Ok(SpotifyItem::Track(id.to_string())),
+            "episode" if valid_id => Ok(SpotifyItem::Episode(id.to_string())),
+            "track" | "episode" => Err(invalid(&format!("url parameter has a malformed {} ID!", kind))),
+            "album" | "artist" => Err(invalid(&format!("url parameter is an {} link, not a track!", kind))),
+            "playlist" | "show" | "user" => Err(invalid(&format!("url parameter is a {} link, not a track!", kind))),
+            _ => Err(invalid("url parameter is not a Spotify track or episode link!")),
+        }
+    }
+
+    /// Resolves a short share link (e.g. https://spotify.link/xyz) by following its redirects
+    /// and extracting the track or episode from the final URL. Only Spotify's share hosts are
+    /// followed, so the server can't be used to fetch arbitrary URLs; other links give Ok(None).
+    pub async fn resolve_share_url(&self, url: &str) -> Result<Option<SpotifyItem>> {
+        let is_share_host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|parsed| parsed.host_str().map(|host| SHARE_URL_HOSTS.contains(&host)))
+            .unwrap_or(false);
+        
+        if !is_share_host {
+            return Ok(None);
+        }
+        
+        let response = self.send_timed("share_url", self.client.get(url)).await?;
+        let final_url = response.url().to_string();
+        debug!("Share URL {} resolved to {}", loggable(url), loggable(&final_url));
+        
+        Spotify::extract_item_result(&final_url).map(Some)
+    }
+
+    /// Get lyrics in the specified format (id3, lrc, elrc, both, srt, vtt, ttml, json-lines, text or raw), adjusted by `opts`.
+    /// The format name is case-insensitive. With a `page`, only that window of lines is returned.
+    pub async fn get_formatted_lyrics(&self, track_id: &str, format: &str, vocal_removal: bool, market: &str, opts: FormatOptions<'_>, page: Option<LinePage>) -> Result<FormattedLyrics> {
+        let format = format.to_ascii_lowercase();
+        let format = format.as_str();
+        let cache_key = format!("{}:{}:{}:{}:{}:{}:{}", track_id, format, opts.offset_ms, vocal_removal, market, opts.lang.unwrap_or(""), opts.skip_empty);
+
+        if let Some(cached) = self.get_cached_lyrics(&cache_key) {
+            debug!("Serving lyrics for track {} ({}) from cache", loggable(track_id), format);
+            METRICS.lyrics_cache_hits_total.inc();
+            return Ok(FormattedLyrics::paged(cached, true, page));
+        }
+
+        // Debug passthrough of Spotify's response, never cached so it always reflects upstream.
+        // Single tracks are served verbatim from `get_lyrics`; this parsed copy is for track lists.
+        if format == "raw" {
+            let raw_lyrics = self.get_lyrics(track_id, vocal_removal, market).await?;
+            return Ok(FormattedLyrics {
+                body: serde_json::from_str(&raw_lyrics)?,
+                cache_hit: false,
+            });
+        }
+        
+        // Missing lyrics don't depend on the format, only on what is asked of Spotify
+        let missing_key = format!("{}:{}:{}", track_id, vocal_removal, market);
+        let lyrics_data = self.get_lyrics_data(track_id, vocal_removal, market, &missing_key).await;
+        
+        // Check if lyrics exist
+        let lyrics_data = lyrics_data.and_then(|lyrics_data| {
+            if lyrics_data.get("lyrics").is_none() {
+                let message = "lyrics for this track is not available on spotify!".to_string();
+                self.cache_missing_lyrics(missing_key, &message);
+                return Err(SpotifyException::NotFound(message));
+            }
+            check_lyrics_shape(&lyrics_data)?;
+            Ok(lyrics_data)
+        });
+        
+        // LRCLIB may have lyrics for tracks Spotify doesn't
+        let (lyrics_data, source) = match lyrics_data {
+            Err(SpotifyException::NotFound(message)) if self.lrclib_url.is_some() => {
+                match self.get_lrclib_lyrics(track_id).await {
+                    Some(lyrics_data) => (lyrics_data, Some("lrclib")),
+                    None => return Err(SpotifyException::NotFound(message)),
+                }
+            },
+            lyrics_data => (lyrics_data?, None),
+        };
         
-        Err(SpotifyException::ApiError("Failed to retrieve lyrics after token refresh".to_string()))
+        let mut formatted = format_lyrics(&lyrics_data, format, FormatOptions { item_type: "track", ..opts })?;
+        if let (Some(source), Some(body)) = (source, formatted.as_object_mut()) {
+            body.insert("source".to_string(), json!(source));
+        }
+
+        self.cache_lyrics(cache_key, &formatted);
+
+        Ok(FormattedLyrics::paged(formatted, false, page))
     }
 
-    /// Extract track ID from a Spotify URL
-    pub fn extract_track_id(url: &str) -> Option<String> {
-        let parts: Vec<&str> = url.split('/').collect();
-        if parts.len() > 4 && parts[3] == "track" {
-            let track_with_params: Vec<&str> = parts[4].split('?').collect();
-            return Some(track_with_params[0].to_string());
+    /// Formats lyrics JSON supplied by a client, in the shape of Spotify's color-lyrics response,
+    /// the same way fetched lyrics are. Nothing is fetched or cached.
+    pub fn convert_lyrics(&self, lyrics_data: serde_json::Value, format: &str, opts: FormatOptions, page: Option<LinePage>) -> Result<serde_json::Value> {
+        let format = format.to_ascii_lowercase();
+        
+        let line_count = match lyrics_data["lyrics"]["lines"].as_array() {
+            Some(lines) => lines.len(),
+            None => return Err(SpotifyException::new("body has no lyrics.lines array")),
+        };
+        
+        if line_count > self.max_lyric_lines {
+            return Err(SpotifyException::new(format!(
+                "lyrics have {} lines, more than the limit of {}",
+                line_count, self.max_lyric_lines
+            )));
         }
-        None
+        
+        let formatted = format_lyrics(&lyrics_data, &format, FormatOptions { item_type: "track", ..opts })?;
+        
+        Ok(FormattedLyrics::paged(formatted, false, page).body)
     }
 
-    /// Get lyrics in the specified format (id3 or lrc)
-    pub async fn get_formatted_lyrics(&self, track_id: &str, format: &str) -> Result<serde_json::Value> {
-        let raw_lyrics = self.get_lyrics(track_id).await?;
+    /// Fetches and parses a track's lyrics from Spotify, answering from the missing-lyrics cache
+    /// when the track recently had none
+    async fn get_lyrics_data(&self, track_id: &str, vocal_removal: bool, market: &str, missing_key: &str) -> Result<serde_json::Value> {
+        if let Some(message) = self.get_missing_lyrics(missing_key) {
+            debug!("Lyrics for track {} are known to be missing", loggable(track_id));
+            return Err(SpotifyException::NotFound(message));
+        }
+
+        METRICS.lyrics_cache_misses_total.inc();
+        
+        let raw_lyrics = match self.get_lyrics(track_id, vocal_removal, market).await {
+            Err(SpotifyException::NotFound(message)) => {
+                self.cache_missing_lyrics(missing_key.to_string(), &message);
+                return Err(SpotifyException::NotFound(message));
+            },
+            result => result?,
+        };
         
         // Parse the JSON response
         let lyrics_data: serde_json::Value = serde_json::from_str(&raw_lyrics)?;
         
-        // Check if lyrics exist
-        if !lyrics_data.get("lyrics").is_some() {
-            return Err(SpotifyException::new("lyrics for this track is not available on spotify!"));
+        // Guard against runaway payloads before building per-line output from them
+        let line_count = lyrics_data["lyrics"]["lines"].as_array().map_or(0, Vec::len);
+        if line_count > self.max_lyric_lines {
+            return Err(SpotifyException::InvalidResponse(format!(
+                "lyrics for track {} have {} lines, more than the limit of {}",
+                loggable(track_id), line_count, self.max_lyric_lines
+            )));
         }
         
-        // Determine sync type
-        let sync_type = if lyrics_data["lyrics"]["syncType"] == "LINE_SYNCED" {
-            "LINE_SYNCED"
-        } else {
-            "UNSYNCED"
+        Ok(lyrics_data)
+    }
+
+    /// Looks the track up on LRCLIB by its title, artist, album and duration, returning the lyrics
+    /// in the shape of Spotify's lyrics JSON. Any failure just means there is nothing to fall back on.
+    async fn get_lrclib_lyrics(&self, track_id: &str) -> Option<serde_json::Value> {
+        let lrclib_url = self.lrclib_url.as_deref()?;
+        
+        let metadata = match self.get_track_metadata(track_id).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("Skipping LRCLIB fallback for track {}: {}", loggable(track_id), e);
+                return None;
+            }
         };
         
-        // Format the lyrics based on the requested format
-        if format == "lrc" {
-            let mut lines = Vec::new();
-            
-            if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
-                for line in lyrics_lines {
-                    let start_time_ms = line["startTimeMs"].as_str().unwrap_or("0").to_string();
-                    let time_tag = self.format_ms(start_time_ms.parse::<u64>().unwrap_or(0));
-                    
-                    let lrc_line = LrcLine {
-                        time_tag,
-                        words: line["words"].as_str().unwrap_or("").to_string(),
-                    };
-                    
-                    lines.push(lrc_line);
-                }
+        // LRCLIB matches on the primary artist and the duration in whole seconds
+        let duration_secs = ((metadata.duration_ms + 500) / 1000).to_string();
+        let request = self.client.get(lrclib_url)
+            .header(USER_AGENT, LRCLIB_USER_AGENT)
+            .query(&[
+                ("track_name", metadata.name.as_str()),
+                ("artist_name", metadata.artists.first().map_or("", String::as_str)),
+                ("album_name", metadata.album.as_str()),
+                ("duration", duration_secs.as_str()),
+            ]);
+        
+        let response = match self.send_timed("lrclib", request).await {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                debug!("LRCLIB has no lyrics for track {} (HTTP status {})", loggable(track_id), response.status().as_u16());
+                return None;
+            },
+            Err(e) => {
+                warn!("LRCLIB request for track {} failed: {}", loggable(track_id), e);
+                return None;
             }
-            
-            let response = LrcResponse {
-                error: false,
-                sync_type: sync_type.to_string(),
-                lines,
-            };
-            
-            Ok(serde_json::to_value(response)?)
-        } else {
-            // Default format is id3
-            let mut lines = Vec::new();
-            
-            if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
-                for line in lyrics_lines {
-                    let id3_line = LyricLine {
-                        start_time_ms: line["startTimeMs"].as_str().unwrap_or("0").to_string(),
-                        words: line["words"].as_str().unwrap_or("").to_string(),
-                        syllables: Vec::new(), // Spotify doesn't provide syllables
-                        end_time_ms: "0".to_string(), // Spotify doesn't provide end time
-                    };
-                    
-                    lines.push(id3_line);
-                }
+        };
+        
+        let lrclib: serde_json::Value = match response.json().await {
+            Ok(lrclib) => lrclib,
+            Err(e) => {
+                warn!("LRCLIB returned an invalid response for track {}: {}", loggable(track_id), e);
+                return None;
             }
-            
-            let response = Id3Response {
-                error: false,
-                sync_type: sync_type.to_string(),
-                lines,
-            };
-            
-            Ok(serde_json::to_value(response)?)
+        };
+        
+        let lyrics_data = lrclib_to_lyrics(&lrclib);
+        let line_count = lyrics_data["lyrics"]["lines"].as_array().map_or(0, Vec::len);
+        if line_count > self.max_lyric_lines {
+            warn!("Ignoring LRCLIB lyrics for track {} with {} lines", loggable(track_id), line_count);
+            return None;
+        }
+        
+        info!("Serving LRCLIB lyrics for track {}", loggable(track_id));
+        Some(lyrics_data)
+    }
+
+    /// Get a podcast episode's transcript in the same formats as track lyrics. Transcripts have no
+    /// translations, so `opts.lang` is ignored.
+    pub async fn get_formatted_transcript(&self, episode_id: &str, format: &str, opts: FormatOptions<'_>, page: Option<LinePage>) -> Result<FormattedLyrics> {
+        let format = format.to_ascii_lowercase();
+        let format = format.as_str();
+        let cache_key = format!("episode:{}:{}:{}:{}", episode_id, format, opts.offset_ms, opts.skip_empty);
+
+        if let Some(cached) = self.get_cached_lyrics(&cache_key) {
+            debug!("Serving transcript for episode {} ({}) from cache", loggable(episode_id), format);
+            METRICS.lyrics_cache_hits_total.inc();
+            return Ok(FormattedLyrics::paged(cached, true, page));
+        }
+
+        METRICS.lyrics_cache_misses_total.inc();
+        
+        let transcript = self.get_transcript(episode_id).await?;
+        
+        if format == "raw" {
+            return Ok(FormattedLyrics {
+                body: transcript,
+                cache_hit: false,
+            });
+        }
+        
+        let lyrics_data = transcript_to_lyrics(&transcript);
+        
+        let line_count = lyrics_data["lyrics"]["lines"].as_array().map_or(0, Vec::len);
+        if line_count > self.max_lyric_lines {
+            return Err(SpotifyException::InvalidResponse(format!(
+                "transcript for episode {} has {} lines, more than the limit of {}",
+                loggable(episode_id), line_count, self.max_lyric_lines
+            )));
+        }
+        
+        let formatted = format_lyrics(&lyrics_data, format, FormatOptions { lang: None, item_type: "episode", ..opts })?;
+
+        self.cache_lyrics(cache_key, &formatted);
+
+        Ok(FormattedLyrics::paged(formatted, false, page))
+    }
+
+    /// Retrieves an episode's transcript from Spotify's read-along endpoint
+    async fn get_transcript(&self, episode_id: &str) -> Result<serde_json::Value> {
+        let token = self.get_access_token().await?;
+        let url = format!("{}{}?format=json", self.transcript_url, episode_id);
+        
+        let response = self.send_timed("transcript", self.spotify_get(&url, Credentials::Bearer(&token))).await?;
+        
+        let status = response.status();
+        
+        // Episodes without a transcript are answered with 404, like tracks without lyrics
+        if status.as_u16() == 404 {
+            return Err(SpotifyException::TranscriptNotFound("transcript for this episode is not available on spotify!".to_string()));
+        }
+        
+        if !status.is_success() {
+            return Err(SpotifyException::ApiError(format!(
+                "Transcript request failed: HTTP status {}", 
+                status
+            )));
+        }
+        
+        Ok(response.json().await?)
+    }
+
+    /// Expiry of the cached access token in milliseconds since the epoch, if one has been fetched
+    pub fn token_expiration_ms(&self) -> Option<u64> {
+        self.load_token().ok()?.access_token_expiration_timestamp_ms
+    }
+
+    /// Checks whether lyrics can actually be served: Spotify is reachable, an access token can be
+    /// had and the on-disk caches are writable. Checks run concurrently and each gives up after
+    /// `HEALTH_CHECK_TIMEOUT`, counting as failed, so a hanging dependency can't stall the probe.
+    pub async fn health_check(&self) -> HealthReport {
+        let upstream_reachable = async {
+            let request = self.client.get(&self.server_time_url).timeout(HEALTH_CHECK_TIMEOUT);
+            matches!(request.send().await, Ok(response) if response.status().is_success())
+        };
+        
+        let token_valid = async {
+            matches!(tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.get_access_token()).await, Ok(Ok(_)))
+        };
+        
+        let (upstream_reachable, token_valid) = tokio::join!(upstream_reachable, token_valid);
+        
+        HealthReport {
+            upstream_reachable,
+            token_valid,
+            cache_writable: self.cache_writable(),
         }
     }
 
-    /// Helper function for getLrcLyrics to change milliseconds to [mm:ss.xx]
-    fn format_ms(&self, milliseconds: u64) -> String {
-        let total_seconds = milliseconds / 1000;
-        let minutes = total_seconds / 60;
-        let seconds = total_seconds % 60;
-        let centiseconds = (milliseconds % 1000) / 10;
+    /// Whether the token file and disk cache directories accept writes
+    fn cache_writable(&self) -> Option<bool> {
+        let mut dirs = Vec::new();
+        if let Some(cache_file) = &self.cache_file {
+            dirs.extend(cache_file.parent());
+        }
+        if let Some(disk_cache) = &self.disk_cache {
+            dirs.push(disk_cache.dir());
+        }
+        
+        if dirs.is_empty() {
+            return None;
+        }
         
-        format!("{:02}:{:02}.{:02}", minutes, seconds, centiseconds)
+        Some(dirs.into_iter().all(is_dir_writable))
     }
 
-    /// Helper function to format milliseconds to SRT time format (hh:mm:ss,ms)
-    #[allow(dead_code)]
-    fn format_srt(&self, milliseconds: u64) -> String {
-        let hours = milliseconds / 3600000;
-        let minutes = (milliseconds % 3600000) / 60000;
-        let seconds = (milliseconds % 60000) / 1000;
-        let ms = milliseconds % 1000;
+    /// Number of entries in the in-memory lyrics cache
+    pub fn lyrics_cache_len(&self) -> usize {
+        self.lyrics_cache.as_ref().map_or(0, |cache| cache.lock().unwrap().len())
+    }
+
+    /// Looks up previously formatted lyrics in the in-memory cache, then the disk cache
+    fn get_cached_lyrics(&self, key: &str) -> Option<serde_json::Value> {
+        if let Some(cached) = self.lyrics_cache.as_ref().and_then(|cache| cache.lock().unwrap().get(key)) {
+            return Some(cached);
+        }
         
-        format!("{:02}:{:02}:{:02},{:03}", hours, minutes, seconds, ms)
+        // Fall back to the disk cache, promoting hits into memory for the next lookup
+        let cached = self.disk_cache.as_ref()?.get(key)?;
+        if let Some(cache) = &self.lyrics_cache {
+            cache.lock().unwrap().insert(key.to_string(), cached.clone());
+        }
+        Some(cached)
+    }
+
+    /// Stores formatted lyrics in the in-memory and disk caches, if enabled
+    fn cache_lyrics(&self, key: String, value: &serde_json::Value) {
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.insert(&key, value);
+        }
+        if let Some(cache) = &self.lyrics_cache {
+            cache.lock().unwrap().insert(key, value.clone());
+        }
+    }
+
+    /// Returns the not-found message of a track recently found to have no lyrics
+    fn get_missing_lyrics(&self, key: &str) -> Option<String> {
+        let message = self.missing_lyrics_cache.as_ref()?.lock().unwrap().get(key)?;
+        message.as_str().map(str::to_string)
+    }
+
+    /// Remembers that a track has no lyrics, if the missing-lyrics cache is enabled
+    fn cache_missing_lyrics(&self, key: String, message: &str) {
+        if let Some(cache) = &self.missing_lyrics_cache {
+            cache.lock().unwrap().insert(key, json!(message));
+        }
+    }
+}
+
+/// Options for `format_lyrics` besides the format itself
+#[derive(Debug, Clone, Copy)]
+pub struct FormatOptions<'a> {
+    /// Milliseconds added to every timestamp, may be negative
+    pub offset_ms: i64,
+    /// Language of a translation to use instead of the original lyrics
+    pub lang: Option<&'a str>,
+    /// Leave out musical interlude lines, whose words are empty or only `♪`
+    pub skip_empty: bool,
+    /// `track` for song lyrics, `episode` for a podcast transcript
+    pub item_type: &'static str,
+}
+
+impl Default for FormatOptions<'_> {
+    fn default() -> Self {
+        FormatOptions {
+            offset_ms: 0,
+            lang: None,
+            skip_empty: false,
+            item_type: "track",
+        }
+    }
+}
+
+/// Builds the requested format from Spotify's lyrics JSON, or a transcript converted to that shape.
+/// Pure, so fetched lyrics and lyrics a client supplies go through exactly the same path.
+pub fn format_lyrics(parsed: &serde_json::Value, format: &str, opts: FormatOptions) -> Result<serde_json::Value> {
+    let FormatOptions { offset_ms, lang, skip_empty, item_type } = opts;
+    
+    // Spotify's sync type is passed through as-is, so clients can tell syllable-synced lyrics
+    // (which also carry line timings) apart. Without any lines there is nothing synced,
+    // whatever Spotify reports, and a missing value means unsynced.
+    let has_lines = parsed["lyrics"]["lines"].as_array().is_some_and(|lines| !lines.is_empty());
+    let reported_sync_type = parsed["lyrics"]["syncType"].as_str().unwrap_or("").to_string();
+    let sync_type = if has_lines && !reported_sync_type.is_empty() {
+        reported_sync_type.as_str()
+    } else {
+        "UNSYNCED"
+    };
+
+    // Translations and skipped lines change the lyrics, so they work on a copy
+    let mut adjusted = None;
+    let language_available = lang.map(|lang| apply_translation(adjusted.get_or_insert_with(|| parsed.clone()), lang));
+    if skip_empty {
+        remove_interlude_lines(adjusted.get_or_insert_with(|| parsed.clone()));
+    }
+    let lyrics_data = adjusted.as_ref().unwrap_or(parsed);
+    
+    let mut metadata = LyricsMetadata::from_lyrics(lyrics_data, sync_type, offset_ms);
+    metadata.item_type = item_type;
+    metadata.requested_language_available = language_available;
+    
+    // Subtitle and NDJSON documents have nowhere to carry the warnings, so they are also logged
+    if !metadata.warnings.is_empty() {
+        warn!("Formatting lyrics with malformed lines: {}", metadata.warnings.join("; "));
+    }
+    
+    // Format the lyrics based on the requested format
+    let formatted = if format == "lrc" || format == "elrc" {
+        let enhanced = format == "elrc";
+        serde_json::to_value(build_lrc_response(lyrics_data, sync_type, offset_ms, enhanced, metadata))?
+    } else if format == "both" {
+        // Both representations built from the single fetched payload
+        let response = BothResponse {
+            status: metadata.status(sync_type),
+            id3: build_id3_response(lyrics_data, sync_type, offset_ms, metadata.clone()),
+            lrc: build_lrc_response(lyrics_data, sync_type, offset_ms, false, metadata),
+        };
+        
+        serde_json::to_value(response)?
+    } else if format == "srt" || format == "vtt" || format == "ttml" {
+        let mut timed_lines = Vec::new();
+        
+        if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
+            for line in lyrics_lines {
+                let start_ms = line["startTimeMs"].as_str().unwrap_or("0").parse::<u64>().unwrap_or(0);
+                let words = line["words"].as_str().unwrap_or("");
+                
+                // Subtitle formats can't represent empty cues
+                if !words.trim().is_empty() {
+                    timed_lines.push((start_ms.saturating_add_signed(offset_ms), words.to_string()));
+                }
+            }
+        }
+        
+        let cues = normalize_cues(timed_lines);
+        
+        // Subtitle documents are plain text, carried as a JSON string
+        let document = match format {
+            "srt" => format_srt_document(&cues),
+            "ttml" => format_ttml_document(&cues, lyrics_data["lyrics"]["language"].as_str().unwrap_or("")),
+            _ => format_vtt_document(&cues),
+        };
+        
+        serde_json::Value::String(document)
+    } else if format == "json-lines" {
+        // One {"t": startMs, "words": ...} object per line (NDJSON), also carried as a JSON string
+        let mut document = String::new();
+        
+        if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
+            for line in lyrics_lines {
+                let start_ms = line["startTimeMs"].as_str().unwrap_or("0").parse::<u64>().unwrap_or(0);
+                let entry = json!({
+                    "t": start_ms.saturating_add_signed(offset_ms),
+                    "words": line["words"].as_str().unwrap_or(""),
+                });
+                
+                document.push_str(&entry.to_string());
+                document.push('\n');
+            }
+        }
+        
+        serde_json::Value::String(document)
+    } else if format == "text" {
+        // Just the words, one line each, also carried as a JSON string
+        let mut document = String::new();
+        
+        if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
+            for line in lyrics_lines {
+                document.push_str(line["words"].as_str().unwrap_or(""));
+                document.push('\n');
+            }
+        }
+        
+        serde_json::Value::String(document)
+    } else {
+        // Default format is id3
+        serde_json::to_value(build_id3_response(lyrics_data, sync_type, offset_ms, metadata))?
+    };
+
+    Ok(formatted)
+}
+
+/// Builds the LRC representation, with time tags omitted for unsynced lyrics.
+/// With `enhanced`, lines that have syllable timings get inline word tags (enhanced LRC).
+fn build_lrc_response(lyrics_data: &serde_json::Value, sync_type: &str, offset_ms: i64, enhanced: bool, metadata: LyricsMetadata) -> LrcResponse {
+    let mut lines = Vec::new();
+    
+    if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
+        for line in lyrics_lines {
+            let time_tag = if sync_type == "UNSYNCED" {
+                None
+            } else {
+                let start_time_ms = line["startTimeMs"].as_str().unwrap_or("0").to_string();
+                let shifted_ms = start_time_ms.parse::<u64>().unwrap_or(0).saturating_add_signed(offset_ms);
+                Some(format_ms(shifted_ms))
+            };
+            
+            let enhanced_words = if enhanced && time_tag.is_some() {
+                format_enhanced_words(line, offset_ms)
+            } else {
+                None
+            };
+            
+            let lrc_line = LrcLine {
+                time_tag,
+                words: enhanced_words.unwrap_or_else(|| line["words"].as_str().unwrap_or("").to_string()),
+            };
+            
+            lines.push(lrc_line);
+        }
+    }
+    
+    LrcResponse {
+        status: metadata.status(sync_type),
+        provider: metadata.provider,
+        language: metadata.language,
+        line_count: metadata.line_count,
+        total_duration_ms: metadata.total_duration_ms,
+        lines,
+    }
+}
+
+/// Builds the id3 representation with raw millisecond start times and theme colors
+fn build_id3_response(lyrics_data: &serde_json::Value, sync_type: &str, offset_ms: i64, metadata: LyricsMetadata) -> Id3Response {
+    let mut lines = Vec::new();
+    
+    if let Some(lyrics_lines) = lyrics_data["lyrics"]["lines"].as_array() {
+        for line in lyrics_lines {
+            let start_time_ms = line["startTimeMs"].as_str().unwrap_or("0");
+            let start_time_ms = if offset_ms == 0 {
+                start_time_ms.to_string()
+            } else {
+                start_time_ms.parse::<u64>().unwrap_or(0).saturating_add_signed(offset_ms).to_string()
+            };
+
+            let id3_line = LyricLine {
+                start_time_ms,
+                words: line["words"].as_str().unwrap_or("").to_string(),
+                syllables: Vec::new(), // Spotify doesn't provide syllables
+                end_time_ms: "0".to_string(), // Spotify doesn't provide end time
+            };
+            
+            lines.push(id3_line);
+        }
+    }
+    
+    let colors = match (
+        lyrics_data["colors"]["background"].as_i64(),
+        lyrics_data["colors"]["text"].as_i64(),
+        lyrics_data["colors"]["highlightText"].as_i64(),
+    ) {
+        (Some(background), Some(text), Some(highlight_text)) => Some(LyricsColors {
+            background,
+            text,
+            highlight_text,
+        }),
+        _ => None,
+    };
+    
+    Id3Response {
+        status: metadata.status(sync_type),
+        provider: metadata.provider,
+        language: metadata.language,
+        line_count: metadata.line_count,
+        total_duration_ms: metadata.total_duration_ms,
+        colors,
+        lines,
+    }
+}
+
+/// Formats milliseconds as an LRC time tag, `mm:ss.xx`. LRC has no hours field, so past
+/// an hour the minutes keep counting (`75:30.50`, `120:00.00`); an `hh:mm:ss.xx` tag would be
+/// misread as minutes and seconds by most parsers.
+fn format_ms(milliseconds: u64) -> String {
+    let total_seconds = milliseconds / 1000;
+    // Deliberately not wrapped at 60, see above
+    let minutes = total_seconds / 60;
+    let seconds = total_seconds % 60;
+    let centiseconds = (milliseconds % 1000) / 10;
+    
+    format!("{:02}:{:02}.{:02}", minutes, seconds, centiseconds)
+}
+
+/// Formats a line's words with enhanced LRC word tags, e.g. `<00:01.00>Look <00:01.40>at`,
+/// splitting the words by each syllable's `numChars`. Returns None when the line has
+/// no usable syllable timing, so the caller falls back to the plain words.
+fn format_enhanced_words(line: &serde_json::Value, offset_ms: i64) -> Option<String> {
+    let syllables = line["syllables"].as_array().filter(|syllables| !syllables.is_empty())?;
+    let chars: Vec<char> = line["words"].as_str()?.chars().collect();
+    
+    let mut position = 0;
+    let mut formatted = String::new();
+    
+    for syllable in syllables {
+        let start_ms = json_u64(&syllable["startTimeMs"])?;
+        let num_chars = json_u64(&syllable["numChars"])? as usize;
+        
+        let end = (position + num_chars).min(chars.len());
+        let text: String = chars[position..end].iter().collect();
+        position = end;
+        
+        // Keep the tag next to the text it times, after any separating whitespace
+        let trimmed = text.trim_start();
+        formatted.push_str(&text[..text.len() - trimmed.len()]);
+        formatted.push_str(&format!("<{}>{}", format_ms(start_ms.saturating_add_signed(offset_ms)), trimmed));
+    }
+    
+    // Any characters the syllables didn't cover belong to the last one
+    formatted.extend(&chars[position..]);
+    
+    Some(formatted)
+}
+
+/// Helper function to format milliseconds to SRT time format (hh:mm:ss,ms)
+fn format_srt(milliseconds: u64) -> String {
+    let hours = milliseconds / 3600000;
+    let minutes = (milliseconds % 3600000) / 60000;
+    let seconds = (milliseconds % 60000) / 1000;
+    let ms = milliseconds % 1000;
+    
+    format!("{:02}:{:02}:{:02},{:03}", hours, minutes, seconds, ms)
+}
+
+/// Helper function to format milliseconds to WebVTT time format (hh:mm:ss.ms)
+fn format_vtt(milliseconds: u64) -> String {
+    format_srt(milliseconds).replace(',', ".")
+}
+
+/// Renders normalized cues as a SubRip (.srt) document
+fn format_srt_document(cues: &[Cue]) -> String {
+    let mut document = String::new();
+    
+    for (index, cue) in cues.iter().enumerate() {
+        document.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            index + 1,
+            format_srt(cue.start_ms),
+            format_srt(cue.end_ms),
+            cue.text
+        ));
+    }
+    
+    document
+}
+
+/// Renders normalized cues as a WebVTT (.vtt) document
+fn format_vtt_document(cues: &[Cue]) -> String {
+    let mut document = String::from("WEBVTT\n\n");
+    
+    for cue in cues {
+        document.push_str(&format!(
+            "{} --> {}\n{}\n\n",
+            format_vtt(cue.start_ms),
+            format_vtt(cue.end_ms),
+            cue.text
+        ));
+    }
+    
+    document
+}
+
+/// Renders normalized cues as a minimal TTML (DFXP) document with one `<p>` per cue
+fn format_ttml_document(cues: &[Cue], language: &str) -> String {
+    let mut document = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tt xmlns=\"http://www.w3.org/ns/ttml\" xml:lang=\"{}\">\n  <body>\n    <div>\n",
+        escape_xml(language)
+    );
+    
+    for cue in cues {
+        document.push_str(&format!(
+            "      <p begin=\"{}\" end=\"{}\">{}</p>\n",
+            format_vtt(cue.start_ms),
+            format_vtt(cue.end_ms),
+            escape_xml(&cue.text)
+        ));
+    }
+    
+    document.push_str("    </div>\n  </body>\n</tt>\n");
+    document
+}
+
+/// Escapes text for use in XML content and attribute values
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Finds a web player version such as `1.2.61.20.g3b4cd5b2` (four numbers and a git hash),
+/// which the page embeds in its config as `clientVersion`
+fn find_app_version(page: &str) -> Option<String> {
+    page.split(|c: char| !(c.is_ascii_alphanumeric() || c == '.'))
+        .find(|token| {
+            let parts: Vec<&str> = token.split('.').collect();
+            parts.len() == 5
+                && parts[..4].iter().all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+                && parts[4].strip_prefix('g').is_some_and(|hash| hash.len() >= 7 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+        })
+        .map(str::to_string)
+}
+
+/// Decodes a base32 TOTP secret, failing rather than falling back to an empty key
+/// that would silently produce tokens Spotify rejects
+pub fn decode_totp_secret(secret_base32: &str) -> Result<Vec<u8>> {
+    match base32::decode(Alphabet::RFC4648 { padding: false }, secret_base32.trim()) {
+        Some(secret) if !secret.is_empty() => Ok(secret),
+        Some(_) => Err(SpotifyException::new("TOTP secret is empty")),
+        None => Err(SpotifyException::new("TOTP secret is not valid base32")),
+    }
+}
+
+/// Drops the lines Spotify uses to mark musical interludes, whose words are empty or only `♪`
+fn remove_interlude_lines(lyrics_data: &mut serde_json::Value) {
+    if let Some(lines) = lyrics_data["lyrics"]["lines"].as_array_mut() {
+        lines.retain(|line| {
+            let words = line["words"].as_str().unwrap_or("");
+            !words.chars().all(|c| c == '♪' || c.is_whitespace())
+        });
+    }
+}
+
+/// Replaces the words of every line with the translation for `lang` from `lyrics.alternatives`,
+/// keeping the original timings. Returns whether the language was available; the original
+/// language always counts as available.
+fn apply_translation(lyrics_data: &mut serde_json::Value, lang: &str) -> bool {
+    if lyrics_data["lyrics"]["language"].as_str().is_some_and(|language| language.eq_ignore_ascii_case(lang)) {
+        return true;
+    }
+    
+    let alternative = lyrics_data["lyrics"]["alternatives"]
+        .as_array()
+        .and_then(|alternatives| {
+            alternatives.iter().find(|alternative| {
+                alternative["language"].as_str().is_some_and(|language| language.eq_ignore_ascii_case(lang))
+            })
+        })
+        .cloned();
+    
+    let Some(alternative) = alternative else {
+        return false;
+    };
+    
+    // Translated lines are matched to the original lines by position, either as plain
+    // strings or as line objects with their own `words`
+    let translated_lines = alternative["lines"].as_array().cloned().unwrap_or_default();
+    
+    if let Some(lines) = lyrics_data["lyrics"]["lines"].as_array_mut() {
+        for (line, translated) in lines.iter_mut().zip(&translated_lines) {
+            if let Some(words) = translated.as_str().or_else(|| translated["words"].as_str()) {
+                line["words"] = serde_json::Value::String(words.to_string());
+            }
+        }
+    }
+    
+    lyrics_data["lyrics"]["language"] = alternative["language"].clone();
+    true
+}
+
+/// Reads a millisecond or count field that Spotify sends either as a string or a number
+fn json_u64(value: &serde_json::Value) -> Option<u64> {
+    value.as_u64().or_else(|| value.as_str()?.parse().ok())
+}
+
+/// Turns (start, text) lines into subtitle cues that players accept: sorted by start time,
+/// lines sharing a start merged into one cue, every cue at least `MIN_CUE_DURATION_MS` long,
+/// and each cue ending 1ms before the next one starts so cues never overlap or invert.
+fn normalize_cues(mut lines: Vec<(u64, String)>) -> Vec<Cue> {
+    lines.sort_by_key(|(start_ms, _)| *start_ms);
+    
+    let mut cues: Vec<Cue> = Vec::new();
+    
+    for (start_ms, text) in lines {
+        match cues.last_mut() {
+            Some(previous) if previous.start_ms == start_ms => {
+                previous.text.push('\n');
+                previous.text.push_str(&text);
+            },
+            Some(previous) => {
+                // Push the cue later if it starts too soon after the previous one
+                let start_ms = start_ms.max(previous.start_ms + MIN_CUE_DURATION_MS);
+                previous.end_ms = start_ms - 1;
+                cues.push(Cue { start_ms, end_ms: start_ms + LAST_CUE_DURATION_MS, text });
+            },
+            None => {
+                cues.push(Cue { start_ms, end_ms: start_ms + LAST_CUE_DURATION_MS, text });
+            }
+        }
+    }
+    
+    cues
+}
+
+/// Builds the HTTP client shared by all Spotify requests so connections and TLS sessions are reused.
+/// Headers common to every request are set as defaults on the client.
+fn build_client(timeout: Duration, identity: &ClientIdentity) -> reqwest::Client {
+    let mut default_headers = HeaderMap::new();
+    default_headers.insert("app-platform", header_value("app-platform", &identity.app_platform, DEFAULT_APP_PLATFORM));
+    default_headers.insert("spotify-app-version", header_value("spotify-app-version", &identity.app_version, DEFAULT_APP_VERSION));
+    
+    reqwest::Client::builder()
+        .user_agent(header_value("user-agent", &identity.user_agent, DEFAULT_USER_AGENT))
+        .default_headers(default_headers)
+        .timeout(timeout)
+        .connect_timeout(timeout.min(CONNECT_TIMEOUT))
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// Converts an LRCLIB record into the shape of Spotify's lyrics JSON. Synced lyrics are preferred;
+/// plain lyrics become unsynced lines, and instrumentals have no lines.
+fn lrclib_to_lyrics(lrclib: &serde_json::Value) -> serde_json::Value {
+    let synced = lrclib["syncedLyrics"].as_str().map(parse_lrc).filter(|lines| !lines.is_empty());
+    
+    let (sync_type, lines) = match synced {
+        Some(lines) => ("LINE_SYNCED", lines),
+        None => {
+            let lines = lrclib["plainLyrics"].as_str()
+                .map(|plain| plain.lines().map(|words| (0, words.to_string())).collect())
+                .unwrap_or_default();
+            ("UNSYNCED", lines)
+        }
+    };
+    
+    let lines: Vec<serde_json::Value> = lines.into_iter()
+        .map(|(start_ms, words)| json!({ "startTimeMs": start_ms.to_string(), "words": words }))
+        .collect();
+    
+    json!({
+        "lyrics": {
+            "syncType": sync_type,
+            "provider": "LRCLIB",
+            "language": "",
+            "lines": lines
+        }
+    })
+}
+
+/// Parses LRC text into timed lines sorted by start time. A line with several time tags
+/// (`[00:12.00][01:30.50]Chorus`) is repeated at each; ID tags and untimed lines are skipped.
+fn parse_lrc(text: &str) -> Vec<(u64, String)> {
+    let mut lines = Vec::new();
+    
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut start_times = Vec::new();
+        
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|tag| tag.split_once(']')) {
+            match parse_lrc_time(tag) {
+                Some(start_ms) => start_times.push(start_ms),
+                None => break,
+            }
+            rest = after;
+        }
+        
+        for start_ms in start_times {
+            lines.push((start_ms, rest.trim().to_string()));
+        }
+    }
+    
+    lines.sort_by_key(|(start_ms, _)| *start_ms);
+    lines
+}
+
+/// Parses an LRC time tag such as `01:02.34` or `01:02` into milliseconds
+fn parse_lrc_time(tag: &str) -> Option<u64> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes = minutes.parse::<u64>().ok()?;
+    let seconds = seconds.parse::<f64>().ok().filter(|seconds| *seconds >= 0.0 && *seconds < 60.0)?;
+    
+    Some(minutes * 60_000 + (seconds * 1000.0).round() as u64)
+}
+
+/// Converts a read-along transcript into the shape of Spotify's lyrics JSON, so it can be formatted
+/// the same way. Each spoken sentence becomes a line; chapter titles are dropped.
+fn transcript_to_lyrics(transcript: &serde_json::Value) -> serde_json::Value {
+    let lines: Vec<serde_json::Value> = transcript["section"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|section| {
+            let words = section["text"]["sentence"]["text"].as_str()?;
+            let start_ms = json_u64(&section["startMs"]).unwrap_or(0);
+            
+            Some(json!({
+                "startTimeMs": start_ms.to_string(),
+                "words": words,
+                "syllables": [],
+                "endTimeMs": "0"
+            }))
+        })
+        .collect();
+    
+    json!({
+        "lyrics": {
+            "syncType": "LINE_SYNCED",
+            "provider": "Spotify",
+            "language": transcript["language"].as_str().unwrap_or(""),
+            "lines": lines
+        }
+    })
+}
+
+/// Checks the parts of Spotify's lyrics response the formatters rely on, so a change in its shape
+/// fails with what changed instead of quietly producing empty or misdated lines
+fn check_lyrics_shape(lyrics_data: &serde_json::Value) -> Result<()> {
+    let lyrics = &lyrics_data["lyrics"];
+    let problems = if lyrics.is_object() {
+        [("lines", "an array", lyrics["lines"].is_array()), ("syncType", "a string", lyrics["syncType"].is_string())]
+            .into_iter()
+            .filter(|&(_, _, valid)| !valid)
+            .map(|(key, expected, _)| match lyrics.get(key) {
+                Some(value) => format!("lyrics.{} is {} instead of {}", key, json_type(value), expected),
+                None => format!("lyrics.{} is missing", key),
+            })
+            .collect::<Vec<_>>()
+    } else {
+        vec![format!("lyrics is {} instead of an object", json_type(lyrics))]
+    };
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(SpotifyException::ApiError(format!("unexpected lyrics response shape: {}", problems.join(", "))))
+    }
+}
+
+/// JSON type name of a value, for describing malformed responses
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+/// Writes the token to a temporary file in the same directory and renames it into place,
+/// so a crash mid-write can never leave a half-written cache behind
+fn write_token_file(cache_file: &Path, data: &CacheData) -> Result<()> {
+    let json = serde_json::to_string(data)?;
+    
+    let dir = cache_file.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(json.as_bytes())?;
+    file.persist(cache_file).map_err(|e| e.error)?;
+    
+    Ok(())
+}
+
+/// URL of the middle image by width. Spotify usually lists album art at 640, 300 and 64 pixels,
+/// and 300 is large enough for a lyrics view without loading the full-size cover.
+fn medium_image_url(images: &serde_json::Value) -> Option<String> {
+    let mut images = images.as_array()?
+        .iter()
+        .filter(|image| image["url"].is_string())
+        .collect::<Vec<_>>();
+    images.sort_by_key(|image| image["width"].as_u64().unwrap_or(0));
+    
+    images.get(images.len() / 2)?["url"].as_str().map(str::to_string)
+}
+
+/// Whether a lyrics response is an HTML page rather than the JSON Spotify normally returns
+fn is_html_body(content_type: Option<&str>, body: &str) -> bool {
+    content_type.is_some_and(|value| value.to_ascii_lowercase().contains("text/html"))
+        || body.trim_start().starts_with('<')
+}
+
+/// Parses a configured header value, warning and using the built-in default if it isn't valid in a header
+fn header_value(name: &str, value: &str, default: &'static str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| {
+        warn!("Ignoring invalid {} value: {:?}", name, value);
+        HeaderValue::from_static(default)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B: SHA-1 vectors for the ASCII secret "12345678901234567890", as 8 digits
+    const RFC_6238_SECRET: &[u8] = b"12345678901234567890";
+    const RFC_6238_VECTORS: [(u64, &str); 6] = [
+        (59, "94287082"),
+        (1111111109, "07081804"),
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+        (20000000000, "65353130"),
+    ];
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        for (time, expected) in RFC_6238_VECTORS {
+            // A 6-digit code is the last 6 digits of the 8-digit one
+            assert_eq!(Spotify::generate_totp(RFC_6238_SECRET, time), &expected[2..], "T = {}", time);
+        }
+    }
+
+    #[test]
+    fn totp_changes_at_30_second_boundaries() {
+        assert_eq!(Spotify::generate_totp(RFC_6238_SECRET, 0), Spotify::generate_totp(RFC_6238_SECRET, 29));
+        assert_ne!(Spotify::generate_totp(RFC_6238_SECRET, 29), Spotify::generate_totp(RFC_6238_SECRET, 30));
+    }
+
+    #[test]
+    fn picks_the_medium_album_image() {
+        let images = serde_json::json!([
+            { "url": "https://i.scdn.co/image/large", "width": 640, "height": 640 },
+            { "url": "https://i.scdn.co/image/small", "width": 64, "height": 64 },
+            { "url": "https://i.scdn.co/image/medium", "width": 300, "height": 300 }
+        ]);
+
+        assert_eq!(medium_image_url(&images).as_deref(), Some("https://i.scdn.co/image/medium"));
+        assert_eq!(medium_image_url(&serde_json::json!([])), None);
+        assert_eq!(medium_image_url(&serde_json::Value::Null), None);
+    }
+
+    fn lyrics_fixture() -> serde_json::Value {
+        serde_json::json!({
+            "lyrics": {
+                "syncType": "LINE_SYNCED",
+                "lines": [
+                    { "startTimeMs": "1000", "words": "First line", "syllables": [], "endTimeMs": "0" },
+                    { "startTimeMs": "4500", "words": "Second line", "syllables": [], "endTimeMs": "0" }
+                ],
+                "provider": "MusixMatch",
+                "language": "en"
+            }
+        })
+    }
+
+    fn formatted(format: &str, opts: FormatOptions) -> serde_json::Value {
+        format_lyrics(&lyrics_fixture(), format, opts).unwrap()
+    }
+
+    fn with_offset(offset_ms: i64) -> FormatOptions<'static> {
+        FormatOptions { offset_ms, ..FormatOptions::default() }
+    }
+
+    #[test]
+    fn positive_offset_delays_every_timestamp() {
+        let lrc = formatted("lrc", with_offset(500));
+        assert_eq!(lrc["lines"][0]["timeTag"], "00:01.50");
+        assert_eq!(lrc["lines"][1]["timeTag"], "00:05.00");
+
+        let srt = formatted("srt", with_offset(500));
+        assert!(srt.as_str().unwrap().starts_with("1\n00:00:01,500 --> 00:00:04,999\n"));
+    }
+
+    #[test]
+    fn negative_offset_clamps_at_zero() {
+        let lrc = formatted("lrc", with_offset(-2000));
+        assert_eq!(lrc["lines"][0]["timeTag"], "00:00.00");
+        assert_eq!(lrc["lines"][1]["timeTag"], "00:02.50");
+
+        let vtt = formatted("vtt", with_offset(-2000));
+        assert!(vtt.as_str().unwrap().contains("00:00:00.000 --> 00:00:02.499\n"));
+    }
+
+    #[test]
+    fn provider_and_language_come_from_the_lyrics() {
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["provider"] = "Petit Lyrics".into();
+        lyrics["lyrics"]["language"] = "ja".into();
+
+        for format in ["id3", "lrc"] {
+            let response = format_lyrics(&lyrics, format, FormatOptions::default()).unwrap();
+            assert_eq!(response["provider"], "Petit Lyrics", "{}", format);
+            assert_eq!(response["language"], "ja", "{}", format);
+        }
+
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"].as_object_mut().unwrap().remove("language");
+        assert_eq!(format_lyrics(&lyrics, "id3", FormatOptions::default()).unwrap()["language"], "");
+    }
+
+    #[test]
+    fn id3_carries_spotify_colors_when_present() {
+        let mut lyrics = lyrics_fixture();
+        lyrics["colors"] = serde_json::json!({ "background": -9079435, "text": -16777216, "highlightText": -1 });
+
+        let id3 = format_lyrics(&lyrics, "id3", FormatOptions::default()).unwrap();
+        assert_eq!(id3["colors"], serde_json::json!({ "background": -9079435, "text": -16777216, "highlightText": -1 }));
+
+        // Left out for LRC and for lyrics without colors
+        assert!(format_lyrics(&lyrics, "lrc", FormatOptions::default()).unwrap().get("colors").is_none());
+        assert!(formatted("id3", FormatOptions::default()).get("colors").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_or_empty_totp_secrets() {
+        assert!(decode_totp_secret("not base32!").is_err());
+        assert!(decode_totp_secret("  ").is_err());
+        assert_eq!(decode_totp_secret(" GEZDGNBV ").unwrap(), b"12345");
+    }
+
+    fn spotify_with_token_file(cache_file: PathBuf) -> Spotify {
+        let mut spotify = Spotify::new(vec!["test-sp-dc".to_string()]);
+        spotify.cache_file = Some(cache_file);
+        spotify
+    }
+
+    #[test]
+    fn corrupt_token_file_reads_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("spotify_token.json");
+        std::fs::write(&cache_file, r#"{"access_token":"trunc"#).unwrap();
+
+        let data = spotify_with_token_file(cache_file).read_token_file().unwrap();
+        assert!(data.access_token.is_none());
+        assert!(data.access_token_expiration_timestamp_ms.is_none());
+    }
+
+    #[test]
+    fn token_file_is_replaced_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("spotify_token.json");
+        std::fs::write(&cache_file, "half-written garbage that is longer than the new token file").unwrap();
+
+        let data = CacheData {
+            access_token: Some("new-token".to_string()),
+            client_id: None,
+            access_token_expiration_timestamp_ms: Some(1_700_000_000_000),
+        };
+        write_token_file(&cache_file, &data).unwrap();
+
+        let read = spotify_with_token_file(cache_file.clone()).read_token_file().unwrap();
+        assert_eq!(read.access_token.as_deref(), Some("new-token"));
+        assert_eq!(read.access_token_expiration_timestamp_ms, Some(1_700_000_000_000));
+
+        // The temporary file was renamed over the target rather than left beside it
+        let entries: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(entries, vec![cache_file]);
+    }
+
+    #[tokio::test]
+    async fn forced_refresh_rewrites_the_token_file() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/server-time"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "serverTime": 1_700_000_000 })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "accessToken": "new-token",
+                "accessTokenExpirationTimestampMs": 4_102_444_800_000u64,
+                "isAnonymous": false
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("spotify_token.json");
+        std::fs::write(&cache_file, r#"{"access_token":"old-token","access_token_expiration_timestamp_ms":4102444800000}"#).unwrap();
+
+        let spotify = spotify_with_token_file(cache_file.clone())
+            .with_server_time_url(format!("{}/api/server-time", server.uri()))
+            .with_token_url(format!("{}/api/token", server.uri()));
+
+        assert_eq!(spotify.force_refresh_token().await.unwrap(), 4_102_444_800_000);
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&cache_file).unwrap()).unwrap();
+        assert_eq!(written["access_token"], "new-token");
+    }
+
+    #[test]
+    fn cues_are_sorted_merged_and_never_overlap() {
+        let cues = normalize_cues(vec![
+            (3000, "third".to_string()),
+            (1000, "first".to_string()),
+            (1000, "also first".to_string()),
+            (1050, "too soon".to_string()),
+        ]);
+
+        let summary: Vec<_> = cues.iter().map(|cue| (cue.start_ms, cue.end_ms, cue.text.as_str())).collect();
+        assert_eq!(summary, [
+            (1000, 1099, "first\nalso first"),
+            (1100, 2999, "too soon"),
+            (3000, 8000, "third"),
+        ]);
+
+        for pair in cues.windows(2) {
+            assert!(pair[0].start_ms < pair[0].end_ms && pair[0].end_ms < pair[1].start_ms);
+        }
+    }
+
+    fn unsynced_fixture() -> serde_json::Value {
+        serde_json::json!({
+            "lyrics": {
+                "syncType": "UNSYNCED",
+                "lines": [
+                    { "startTimeMs": "0", "words": "First line", "syllables": [], "endTimeMs": "0" },
+                    { "startTimeMs": "0", "words": "Second line", "syllables": [], "endTimeMs": "0" }
+                ],
+                "provider": "MusixMatch",
+                "language": "en"
+            }
+        })
+    }
+
+    #[test]
+    fn unsynced_lrc_has_no_time_tags() {
+        let lrc = format_lyrics(&unsynced_fixture(), "lrc", FormatOptions::default()).unwrap();
+
+        assert_eq!(lrc["syncType"], "UNSYNCED");
+        assert_eq!(lrc["lines"], serde_json::json!([{ "words": "First line" }, { "words": "Second line" }]));
+        assert!(!lrc.to_string().contains("[00:"));
+    }
+
+    #[test]
+    fn reports_line_count_and_lyric_span() {
+        for format in ["id3", "lrc"] {
+            let response = formatted(format, FormatOptions::default());
+            assert_eq!(response["lineCount"], 2, "{}", format);
+            assert_eq!(response["totalDurationMs"], 4500, "{}", format);
+        }
+
+        // The span follows the offset, and unsynced lyrics have none
+        assert_eq!(formatted("id3", with_offset(500))["totalDurationMs"], 5000);
+        let unsynced = format_lyrics(&unsynced_fixture(), "id3", FormatOptions::default()).unwrap();
+        assert_eq!(unsynced["lineCount"], 2);
+        assert_eq!(unsynced["totalDurationMs"], 0);
+    }
+
+    #[test]
+    fn both_holds_consistent_id3_and_lrc() {
+        let both = formatted("both", with_offset(500));
+
+        assert_eq!(both["syncType"], "LINE_SYNCED");
+        assert_eq!(both["id3"], formatted("id3", with_offset(500)));
+        assert_eq!(both["lrc"], formatted("lrc", with_offset(500)));
+
+        let id3_lines = both["id3"]["lines"].as_array().unwrap();
+        let lrc_lines = both["lrc"]["lines"].as_array().unwrap();
+        assert_eq!(id3_lines.len(), lrc_lines.len());
+        for (id3, lrc) in id3_lines.iter().zip(lrc_lines) {
+            assert_eq!(id3["words"], lrc["words"]);
+            let start_ms = id3["startTimeMs"].as_str().unwrap().parse().unwrap();
+            assert_eq!(lrc["timeTag"], format_ms(start_ms));
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_share_link_redirects_to_the_track() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let port = server.address().port();
+        Mock::given(method("GET"))
+            .and(path("/abc123"))
+            .respond_with(ResponseTemplate::new(301)
+                .insert_header("Location", format!("http://open.spotify.com:{}/track/4cOdK2wGLETKBW3PvgPWqT?si=x", port).as_str()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/track/4cOdK2wGLETKBW3PvgPWqT"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Both hosts resolve to the mock server instead of going out to the network
+        let mut spotify = Spotify::new(vec!["test-sp-dc".to_string()]);
+        spotify.client = reqwest::Client::builder()
+            .resolve("spotify.link", *server.address())
+            .resolve("open.spotify.com", *server.address())
+            .build()
+            .unwrap();
+
+        let item = spotify.resolve_share_url(&format!("http://spotify.link:{}/abc123", port)).await.unwrap();
+        assert!(matches!(item, Some(SpotifyItem::Track(id)) if id == "4cOdK2wGLETKBW3PvgPWqT"));
+    }
+
+    #[tokio::test]
+    async fn only_follows_spotify_share_hosts() {
+        let spotify = Spotify::new(vec!["test-sp-dc".to_string()]);
+        assert!(spotify.resolve_share_url("http://127.0.0.1:1/abc123").await.unwrap().is_none());
+        assert!(spotify.resolve_share_url("https://example.com/track/4cOdK2wGLETKBW3PvgPWqT").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn format_ms_counts_minutes_past_an_hour() {
+        assert_eq!(format_ms(0), "00:00.00");
+        assert_eq!(format_ms(61_239), "01:01.23");
+        assert_eq!(format_ms(3_723_450), "62:03.45");
+    }
+
+    #[test]
+    fn elrc_tags_each_word_and_falls_back_per_line() {
+        let lyrics = serde_json::json!({
+            "lyrics": {
+                "syncType": "SYLLABLE_SYNCED",
+                "lines": [
+                    {
+                        "startTimeMs": "1000",
+                        "words": "Look at the stars",
+                        "syllables": [
+                            { "startTimeMs": "1000", "numChars": 4 },
+                            { "startTimeMs": "1400", "numChars": 3 },
+                            { "startTimeMs": "1650", "numChars": 4 },
+                            { "startTimeMs": "2000", "numChars": 6 }
+                        ],
+                        "endTimeMs": "0"
+                    },
+                    { "startTimeMs": "4500", "words": "No syllables here", "syllables": [], "endTimeMs": "0" }
+                ]
+            }
+        });
+
+        let elrc = format_lyrics(&lyrics, "elrc", FormatOptions::default()).unwrap();
+        assert_eq!(elrc["lines"][0]["timeTag"], "00:01.00");
+        assert_eq!(elrc["lines"][0]["words"], "<00:01.00>Look <00:01.40>at <00:01.65>the <00:02.00>stars");
+        assert_eq!(elrc["lines"][1]["words"], "No syllables here");
+
+        let shifted = format_lyrics(&lyrics, "elrc", with_offset(500)).unwrap();
+        assert!(shifted["lines"][0]["words"].as_str().unwrap().starts_with("<00:01.50>Look <00:01.90>at"));
+
+        // Plain LRC ignores the syllables
+        let lrc = format_lyrics(&lyrics, "lrc", FormatOptions::default()).unwrap();
+        assert_eq!(lrc["lines"][0]["words"], "Look at the stars");
+    }
+
+    fn translated_fixture() -> serde_json::Value {
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["alternatives"] = serde_json::json!([
+            { "language": "es", "lines": ["Primera línea", { "words": "Segunda línea" }] }
+        ]);
+        lyrics
+    }
+
+    fn with_lang(lang: &str) -> FormatOptions<'_> {
+        FormatOptions { lang: Some(lang), ..FormatOptions::default() }
+    }
+
+    #[test]
+    fn lang_picks_the_matching_translation() {
+        let id3 = format_lyrics(&translated_fixture(), "id3", with_lang("ES")).unwrap();
+        assert_eq!(id3["requestedLanguageAvailable"], true);
+        assert_eq!(id3["language"], "es");
+        assert_eq!(id3["lines"][0]["words"], "Primera línea");
+        assert_eq!(id3["lines"][1]["words"], "Segunda línea");
+        // Timings stay those of the original lines
+        assert_eq!(id3["lines"][1]["startTimeMs"], "4500");
+
+        // Asking for the original language is not a translation
+        let lrc = format_lyrics(&translated_fixture(), "lrc", with_lang("en")).unwrap();
+        assert_eq!(lrc["requestedLanguageAvailable"], true);
+        assert_eq!(lrc["lines"][0]["words"], "First line");
+    }
+
+    #[test]
+    fn unavailable_lang_returns_the_original_lyrics() {
+        let id3 = format_lyrics(&translated_fixture(), "id3", with_lang("fr")).unwrap();
+        assert_eq!(id3["requestedLanguageAvailable"], false);
+        assert_eq!(id3["language"], "en");
+        assert_eq!(id3["lines"][0]["words"], "First line");
+
+        // Only reported when a language was asked for
+        let id3 = format_lyrics(&translated_fixture(), "id3", FormatOptions::default()).unwrap();
+        assert!(id3.get("requestedLanguageAvailable").is_none());
+    }
+
+    #[test]
+    fn detects_html_pages_by_content_type_or_body() {
+        assert!(is_html_body(Some("text/html; charset=UTF-8"), "{}"));
+        assert!(is_html_body(Some("application/json"), "  \n<!DOCTYPE html><html>Service Unavailable</html>"));
+        assert!(is_html_body(None, "<html></html>"));
+        assert!(!is_html_body(Some("application/json"), "{\"lyrics\": {}}"));
+        assert!(!is_html_body(None, ""));
+    }
+
+    #[test]
+    fn empty_lines_are_reported_as_instrumental() {
+        let lyrics = serde_json::json!({
+            "lyrics": { "syncType": "LINE_SYNCED", "lines": [], "provider": "MusixMatch", "language": "en" }
+        });
+
+        for format in ["id3", "lrc", "both"] {
+            let body = format_lyrics(&lyrics, format, FormatOptions::default()).unwrap();
+            assert_eq!(body["instrumental"], true, "{}", format);
+            // Nothing is synced without lines, whatever Spotify says
+            assert_eq!(body["syncType"], "UNSYNCED", "{}", format);
+        }
+        let id3 = format_lyrics(&lyrics, "id3", FormatOptions::default()).unwrap();
+        assert_eq!(id3["lines"], serde_json::json!([]));
+        assert_eq!(id3["lineCount"], 0);
+
+        assert_eq!(formatted("id3", FormatOptions::default())["instrumental"], false);
+    }
+
+    fn url_error(url: &str) -> String {
+        match Spotify::extract_item_result(url) {
+            Err(SpotifyException::InvalidUrl(message)) => message,
+            other => panic!("expected InvalidUrl for {}, got {:?}", url, other),
+        }
+    }
+
+    #[test]
+    fn extracts_tracks_and_episodes_from_links_and_uris() {
+        let track = SpotifyItem::Track("4cOdK2wGLETKBW3PvgPWqT".to_string());
+        for url in [
+            "https://open.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT",
+            "https://open.spotify.com/intl-de/track/4cOdK2wGLETKBW3PvgPWqT?si=abc",
+            " http://play.spotify.com/track/4cOdK2wGLETKBW3PvgPWqT ",
+            "spotify:track:4cOdK2wGLETKBW3PvgPWqT",
+        ] {
+            assert_eq!(Spotify::extract_item_result(url).unwrap(), track, "{}", url);
+        }
+
+        let episode = SpotifyItem::Episode("512ojhOuo1ktJprKbVcKyQ".to_string());
+        assert_eq!(Spotify::extract_item_result("https://open.spotify.com/episode/512ojhOuo1ktJprKbVcKyQ").unwrap(), episode);
+        assert_eq!(Spotify::extract_item_result("spotify:episode:512ojhOuo1ktJprKbVcKyQ").unwrap(), episode);
+    }
+
+    #[test]
+    fn url_errors_say_what_is_wrong_with_the_link() {
+        assert_eq!(url_error("not a url"), "url parameter is not a valid URL!");
+        assert_eq!(url_error("https://example.com/track/4cOdK2wGLETKBW3PvgPWqT"), "url parameter is not a Spotify link!");
+        assert_eq!(url_error("https://open.spotify.com/album/1GbtB4zTqAsyfZEsm1RZfx"), "url parameter is an album link, not a track!");
+        assert_eq!(url_error("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"), "url parameter is a playlist link, not a track!");
+        assert_eq!(url_error("https://open.spotify.com/track/"), "url parameter has a malformed track ID!");
+        assert_eq!(url_error("https://open.spotify.com/track/4cOdK2wG%2F..%2F"), "url parameter has a malformed track ID!");
+        assert_eq!(url_error("https://open.spotify.com/genre/pop"), "url parameter is not a Spotify track or episode link!");
+    }
+
+    #[tokio::test]
+    async fn token_stays_in_memory_without_a_token_file() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/server-time"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "serverTime": 1_700_000_000 })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "accessToken": "memory-token",
+                "accessTokenExpirationTimestampMs": 4_102_444_800_000u64,
+                "isAnonymous": false
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // A token file left by an earlier run is neither read nor rewritten
+        let dir = tempfile::tempdir().unwrap();
+        let cache_file = dir.path().join("spotify_token.json");
+        let stale = r#"{"access_token":"file-token","access_token_expiration_timestamp_ms":4102444800000}"#;
+        std::fs::write(&cache_file, stale).unwrap();
+
+        let spotify = spotify_with_token_file(cache_file.clone())
+            .without_token_file()
+            .with_server_time_url(format!("{}/api/server-time", server.uri()))
+            .with_token_url(format!("{}/api/token", server.uri()));
+
+        assert_eq!(spotify.load_token().unwrap().access_token, None);
+        assert_eq!(spotify.get_access_token().await.unwrap(), "memory-token");
+        // The second lookup is answered from memory
+        assert_eq!(spotify.get_access_token().await.unwrap(), "memory-token");
+        assert_eq!(std::fs::read_to_string(&cache_file).unwrap(), stale);
+    }
+
+    #[test]
+    fn lrc_tags_are_ordered_escaped_and_skipped_when_empty() {
+        let tags = LrcTags {
+            title: Some("Song [Live]".to_string()),
+            artist: Some(String::new()),
+            album: Some("Album".to_string()),
+            length_ms: Some(213_999),
+        };
+        assert_eq!(tags.to_lines(), ["[ti:Song [Live)]", "[al:Album]", "[length:03:33]"]);
+
+        let mut both = formatted("both", FormatOptions::default());
+        tags.apply(&mut both, "both");
+        assert_eq!(both["lrc"]["idTags"][2], "[length:03:33]");
+        assert!(both.get("idTags").is_none());
+
+        // Nothing to add without any values, and id3 has nowhere to put them
+        let mut lrc = formatted("lrc", FormatOptions::default());
+        LrcTags::default().apply(&mut lrc, "lrc");
+        assert!(lrc.get("idTags").is_none());
+        let mut id3 = formatted("id3", FormatOptions::default());
+        tags.apply(&mut id3, "id3");
+        assert!(id3.get("idTags").is_none());
+    }
+
+    #[test]
+    fn sync_type_is_passed_through_verbatim() {
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["syncType"] = json!("SYLLABLE_SYNCED");
+        for format in ["id3", "lrc", "both"] {
+            let body = format_lyrics(&lyrics, format, FormatOptions::default()).unwrap();
+            assert_eq!(body["syncType"], "SYLLABLE_SYNCED", "{}", format);
+        }
+        // Syllable-synced lyrics still carry line times
+        let lrc = format_lyrics(&lyrics, "lrc", FormatOptions::default()).unwrap();
+        assert_eq!(lrc["lines"][0]["timeTag"], "00:01.00");
+
+        lyrics["lyrics"].as_object_mut().unwrap().remove("syncType");
+        assert_eq!(format_lyrics(&lyrics, "id3", FormatOptions::default()).unwrap()["syncType"], "UNSYNCED");
+    }
+
+    #[test]
+    fn lrclib_plain_lyrics_are_unsynced() {
+        let lyrics = lrclib_to_lyrics(&json!({ "syncedLyrics": null, "plainLyrics": "First line\nSecond line" }));
+        assert_eq!(lyrics["lyrics"]["syncType"], "UNSYNCED");
+        assert_eq!(lyrics["lyrics"]["lines"][1]["words"], "Second line");
+
+        // A line with several time tags is repeated at each
+        let lyrics = lrclib_to_lyrics(&json!({ "syncedLyrics": "[00:10.00][00:02.00]Chorus\nuntimed\n[00:05.00]Verse" }));
+        let lines: Vec<_> = lyrics["lyrics"]["lines"].as_array().unwrap().iter()
+            .map(|line| (line["startTimeMs"].as_str().unwrap(), line["words"].as_str().unwrap()))
+            .collect();
+        assert_eq!(lines, [("2000", "Chorus"), ("5000", "Verse"), ("10000", "Chorus")]);
+    }
+
+    #[test]
+    fn lrc_minutes_keep_counting_past_an_hour() {
+        assert_eq!(format_ms(3_599_990), "59:59.99");
+        assert_eq!(format_ms(3_600_000), "60:00.00");
+        assert_eq!(format_ms(4_530_500), "75:30.50");
+        assert_eq!(format_ms(7_200_000), "120:00.00");
+
+        // Subtitle formats have an hours field instead
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["lines"][1]["startTimeMs"] = json!("4530500");
+        let lrc = format_lyrics(&lyrics, "lrc", FormatOptions::default()).unwrap();
+        assert_eq!(lrc["lines"][1]["timeTag"], "75:30.50");
+        let srt = format_lyrics(&lyrics, "srt", FormatOptions::default()).unwrap();
+        assert!(srt.as_str().unwrap().contains("\n01:15:30,500 --> "));
+    }
+
+    #[test]
+    fn json_lines_has_one_object_per_line() {
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["lines"][1]["words"] = json!("Says \"hi\"\tthere");
+        let document = format_lyrics(&lyrics, "json-lines", with_offset(250)).unwrap();
+        let document = document.as_str().unwrap();
+
+        assert!(document.ends_with('\n'));
+        let lines: Vec<serde_json::Value> = document.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines, [
+            json!({ "t": 1250, "words": "First line" }),
+            json!({ "t": 4750, "words": "Says \"hi\"\tthere" }),
+        ]);
+    }
+
+    #[test]
+    fn totp_secret_errors_say_what_is_wrong() {
+        let error = decode_totp_secret("not base32!").unwrap_err().to_string();
+        assert!(error.contains("TOTP secret is not valid base32"), "{}", error);
+        let error = decode_totp_secret("").unwrap_err().to_string();
+        assert!(error.contains("TOTP secret is empty"), "{}", error);
+        // The built-in secret is decoded the same way, so it must stay valid
+        assert!(!decode_totp_secret(DEFAULT_TOTP_SECRET).unwrap().is_empty());
+    }
+
+    #[test]
+    fn format_lyrics_builds_whole_subtitle_documents() {
+        let srt = formatted("srt", FormatOptions::default());
+        assert_eq!(srt, "1\n00:00:01,000 --> 00:00:04,499\nFirst line\n\n2\n00:00:04,500 --> 00:00:09,500\nSecond line\n\n");
+
+        let vtt = formatted("vtt", FormatOptions::default());
+        assert_eq!(vtt, "WEBVTT\n\n00:00:01.000 --> 00:00:04.499\nFirst line\n\n00:00:04.500 --> 00:00:09.500\nSecond line\n\n");
+
+        assert_eq!(formatted("text", FormatOptions::default()), "First line\nSecond line\n");
+    }
+
+    #[test]
+    fn format_lyrics_builds_id3_lines_from_the_fixture() {
+        let id3 = formatted("id3", FormatOptions::default());
+        assert_eq!(id3["error"], false);
+        assert_eq!(id3["syncType"], "LINE_SYNCED");
+        assert_eq!(id3["lines"][1], json!({ "startTimeMs": "4500", "words": "Second line", "syllables": [], "endTimeMs": "0" }));
+        assert!(id3.get("warnings").is_none());
+    }
+
+    #[test]
+    fn format_lyrics_falls_back_to_id3() {
+        // The handlers validate the format, so anything else reaching here is treated as id3
+        assert_eq!(formatted("docx", FormatOptions::default()), formatted("id3", FormatOptions::default()));
+    }
+
+    #[test]
+    fn malformed_start_times_are_reported_as_warnings() {
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["lines"][1]["startTimeMs"] = json!("4.5s");
+        lyrics["lyrics"]["lines"].as_array_mut().unwrap().push(json!({ "words": "Third line" }));
+
+        let expected = json!([
+            "line 2 has an invalid startTimeMs \"4.5s\", using 0",
+            "line 3 has no startTimeMs, using 0"
+        ]);
+        let lrc = format_lyrics(&lyrics, "lrc", FormatOptions::default()).unwrap();
+        assert_eq!(lrc["warnings"], expected);
+        assert_eq!(lrc["lines"][1]["timeTag"], "00:00.00");
+        assert_eq!(format_lyrics(&lyrics, "id3", FormatOptions::default()).unwrap()["warnings"], expected);
+
+        // Unsynced lyrics have no timings to get wrong
+        lyrics["lyrics"]["syncType"] = json!("UNSYNCED");
+        assert!(format_lyrics(&lyrics, "id3", FormatOptions::default()).unwrap().get("warnings").is_none());
+    }
+
+    #[test]
+    fn lyrics_shape_check_accepts_well_formed_responses() {
+        assert!(check_lyrics_shape(&lyrics_fixture()).is_ok());
+    }
+
+    #[test]
+    fn lyrics_shape_check_lists_what_is_missing_or_mistyped() {
+        let shape_error = |lyrics_data: serde_json::Value| match check_lyrics_shape(&lyrics_data) {
+            Err(SpotifyException::ApiError(message)) => message,
+            other => panic!("expected an API error, got {:?}", other),
+        };
+
+        let mut missing_lines = lyrics_fixture();
+        missing_lines["lyrics"].as_object_mut().unwrap().remove("lines");
+        assert_eq!(shape_error(missing_lines), "unexpected lyrics response shape: lyrics.lines is missing");
+
+        let mut mistyped = lyrics_fixture();
+        mistyped["lyrics"]["lines"] = json!({});
+        mistyped["lyrics"]["syncType"] = json!(1);
+        assert_eq!(
+            shape_error(mistyped),
+            "unexpected lyrics response shape: lyrics.lines is an object instead of an array, lyrics.syncType is a number instead of a string"
+        );
+
+        assert_eq!(shape_error(json!({ "lyrics": null })), "unexpected lyrics response shape: lyrics is null instead of an object");
+    }
+
+    #[test]
+    fn ttml_matches_the_expected_document() {
+        let mut lyrics = lyrics_fixture();
+        lyrics["lyrics"]["lines"][1]["words"] = json!("Rock & <roll> \"all\" night");
+
+        let ttml = format_lyrics(&lyrics, "ttml", FormatOptions::default()).unwrap();
+        assert_eq!(ttml, include_str!("../tests/fixtures/lyrics.ttml"));
     }
 }
@@ -1,3 +1,4 @@
+use crate::privacy::is_redacting_logs;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,7 +7,10 @@ pub enum SpotifyException {
     ApiError(String),
     
     #[error("HTTP request error: {0}")]
-    RequestError(#[from] reqwest::Error),
+    RequestError(reqwest::Error),
+    
+    #[error("Request to Spotify timed out: {0}")]
+    Timeout(reqwest::Error),
     
     #[error("JSON parsing error: {0}")]
     JsonError(#[from] serde_json::Error),
@@ -17,12 +21,113 @@ pub enum SpotifyException {
     #[error("URL encoding error: {0}")]
     UrlEncodedError(#[from] serde_urlencoded::ser::Error),
     
+    /// Spotify has no lyrics for the track
+    #[error("{0}")]
+    NotFound(String),
+    
+    #[error("{0}")]
+    TrackNotFound(String),
+    
+    #[error("{0}")]
+    TranscriptNotFound(String),
+    
+    #[error("{0}")]
+    AlbumNotFound(String),
+    
+    #[error("{0}")]
+    PlaylistNotFound(String),
+    
+    #[error("Spotify authentication failed: {0}")]
+    AuthError(String),
+    
+    #[error("Unexpected Spotify response: {0}")]
+    InvalidResponse(String),
+    
+    #[error("{0}")]
+    InvalidUrl(String),
+    
+    #[error("Spotify is rate limiting requests, retry in {}s", .0.as_secs().max(1))]
+    RateLimited(std::time::Duration),
+    
     #[error("{0}")]
     Generic(String),
 }
 
+impl From<reqwest::Error> for SpotifyException {
+    fn from(error: reqwest::Error) -> Self {
+        SpotifyException::from_request_error(error, is_redacting_logs())
+    }
+}
+
 impl SpotifyException {
     pub fn new<S: Into<String>>(message: S) -> Self {
         SpotifyException::Generic(message.into())
     }
-}
\ No newline at end of file
+
+    // The error's message includes the request URL and with it the track ID, which no_store mode
+    // keeps out of logs and error responses
+    fn from_request_error(error: reqwest::Error, redact: bool) -> Self {
+        let error = if redact { error.without_url() } else { error };
+        if error.is_timeout() {
+            SpotifyException::Timeout(error)
+        } else {
+            SpotifyException::RequestError(error)
+        }
+    }
+
+    /// Stable machine-readable code sent as `code` in error responses
+    pub fn code(&self) -> &'static str {
+        match self {
+            SpotifyException::NotFound(_) => "NO_LYRICS",
+            SpotifyException::TrackNotFound(_) => "TRACK_NOT_FOUND",
+            SpotifyException::TranscriptNotFound(_) => "NO_TRANSCRIPT",
+            SpotifyException::AlbumNotFound(_) => "ALBUM_NOT_FOUND",
+            SpotifyException::PlaylistNotFound(_) => "PLAYLIST_NOT_FOUND",
+            SpotifyException::AuthError(_) => "UPSTREAM_AUTH",
+            SpotifyException::Timeout(_) => "UPSTREAM_TIMEOUT",
+            SpotifyException::InvalidResponse(_) => "UPSTREAM_INVALID",
+            SpotifyException::InvalidUrl(_) => "INVALID_URL",
+            SpotifyException::RateLimited(_) => "UPSTREAM_RATE_LIMITED",
+            SpotifyException::ApiError(_) | SpotifyException::RequestError(_) => "UPSTREAM_ERROR",
+            SpotifyException::JsonError(_)
+            | SpotifyException::IoError(_)
+            | SpotifyException::UrlEncodedError(_)
+            | SpotifyException::Generic(_) => "INTERNAL_ERROR",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_not_found_source_has_its_own_code() {
+        let cases = [
+            (SpotifyException::NotFound(String::new()), "NO_LYRICS"),
+            (SpotifyException::TrackNotFound(String::new()), "TRACK_NOT_FOUND"),
+            (SpotifyException::TranscriptNotFound(String::new()), "NO_TRANSCRIPT"),
+            (SpotifyException::AlbumNotFound(String::new()), "ALBUM_NOT_FOUND"),
+            (SpotifyException::PlaylistNotFound(String::new()), "PLAYLIST_NOT_FOUND"),
+        ];
+
+        for (e, code) in cases {
+            assert_eq!(e.code(), code);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_requests_leave_the_url_out_when_redacting() {
+        // Nothing listens on a port just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{}/color-lyrics/v2/track/4cOdK2wGLETKBW3PvgPWqT", port);
+        let failed_request = || async { reqwest::get(&url).await.unwrap_err() };
+
+        let redacted = SpotifyException::from_request_error(failed_request().await, true);
+        assert_eq!(redacted.code(), "UPSTREAM_ERROR");
+        assert!(!redacted.to_string().contains("4cOdK2wGLETKBW3PvgPWqT"), "{}", redacted);
+
+        let logged = SpotifyException::from_request_error(failed_request().await, false);
+        assert!(logged.to_string().contains("4cOdK2wGLETKBW3PvgPWqT"), "{}", logged);
+    }
+}
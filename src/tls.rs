@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use rustls::ServerConfig;
+
+/// Builds a rustls server config from a PEM certificate chain and private key.
+/// HTTP/2 is negotiated through ALPN by actix when the config is bound.
+pub fn load_server_config(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let mut cert_reader = BufReader::new(open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<io::Result<Vec<_>>>()?;
+
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates found in {}", cert_path)));
+    }
+
+    let mut key_reader = BufReader::new(open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_reader)?
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", key_path)))?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_data(format!("invalid TLS certificate or key: {}", e)))
+}
+
+/// Opens a file, naming it in the error so a typo in the config is easy to spot
+fn open(path: &str) -> io::Result<File> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("failed to open {}: {}", path, e)))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Self-signed for localhost and 127.0.0.1, only ever used by tests
+    const TEST_CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.crt");
+    const TEST_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.key");
+
+    #[test]
+    fn loads_a_certificate_and_key() {
+        assert!(load_server_config(TEST_CERT, TEST_KEY).is_ok());
+    }
+
+    #[test]
+    fn names_the_file_that_is_missing_or_wrong() {
+        let e = load_server_config(TEST_CERT, "/nonexistent/key.pem").unwrap_err();
+        assert!(e.to_string().contains("/nonexistent/key.pem"), "{}", e);
+
+        // A key where the certificate should be has no certificates in it
+        let e = load_server_config(TEST_KEY, TEST_KEY).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("no certificates"), "{}", e);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::privacy::loggable;
+use log::info;
+use reqwest::header::{HeaderMap, AUTHORIZATION, COOKIE, SET_COOKIE};
+use reqwest::ResponseBuilderExt;
+
+// Longer response bodies are cut off in the log
+const MAX_LOGGED_BODY_CHARS: usize = 2000;
+
+// JSON fields holding credentials, such as the access token in token responses
+const SECRET_FIELDS: [&str; 2] = ["accessToken", "access_token"];
+
+/// Sends a request to Spotify, logging the URL, headers, status and the start of the response body.
+/// The sp_dc cookie, bearer token and access tokens in response bodies are replaced with
+/// `[redacted]` before anything is logged.
+pub async fn send_logged(client: &reqwest::Client, endpoint: &str, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let request = request.build()?;
+    info!(
+        "Upstream {} request: {} {} [{}]",
+        endpoint,
+        request.method(),
+        loggable(request.url().as_str()),
+        redacted_headers(request.headers())
+    );
+
+    let response = client.execute(request).await?;
+    let status = response.status();
+    let version = response.version();
+    let url = response.url().clone();
+    let headers = response.headers().clone();
+
+    // The body can only be read once, so the response is rebuilt around it for the caller
+    let body = response.bytes().await?;
+    info!(
+        "Upstream {} response: {} [{}] {}",
+        endpoint,
+        status,
+        redacted_headers(&headers),
+        loggable(&truncated(&redacted_body(&body)))
+    );
+
+    let mut builder = http::Response::builder().status(status).version(version).url(url);
+    if let Some(builder_headers) = builder.headers_mut() {
+        *builder_headers = headers;
+    }
+
+    Ok(builder.body(body).expect("parts taken from a valid response").into())
+}
+
+/// Headers as `name: value` pairs, with the cookie and authorization values hidden
+fn redacted_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            if name == COOKIE || name == SET_COOKIE || name == AUTHORIZATION {
+                format!("{}: [redacted]", name)
+            } else {
+                format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The body as text, with the values of credential fields hidden when it is JSON
+fn redacted_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut json) => {
+            redact_secret_fields(&mut json);
+            json.to_string()
+        },
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+fn redact_secret_fields(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) {
+                    *field = serde_json::Value::String("[redacted]".to_string());
+                } else {
+                    redact_secret_fields(field);
+                }
+            }
+        },
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_secret_fields),
+        _ => {},
+    }
+}
+
+fn truncated(body: &str) -> String {
+    match body.char_indices().nth(MAX_LOGGED_BODY_CHARS) {
+        Some((cut, _)) => format!("{}... ({} bytes)", &body[..cut], body.len()),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const SP_DC: &str = "sp-dc-secret-value";
+    const ACCESS_TOKEN: &str = "access-token-secret-value";
+
+    /// Keeps every log line so tests can check what would have been written
+    struct CapturingLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    #[tokio::test]
+    async fn secrets_never_reach_the_log() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/token"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("set-cookie", format!("sp_dc={}; Path=/", SP_DC))
+                .set_body_json(serde_json::json!({ "accessToken": ACCESS_TOKEN, "isAnonymous": false })))
+            .mount(&server)
+            .await;
+
+        let client = reqwest::Client::new();
+        let request = client.get(format!("{}/api/token", server.uri()))
+            .header(COOKIE, format!("sp_dc={}", SP_DC))
+            .bearer_auth(ACCESS_TOKEN);
+        let response = send_logged(&client, "token_redaction_test", request).await.unwrap();
+
+        // The caller still gets the whole response
+        assert!(response.text().await.unwrap().contains(ACCESS_TOKEN));
+
+        let lines: Vec<String> = LOGGER.0.lock().unwrap()
+            .iter()
+            .filter(|line| line.contains("token_redaction_test"))
+            .cloned()
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("cookie: [redacted]") && lines[0].contains("authorization: [redacted]"), "{}", lines[0]);
+        assert!(lines[1].contains("200 OK") && lines[1].contains(r#""accessToken":"[redacted]""#), "{}", lines[1]);
+        for line in &lines {
+            assert!(!line.contains(SP_DC) && !line.contains(ACCESS_TOKEN), "secret logged: {}", line);
+        }
+    }
+
+    #[test]
+    fn long_bodies_are_truncated() {
+        let body = "a".repeat(MAX_LOGGED_BODY_CHARS + 10);
+        assert_eq!(truncated(&body), format!("{}... ({} bytes)", "a".repeat(MAX_LOGGED_BODY_CHARS), body.len()));
+        assert_eq!(truncated("short"), "short");
+    }
+
+    #[test]
+    fn nested_access_tokens_are_redacted() {
+        let body = br#"{"session":{"access_token":"secret"},"items":[{"accessToken":"secret"}],"name":"kept"}"#;
+        assert_eq!(
+            redacted_body(body),
+            r#"{"items":[{"accessToken":"[redacted]"}],"name":"kept","session":{"access_token":"[redacted]"}}"#
+        );
+        assert_eq!(redacted_body(b"not json"), "not json");
+    }
+}